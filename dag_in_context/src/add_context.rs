@@ -3,14 +3,14 @@
 //! by remembering the most recent context (ex. DoWhile or If).
 //! Mantains the sharing invariant (see restore_sharing_invariant) by using a cache.
 
-use egglog::Term;
+use egglog::ast::{Action, DUMMY_SPAN};
 use indexmap::IndexMap;
 
 use crate::{
-    print_with_intermediate_helper,
     schema::{Assumption, Expr, RcExpr, TreeProgram},
     schema_helpers::AssumptionRef,
     to_egglog::TreeToEgglog,
+    TermActions,
 };
 
 pub struct ContextCache {
@@ -90,35 +90,24 @@ impl ContextCache {
             })
     }
 
-    pub(crate) fn get_unions_with_sharing(
+    /// Actions for the loop context unions, adding the assumptions to `actions`.
+    pub(crate) fn loop_context_union_actions(
         &self,
-        printed: &mut String,
         tree_state: &mut TreeToEgglog,
-        term_cache: &mut IndexMap<Term, String>,
-    ) -> String {
+        actions: &mut TermActions,
+    ) -> Vec<Action> {
         self.loop_context_unions
             .iter()
             .map(|(a, b)| {
                 let internal_a = a.to_egglog_internal(tree_state);
                 let internal_b = b.to_egglog_internal(tree_state);
 
-                let shared_a = print_with_intermediate_helper(
-                    &tree_state.termdag,
-                    internal_a,
-                    term_cache,
-                    printed,
-                );
-                let shared_b = print_with_intermediate_helper(
-                    &tree_state.termdag,
-                    internal_b,
-                    term_cache,
-                    printed,
-                );
+                let shared_a = actions.add_term(&tree_state.termdag, internal_a);
+                let shared_b = actions.add_term(&tree_state.termdag, internal_b);
 
-                format!("(union {shared_a} {shared_b})")
+                Action::Union(DUMMY_SPAN.clone(), shared_a, shared_b)
             })
-            .collect::<Vec<_>>()
-            .join("\n")
+            .collect()
     }
 
     pub fn new_placeholder(&mut self) -> Assumption {
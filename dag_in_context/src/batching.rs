@@ -0,0 +1,265 @@
+//! Splits the functions of a program into batches that are optimized in separate egraphs.
+//! Batches follow the call graph bottom-up, so callees are optimized before their callers.
+
+use std::rc::Rc;
+
+use clap::ValueEnum;
+use indexmap::{IndexMap, IndexSet};
+
+use crate::schema::{Expr, RcExpr, TreeProgram};
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Batching {
+    /// Optimize every function in one egraph.
+    #[default]
+    WholeProgram,
+    /// Optimize each strongly connected component of the call graph in its own egraph.
+    Sccs,
+    /// Like `Sccs`, but merge neighboring components while their
+    /// total size stays under `max_batch_size`.
+    Clusters,
+}
+
+/// Splits `fns` into batches according to `batching`.
+/// For `Sccs` and `Clusters`, callees come in earlier batches than their callers,
+/// except for mutually recursive functions, which are always in the same batch.
+/// Calls to functions outside of `fns` are ignored.
+pub(crate) fn batches(
+    program: &TreeProgram,
+    fns: &[String],
+    batching: Batching,
+    max_batch_size: usize,
+) -> Vec<Vec<String>> {
+    match batching {
+        Batching::WholeProgram => vec![fns.to_vec()],
+        Batching::Sccs => call_graph_sccs(program, fns),
+        Batching::Clusters => {
+            let mut clusters: Vec<(Vec<String>, usize)> = vec![];
+            for scc in call_graph_sccs(program, fns) {
+                let size = scc
+                    .iter()
+                    .map(|func| function_size(program.get_function(func).unwrap()))
+                    .sum::<usize>();
+                match clusters.last_mut() {
+                    Some((cluster, cluster_size)) if *cluster_size + size <= max_batch_size => {
+                        cluster.extend(scc);
+                        *cluster_size += size;
+                    }
+                    _ => clusters.push((scc, size)),
+                }
+            }
+            clusters
+                .into_iter()
+                .map(|(cluster, _size)| cluster)
+                .collect()
+        }
+    }
+}
+
+/// Groups `batches` into levels that can be optimized at the same time.
+/// Every batch is in a later level than the batches of the functions it calls,
+/// so optimizing the levels in order gives the same result as optimizing the batches in order.
+/// Batches keep their relative order within a level.
+pub(crate) fn batch_levels(
+    program: &TreeProgram,
+    batches: Vec<Vec<String>>,
+) -> Vec<Vec<Vec<String>>> {
+    let mut fn_level: IndexMap<String, usize> = IndexMap::new();
+    let mut levels: Vec<Vec<Vec<String>>> = vec![];
+    for batch in batches {
+        let level = batch
+            .iter()
+            .flat_map(|func| callees(program.get_function(func).unwrap()))
+            .filter_map(|callee| fn_level.get(&callee).map(|level| level + 1))
+            .max()
+            .unwrap_or(0);
+        for func in &batch {
+            fn_level.insert(func.clone(), level);
+        }
+        if level == levels.len() {
+            levels.push(vec![]);
+        }
+        levels[level].push(batch);
+    }
+    levels
+}
+
+/// The number of distinct expressions in a function.
+pub(crate) fn function_size(func: &RcExpr) -> usize {
+    let mut seen = IndexSet::new();
+    let mut todo = vec![func.clone()];
+    while let Some(expr) = todo.pop() {
+        if seen.insert(Rc::as_ptr(&expr)) {
+            todo.extend(expr.children_exprs());
+        }
+    }
+    seen.len()
+}
+
+/// Names of the functions called by `func`.
+fn callees(func: &RcExpr) -> IndexSet<String> {
+    let mut seen = IndexSet::new();
+    let mut callees = IndexSet::new();
+    let mut todo = vec![func.clone()];
+    while let Some(expr) = todo.pop() {
+        if !seen.insert(Rc::as_ptr(&expr)) {
+            continue;
+        }
+        if let Expr::Call(name, _) = expr.as_ref() {
+            callees.insert(name.clone());
+        }
+        todo.extend(expr.children_exprs());
+    }
+    callees
+}
+
+/// Strongly connected components of the call graph restricted to `fns`,
+/// using Tarjan's algorithm.
+/// Components are returned in reverse topological order, callees first.
+fn call_graph_sccs(program: &TreeProgram, fns: &[String]) -> Vec<Vec<String>> {
+    let graph: IndexMap<String, Vec<String>> = fns
+        .iter()
+        .map(|func| {
+            let callees = callees(program.get_function(func).unwrap())
+                .into_iter()
+                .filter(|callee| fns.contains(callee))
+                .collect();
+            (func.clone(), callees)
+        })
+        .collect();
+
+    struct Tarjan<'a> {
+        graph: &'a IndexMap<String, Vec<String>>,
+        index: IndexMap<&'a str, usize>,
+        lowlink: IndexMap<&'a str, usize>,
+        stack: Vec<&'a str>,
+        on_stack: IndexSet<&'a str>,
+        sccs: Vec<Vec<String>>,
+    }
+
+    impl<'a> Tarjan<'a> {
+        fn visit(&mut self, func: &'a str) {
+            let index = self.index.len();
+            self.index.insert(func, index);
+            self.lowlink.insert(func, index);
+            self.stack.push(func);
+            self.on_stack.insert(func);
+
+            for callee in &self.graph[func] {
+                if !self.index.contains_key(callee.as_str()) {
+                    self.visit(callee);
+                    let lowlink = self.lowlink[func].min(self.lowlink[callee.as_str()]);
+                    self.lowlink.insert(func, lowlink);
+                } else if self.on_stack.contains(callee.as_str()) {
+                    let lowlink = self.lowlink[func].min(self.index[callee.as_str()]);
+                    self.lowlink.insert(func, lowlink);
+                }
+            }
+
+            if self.lowlink[func] == self.index[func] {
+                let mut scc = vec![];
+                loop {
+                    let member = self.stack.pop().unwrap();
+                    self.on_stack.swap_remove(member);
+                    scc.push(member.to_string());
+                    if member == func {
+                        break;
+                    }
+                }
+                // keep the program's order of functions within a component
+                scc.sort_by_key(|member| fns_position(self.graph, member));
+                self.sccs.push(scc);
+            }
+        }
+    }
+
+    fn fns_position(graph: &IndexMap<String, Vec<String>>, func: &str) -> usize {
+        graph.get_index_of(func).unwrap()
+    }
+
+    let mut tarjan = Tarjan {
+        graph: &graph,
+        index: IndexMap::new(),
+        lowlink: IndexMap::new(),
+        stack: vec![],
+        on_stack: IndexSet::new(),
+        sccs: vec![],
+    };
+    for func in graph.keys() {
+        if !tarjan.index.contains_key(func.as_str()) {
+            tarjan.visit(func);
+        }
+    }
+    tarjan.sccs
+}
+
+#[test]
+fn test_call_graph_batches() {
+    use crate::ast::*;
+
+    let ty = tuplet!(intt(), statet());
+    // main calls f and g, f and g call each other, g calls h
+    let prog = program!(
+        function("main", ty.clone(), ty.clone(), call("f", arg())),
+        function("f", ty.clone(), ty.clone(), call("g", call("g", arg()))),
+        function("g", ty.clone(), ty.clone(), call("f", call("h", arg()))),
+        function("h", ty.clone(), ty.clone(), arg())
+    );
+    let fns = prog.fns();
+
+    assert_eq!(
+        batches(&prog, &fns, Batching::WholeProgram, 0),
+        vec![fns.clone()]
+    );
+    assert_eq!(
+        batches(&prog, &fns, Batching::Sccs, 0),
+        vec![
+            vec!["h".to_string()],
+            vec!["f".to_string(), "g".to_string()],
+            vec!["main".to_string()]
+        ]
+    );
+    // h and {f, g} fit together, main does not fit with them
+    let small = function_size(prog.get_function("h").unwrap())
+        + function_size(prog.get_function("f").unwrap())
+        + function_size(prog.get_function("g").unwrap());
+    assert_eq!(
+        batches(&prog, &fns, Batching::Clusters, small),
+        vec![
+            vec!["h".to_string(), "f".to_string(), "g".to_string()],
+            vec!["main".to_string()]
+        ]
+    );
+    // h has to be optimized before {f, g}, which has to be optimized before main
+    assert_eq!(
+        batch_levels(&prog, batches(&prog, &fns, Batching::Sccs, 0)).len(),
+        3
+    );
+    // only the functions asked for are batched
+    assert_eq!(
+        batches(
+            &prog,
+            &["main".to_string(), "h".to_string()],
+            Batching::Sccs,
+            0
+        ),
+        vec![vec!["h".to_string()], vec!["main".to_string()]]
+    );
+
+    // neither of these calls the other, so they can be optimized together
+    let leaves = vec![vec!["h".to_string()], vec!["other".to_string()]];
+    let prog = program!(
+        function(
+            "main",
+            ty.clone(),
+            ty.clone(),
+            call("h", call("other", arg()))
+        ),
+        function("h", ty.clone(), ty.clone(), arg()),
+        function("other", ty.clone(), ty.clone(), arg())
+    );
+    assert_eq!(
+        batch_levels(&prog, batches(&prog, &prog.fns(), Batching::Sccs, 0)),
+        vec![leaves, vec![vec!["main".to_string()]]]
+    );
+}
@@ -0,0 +1,276 @@
+//! An on-disk cache of optimized functions, so that unchanged functions
+//! aren't re-optimized on every run.
+//! Each batch of functions is keyed by a SHA-256 hash of the egglog program built for it,
+//! which covers the functions before optimization, the inlined callees and the schedule.
+//! The hash also covers the full prologue (with any extra rules), so editing a rule
+//! invalidates the cache, the eggcc version, the egglog revision,
+//! and the sources of the code that turns the egraph into a program
+//! (the extractor, which holds the cost model, and the conversions it uses).
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use egglog::{Term, TermDag};
+use indexmap::IndexMap;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    extra_rules::ExtraRules, from_egglog::FromEgglog, prologue_with_extra_rules, schema::RcExpr,
+    to_egglog::TreeToEgglog,
+};
+
+/// Bump this when the format of cache files changes.
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Sources that affect the optimized program but not the egglog program.
+const EXTRACTION_SOURCES: [&str; 7] = [
+    include_str!("greedy_dag_extractor.rs"),
+    include_str!("linearity.rs"),
+    include_str!("from_egglog.rs"),
+    include_str!("to_egglog.rs"),
+    include_str!("add_context.rs"),
+    include_str!("typechecker.rs"),
+    include_str!("schema_helpers.rs"),
+];
+
+/// The egglog line of our manifest, which pins the egglog revision.
+fn egglog_dependency() -> &'static str {
+    include_str!("../Cargo.toml")
+        .lines()
+        .find(|line| line.starts_with("egglog "))
+        .expect("egglog is a dependency of dag_in_context")
+}
+
+pub(crate) struct OptimizationCache {
+    dir: PathBuf,
+}
+
+impl OptimizationCache {
+    pub(crate) fn new(dir: &Path) -> OptimizationCache {
+        OptimizationCache {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// The key for optimizing a batch by running `egglog_prog` after the prologue.
+    pub(crate) fn key(
+        &self,
+        extra_rules: Option<&ExtraRules>,
+        egglog_prog: &str,
+        should_maintain_linearity: bool,
+    ) -> String {
+        let mut hasher = Sha256::new();
+        // each part is prefixed with its length, so parts can't run into each other
+        let mut part = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        part(&CACHE_FORMAT_VERSION.to_le_bytes());
+        part(env!("CARGO_PKG_VERSION").as_bytes());
+        part(egglog_dependency().as_bytes());
+        part(prologue_with_extra_rules(extra_rules).as_bytes());
+        for source in EXTRACTION_SOURCES {
+            part(source.as_bytes());
+        }
+        part(&[should_maintain_linearity as u8]);
+        part(egglog_prog.as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.eggcc-cache"))
+    }
+
+    /// The optimized functions stored under `key`, if any.
+    /// Unreadable cache files are treated as misses.
+    pub(crate) fn load(&self, key: &str) -> Option<Vec<(String, RcExpr)>> {
+        let contents = std::fs::read_to_string(self.path(key)).ok()?;
+        let fns = parse_cache_file(&contents);
+        if fns.is_none() {
+            eprintln!(
+                "Warning: ignoring malformed cache file {}",
+                self.path(key).display()
+            );
+        }
+        fns
+    }
+
+    /// Stores the optimized functions under `key`.
+    /// Failing to write the cache only produces a warning.
+    pub(crate) fn store(&self, key: &str, fns: &[(String, RcExpr)]) {
+        let contents = print_cache_file(fns);
+        // write to a temporary file first so that concurrent runs never see a partial file
+        let tmp_path = self.dir.join(format!("{key}.{}.tmp", std::process::id()));
+        let result = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&tmp_path, contents))
+            .and_then(|_| std::fs::rename(&tmp_path, self.path(key)));
+        if let Err(err) = result {
+            eprintln!(
+                "Warning: failed to write cache file {}: {err}",
+                self.path(key).display()
+            );
+        }
+    }
+}
+
+/// Prints the functions as a list of terms, one per line, followed by the root term of each function.
+/// Terms refer to their children by line number, so sharing is preserved.
+/// Line numbers count the `L` and `A` lines from 0, and children always come before their parents.
+/// Literals are printed as egglog expressions, and `A` lines give the egglog constructor.
+/// Functions are listed in the order they were given.
+/// ```text
+/// eggcc-cache 1
+/// L <literal>
+/// A <head> <child line>...
+/// fn <name> <root line>
+/// ```
+pub(crate) fn print_cache_file(fns: &[(String, RcExpr)]) -> String {
+    let mut state = TreeToEgglog::new();
+    let roots = fns
+        .iter()
+        .map(|(name, func)| (name, func.to_egglog_with(&mut state)))
+        .collect::<Vec<_>>();
+
+    let mut printed = format!("eggcc-cache {CACHE_FORMAT_VERSION}\n");
+    let mut lines = IndexMap::<Term, usize>::new();
+    let mut fn_lines = String::new();
+    for (name, root) in roots {
+        let line = print_term(&state.termdag, root, &mut lines, &mut printed);
+        writeln!(fn_lines, "fn {name} {line}").unwrap();
+    }
+    printed.push_str(&fn_lines);
+    printed
+}
+
+/// Prints `term` and its children if they haven't been printed yet,
+/// returning the line number of `term`.
+fn print_term(
+    termdag: &TermDag,
+    term: Term,
+    lines: &mut IndexMap<Term, usize>,
+    printed: &mut String,
+) -> usize {
+    if let Some(line) = lines.get(&term) {
+        return *line;
+    }
+    match &term {
+        Term::App(head, children) => {
+            let children = children
+                .iter()
+                .map(|child| {
+                    print_term(termdag, termdag.get(*child).clone(), lines, printed).to_string()
+                })
+                .collect::<Vec<_>>();
+            writeln!(printed, "A {head} {}", children.join(" ")).unwrap();
+        }
+        Term::Lit(_) | Term::Var(_) => {
+            writeln!(printed, "L {}", termdag.to_string(&term)).unwrap();
+        }
+    }
+    let line = lines.len();
+    lines.insert(term, line);
+    line
+}
+
+pub(crate) fn parse_cache_file(contents: &str) -> Option<Vec<(String, RcExpr)>> {
+    let mut lines = contents.lines();
+    if lines.next()? != format!("eggcc-cache {CACHE_FORMAT_VERSION}") {
+        return None;
+    }
+
+    let mut termdag = TermDag::default();
+    let mut terms: Vec<Term> = vec![];
+    let mut roots: Vec<(String, Term)> = vec![];
+    for line in lines {
+        let (kind, rest) = line.split_once(' ')?;
+        match kind {
+            "L" => {
+                let expr = egglog::ast::parse_expr(None, rest).ok()?;
+                terms.push(termdag.expr_to_term(&expr));
+            }
+            "A" => {
+                let mut parts = rest.split(' ').filter(|part| !part.is_empty());
+                let head = parts.next()?;
+                let children = parts
+                    .map(|child| terms.get(child.parse::<usize>().ok()?).cloned())
+                    .collect::<Option<Vec<_>>>()?;
+                terms.push(termdag.app(head.into(), children));
+            }
+            "fn" => {
+                let (name, root) = rest.split_once(' ')?;
+                let root = terms.get(root.parse::<usize>().ok()?)?.clone();
+                roots.push((name.to_string(), root));
+            }
+            _ => return None,
+        }
+    }
+
+    let mut converter = FromEgglog {
+        termdag: &termdag,
+        conversion_cache: IndexMap::new(),
+    };
+    Some(
+        roots
+            .into_iter()
+            .map(|(name, root)| (name, converter.expr_from_egglog(root)))
+            .collect(),
+    )
+}
+
+#[test]
+fn test_cache_round_trip() {
+    use crate::ast::*;
+    use crate::schema::TreeProgram;
+
+    let shared = add(getat(0), int(1));
+    let prog = program!(
+        function(
+            "main",
+            tuplet!(intt(), statet()),
+            tuplet!(intt(), statet()),
+            parallel!(mul(shared.clone(), shared), getat(1))
+        ),
+        function(
+            "other",
+            tuplet!(boolt(), statet()),
+            tuplet!(boolt(), statet()),
+            parallel!(not(getat(0)), getat(1))
+        ),
+    )
+    .add_dummy_ctx()
+    .0;
+    let fns = prog
+        .fns()
+        .into_iter()
+        .map(|name| {
+            let func = prog.get_function(&name).unwrap().clone();
+            (name, func)
+        })
+        .collect::<Vec<_>>();
+
+    let dir = std::env::temp_dir().join(format!("eggcc-cache-test-{}", std::process::id()));
+    let cache = OptimizationCache::new(&dir);
+    let key = cache.key(None, "(egglog program)", true);
+    assert_ne!(key, cache.key(None, "(egglog program)", false));
+    // the key only changes with egglog if the revision is pinned
+    assert!(egglog_dependency().contains("rev = "));
+    assert!(cache.load(&key).is_none());
+
+    cache.store(&key, &fns);
+    let loaded = cache.load(&key).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let loaded_prog = TreeProgram {
+        entry: loaded[0].1.clone(),
+        functions: vec![loaded[1].1.clone()],
+    };
+    assert_eq!(loaded[0].0, "main");
+    assert_eq!(loaded[1].0, "other");
+    assert!(crate::are_progs_eq(loaded_prog, prog));
+}
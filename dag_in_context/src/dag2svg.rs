@@ -30,6 +30,8 @@ struct DotConverter {
     pub done: HashSet<UniqueExpr>,
     pub get_name: HashMap<UniqueExpr, String>,
     pub name_counter: usize,
+    /// Colors for highlighted expressions, in every scope they appear in.
+    pub colors: HashMap<*const Expr, String>,
 }
 
 impl DotConverter {
@@ -82,10 +84,41 @@ impl DotConverter {
     pub fn graphviz_vertex(&mut self, expr: &RcExpr) -> Vertex {
         Vertex::N(self.graphviz_nodeid(expr))
     }
+
+    fn color_attributes(&self, expr: &RcExpr) -> Vec<Attribute> {
+        match self.colors.get(&Rc::as_ptr(expr)) {
+            Some(color) => ["color", "fontcolor"]
+                .into_iter()
+                .map(|attr| {
+                    Attribute(
+                        Id::Plain(attr.to_string()),
+                        Id::Plain(format!("\"{color}\"")),
+                    )
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Colors the cluster of a region node, like `color_attributes` colors other nodes.
+    fn cluster_color(&self, expr: &RcExpr) -> Vec<Stmt> {
+        self.color_attributes(expr)
+            .into_iter()
+            .map(Stmt::Attribute)
+            .collect()
+    }
 }
 
 pub fn tree_to_svg(prog: &TreeProgram) -> String {
-    let dot_code = prog.to_dot();
+    tree_to_svg_with_colors(prog, &HashMap::new())
+}
+
+/// Like `tree_to_svg`, but draws the given expressions in the given graphviz colors.
+pub fn tree_to_svg_with_colors(
+    prog: &TreeProgram,
+    colors: &HashMap<*const Expr, String>,
+) -> String {
+    let dot_code = prog.to_dot_with_colors(colors);
     String::from_utf8(
         exec(
             dot_code,
@@ -99,11 +132,16 @@ pub fn tree_to_svg(prog: &TreeProgram) -> String {
 
 impl TreeProgram {
     pub fn to_dot(&self) -> Graph {
+        self.to_dot_with_colors(&HashMap::new())
+    }
+
+    pub fn to_dot_with_colors(&self, colors: &HashMap<*const Expr, String>) -> Graph {
         let mut dot_converter = DotConverter {
             done: HashSet::new(),
             get_name: HashMap::new(),
             name_counter: 0,
             current_scope: std::ptr::null(),
+            colors: colors.clone(),
         };
         let mut stmts = self.to_dot_with(&mut dot_converter);
 
@@ -136,6 +174,7 @@ impl Expr {
             get_name: HashMap::new(),
             name_counter: 0,
             current_scope: Rc::as_ptr(self),
+            colors: HashMap::new(),
         };
 
         Graph::DiGraph {
@@ -162,8 +201,10 @@ impl Expr {
 
                 let scope_before = conv.current_scope;
                 conv.current_scope = id;
+                let mut body_stmts = conv.cluster_color(self);
+                body_stmts.extend(body.to_dot_with(conv));
                 stmts.push(Stmt::Subgraph(Subgraph {
-                    stmts: body.to_dot_with(conv),
+                    stmts: body_stmts,
                     id: id_outside,
                 }));
                 stmts.push(Stmt::Edge(Edge {
@@ -186,8 +227,10 @@ impl Expr {
 
                 let scope_before = conv.current_scope;
                 conv.current_scope = id;
+                let mut then_stmts = conv.cluster_color(self);
+                then_stmts.extend(then_case.to_dot_with(conv));
                 stmts.push(Stmt::Subgraph(Subgraph {
-                    stmts: then_case.to_dot_with(conv),
+                    stmts: then_stmts,
                     id: id_outside.clone(),
                 }));
                 stmts.push(Stmt::Edge(Edge {
@@ -196,8 +239,10 @@ impl Expr {
                 }));
 
                 conv.current_scope = id;
+                let mut else_stmts = conv.cluster_color(self);
+                else_stmts.extend(else_case.to_dot_with(conv));
                 stmts.push(Stmt::Subgraph(Subgraph {
-                    stmts: else_case.to_dot_with(conv),
+                    stmts: else_stmts,
                     id: id_outside,
                 }));
                 stmts.push(Stmt::Edge(Edge {
@@ -230,7 +275,7 @@ impl Expr {
                 let children = self.children_same_scope();
                 let mut stmts = vec![Stmt::Node(Node {
                     id: conv.graphviz_nodeid(self),
-                    attributes: vec![],
+                    attributes: conv.color_attributes(self),
                 })];
                 for child in children {
                     let child_stmts = child.to_dot_with(conv);
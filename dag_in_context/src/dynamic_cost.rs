@@ -0,0 +1,187 @@
+//! Profiles of single runs of the interpreter, from `profile_dag_prog`.
+//! Weighting how often each expression was evaluated by a `CostModel`'s op costs
+//! gives the dynamic cost of the run, which shows how well the static estimates
+//! of the extractor (like its guess of 1000 iterations per loop) match real executions.
+
+use std::fmt::Write;
+
+use indexmap::IndexMap;
+
+use crate::{
+    greedy_dag_extractor::CostModel,
+    interpreter::{interpret_dag_prog_internal, InterpretConfig, RuntimeError, Value},
+    schema::{Expr, RcExpr, TreeProgram},
+};
+
+/// How often each expression of a program was evaluated in one run.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Calls to each function, in the order they were first called.
+    pub calls: IndexMap<String, u64>,
+    /// Evaluations of each expression, in the order they were first evaluated.
+    exprs: IndexMap<*const Expr, ExprProfile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExprProfile {
+    pub expr: RcExpr,
+    /// The function the expression was first evaluated in.
+    pub function: String,
+    pub evals: u64,
+    /// For loops, the number of iterations over all evaluations.
+    pub iterations: u64,
+}
+
+/// Interprets a program like `interpret_dag_prog_with_config`,
+/// also returning how often each function and expression was evaluated.
+pub fn profile_dag_prog(
+    prog: &TreeProgram,
+    arg: &Value,
+    config: &InterpretConfig,
+) -> Result<(Value, Vec<String>, Profile), RuntimeError> {
+    let (value, log, profile) =
+        interpret_dag_prog_internal(prog, arg, config, Some(Profile::default()))?;
+    Ok((value, log, profile.unwrap()))
+}
+
+impl Profile {
+    pub(crate) fn record_call(&mut self, func: &str) {
+        *self.calls.entry(func.to_string()).or_default() += 1;
+    }
+
+    pub(crate) fn record_eval(&mut self, expr: &RcExpr, function: Option<&String>) {
+        self.exprs
+            .entry(RcExpr::as_ptr(expr))
+            .or_insert_with(|| ExprProfile {
+                expr: expr.clone(),
+                function: function.cloned().unwrap_or_default(),
+                evals: 0,
+                iterations: 0,
+            })
+            .evals += 1;
+    }
+
+    /// Records an iteration of a loop, which has already been recorded by `record_eval`.
+    pub(crate) fn record_iteration(&mut self, expr: &RcExpr) {
+        if let Some(profile) = self.exprs.get_mut(&RcExpr::as_ptr(expr)) {
+            profile.iterations += 1;
+        }
+    }
+
+    pub fn exprs(&self) -> impl Iterator<Item = &ExprProfile> {
+        self.exprs.values()
+    }
+
+    /// The dynamic cost of each function: the cost of each expression
+    /// evaluated in it, times the number of evaluations.
+    pub fn function_costs(&self, cost_model: &impl CostModel) -> IndexMap<String, f64> {
+        let mut costs = IndexMap::<String, f64>::new();
+        for profile in self.exprs() {
+            *costs.entry(profile.function.clone()).or_default() +=
+                node_cost(&profile.expr, cost_model) * profile.evals as f64;
+        }
+        costs
+    }
+
+    pub fn total_cost(&self, cost_model: &impl CostModel) -> f64 {
+        self.function_costs(cost_model).values().sum()
+    }
+
+    /// A summary of the calls and cost of each function,
+    /// and the iterations of each loop.
+    pub fn summary(&self, cost_model: &impl CostModel) -> String {
+        let costs = self.function_costs(cost_model);
+        let mut res = format!("total cost: {}\n", self.total_cost(cost_model));
+        for (func, calls) in &self.calls {
+            let evals: u64 = self
+                .exprs()
+                .filter(|profile| &profile.function == func)
+                .map(|profile| profile.evals)
+                .sum();
+            let cost = costs.get(func).copied().unwrap_or_default();
+            writeln!(
+                res,
+                "{func}: {calls} calls, {evals} evaluations, cost {cost}"
+            )
+            .unwrap();
+            for profile in self.exprs() {
+                if &profile.function == func && matches!(profile.expr.as_ref(), Expr::DoWhile(..)) {
+                    writeln!(
+                        res,
+                        "  loop: {} runs, {:.1} iterations per run",
+                        profile.evals,
+                        profile.iterations as f64 / profile.evals as f64
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        res
+    }
+}
+
+/// The cost of evaluating `expr` once, not counting its children,
+/// which are charged for their own evaluations.
+fn node_cost(expr: &RcExpr, cost_model: &impl CostModel) -> f64 {
+    let op = match expr.as_ref() {
+        Expr::Bop(op, ..) => Some(op.name()),
+        Expr::Uop(op, ..) => Some(op.name()),
+        Expr::Top(op, ..) => Some(op.name()),
+        _ => None,
+    };
+    let constructor = match expr.as_ref() {
+        // symbolic expressions are never evaluated
+        Expr::Symbolic(..) => return 0.0,
+        _ => expr.constructor(),
+    };
+    cost_model.get_op_cost(constructor.name()).into_inner()
+        + op.map_or(0.0, |op| cost_model.get_op_cost(op).into_inner())
+}
+
+#[test]
+fn test_profile_dag_prog() {
+    use crate::ast::*;
+    use crate::greedy_dag_extractor::DefaultCostModel;
+
+    // adds one to its argument three times in a loop
+    let prog = program!(
+        function("main", base(intt()), base(intt()), call("inc_loop", arg())),
+        function(
+            "inc_loop",
+            base(intt()),
+            base(intt()),
+            get(
+                dowhile(
+                    parallel!(arg(), int(0)),
+                    parallel!(
+                        less_than(add(getat(1), int(1)), int(3)),
+                        add(getat(0), int(1)),
+                        add(getat(1), int(1))
+                    )
+                ),
+                0
+            )
+        ),
+    );
+    let (value, _log, profile) =
+        profile_dag_prog(&prog, &intv(5), &InterpretConfig::default()).unwrap();
+    assert_eq!(value, intv(8));
+    assert_eq!(profile.calls["main"], 1);
+    assert_eq!(profile.calls["inc_loop"], 1);
+
+    let dowhile = profile
+        .exprs()
+        .find(|profile| matches!(profile.expr.as_ref(), Expr::DoWhile(..)))
+        .unwrap();
+    assert_eq!(dowhile.function, "inc_loop");
+    assert_eq!((dowhile.evals, dowhile.iterations), (1, 3));
+
+    // the loop body's additions run once per iteration
+    let costs = profile.function_costs(&DefaultCostModel);
+    let add_cost = DefaultCostModel.get_op_cost("Add").into_inner();
+    assert!(costs["inc_loop"] >= 3.0 * 3.0 * add_cost);
+    assert_eq!(
+        profile.total_cost(&DefaultCostModel),
+        costs.values().sum::<f64>()
+    );
+}
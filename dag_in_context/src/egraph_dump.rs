@@ -0,0 +1,186 @@
+//! Dumps of the serialized egraphs that `optimize` extracts from,
+//! so that extraction can be re-run without running the egglog schedules again.
+//! `optimize` writes one dump per pass and batch when `EggccConfig::dump_egraphs` is set.
+//!
+//! Each dump is a JSON file named `passNNN-batchNNN.json` holding an `EgraphDump`:
+//! the egraph in the JSON format of `egraph_serialize`, the root eclass of each
+//! function in the batch, and the other fields extraction needs.
+//! The program before extraction uses the JSON encoding of tree programs (see `tree_json`).
+
+use std::path::{Path, PathBuf};
+
+use egglog::TermDag;
+use egraph_serialize::ClassId;
+use indexmap::IndexSet;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    greedy_dag_extractor::{extract_with_report, get_root, has_debug_exprs, DefaultCostModel},
+    schema::TreeProgram,
+};
+
+#[derive(Debug, Error)]
+pub enum EgraphDumpError {
+    #[error("Could not read egraph dump: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Egraph dump is not valid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EgraphDump {
+    pub pass: usize,
+    /// The functions extracted from this egraph.
+    pub batch: Vec<String>,
+    /// The root eclass of each function in `batch`.
+    pub roots: Vec<(String, ClassId)>,
+    pub unextractables: Vec<String>,
+    pub should_maintain_linearity: bool,
+    /// The whole program before extraction.
+    /// Extraction replaces the functions in `batch` and typechecks against the rest.
+    pub program: TreeProgram,
+    pub egraph: egraph_serialize::EGraph,
+}
+
+impl EgraphDump {
+    pub(crate) fn new(
+        pass: usize,
+        batch: &[String],
+        program: &TreeProgram,
+        egraph: &egraph_serialize::EGraph,
+        unextractables: &IndexSet<String>,
+        should_maintain_linearity: bool,
+    ) -> EgraphDump {
+        let roots = batch
+            .iter()
+            .map(|func| {
+                (
+                    func.clone(),
+                    egraph.nid_to_cid(&get_root(egraph, func)).clone(),
+                )
+            })
+            .collect();
+        EgraphDump {
+            pass,
+            batch: batch.to_vec(),
+            roots,
+            unextractables: unextractables.iter().cloned().collect(),
+            should_maintain_linearity,
+            program: program.clone(),
+            egraph: egraph.clone(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<EgraphDump, EgraphDumpError> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Writes the dump to `dir`, named after the pass and
+    /// how many batches of the pass were dumped before this one.
+    /// Failing to write the dump only produces a warning.
+    pub(crate) fn store(&self, dir: &Path, batch_index: usize) {
+        let path = dump_path(dir, self.pass, batch_index);
+        let write = || -> Result<(), EgraphDumpError> {
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(&path, serde_json::to_string(self)?)?;
+            Ok(())
+        };
+        if let Err(err) = write() {
+            eprintln!(
+                "Warning: failed to write egraph dump {}: {err}",
+                path.display()
+            );
+        }
+    }
+
+    /// Extracts the functions in `batch` from the egraph with the default cost model,
+    /// returning the program with those functions replaced and the total cost of the batch.
+    pub fn extract(&self) -> (TreeProgram, f64) {
+        let (cost, extracted, _report) = extract_with_report(
+            &self.program,
+            self.batch.clone(),
+            self.egraph.clone(),
+            self.unextractables.iter().cloned().collect(),
+            &mut TermDag::default(),
+            DefaultCostModel,
+            self.should_maintain_linearity,
+            has_debug_exprs(&self.egraph),
+            false,
+        );
+        (extracted, cost.into_inner())
+    }
+}
+
+/// `pass003-batch001.json` for the second batch of the fourth pass,
+/// so that dumps sort in the order they were written.
+fn dump_path(dir: &Path, pass: usize, batch_index: usize) -> PathBuf {
+    dir.join(format!("pass{pass:03}-batch{batch_index:03}.json"))
+}
+
+/// The dumps in `dir`, in the order they were written.
+pub fn dumps_in_dir(dir: &Path) -> Result<Vec<PathBuf>, EgraphDumpError> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| {
+        path.extension().is_some_and(|ext| ext == "json")
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("pass"))
+    });
+    paths.sort();
+    Ok(paths)
+}
+
+#[test]
+fn test_extract_from_dump() {
+    use crate::ast::*;
+    use crate::{are_progs_eq, optimize, EggccConfig};
+
+    let prog = program!(
+        function(
+            "main",
+            tuplet!(intt(), statet()),
+            tuplet!(intt(), statet()),
+            parallel!(add(int(1), int(2)), getat(1))
+        ),
+        function(
+            "other",
+            tuplet!(intt(), statet()),
+            tuplet!(intt(), statet()),
+            parallel!(mul(getat(0), int(1)), getat(1))
+        ),
+    );
+    let dir = std::env::temp_dir().join(format!("eggcc-dump-test-{}", std::process::id()));
+    let config = EggccConfig {
+        stop_after_n_passes: 1,
+        dump_egraphs: Some(dir.clone()),
+        ..Default::default()
+    };
+    let optimized = optimize(&prog, &config).unwrap();
+
+    let dumps = dumps_in_dir(&dir).unwrap();
+    assert_eq!(dumps.len(), 1);
+    let dump = EgraphDump::load(&dumps[0]).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(dump.pass, 0);
+    assert_eq!(dump.batch, vec!["main".to_string(), "other".to_string()]);
+    assert_eq!(
+        dump.roots
+            .iter()
+            .map(|(func, _root)| func.clone())
+            .collect::<Vec<_>>(),
+        dump.batch
+    );
+
+    // the program before the first pass is the input program
+    assert!(are_progs_eq(dump.program.clone(), prog));
+
+    // extracting again gives the same program as the pass did
+    let (extracted, cost) = dump.extract();
+    assert!(cost > 0.0);
+    assert!(are_progs_eq(extracted.add_context().0, optimized));
+}
@@ -0,0 +1,217 @@
+//! User-supplied egglog rules, appended to the prologue.
+//! Every ruleset declared by the rules joins `all-optimizations`,
+//! so the built-in schedules run them alongside the built-in rulesets.
+
+use std::path::Path;
+
+use indexmap::IndexSet;
+use thiserror::Error;
+
+use crate::prologue_with_extra_rules;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtraRules {
+    /// Egglog source for the rules.
+    pub source: String,
+    /// Rulesets declared in `source`, in order of declaration.
+    pub rulesets: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum ExtraRulesError {
+    #[error("Could not read extra rules file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Extra rules use constructors that are not defined by eggcc or the rules file: {}",
+        .0.iter().map(|(name, line)| format!("`{name}` (line {line})")).collect::<Vec<_>>().join(", "))]
+    UnknownConstructors(Vec<(String, usize)>),
+    #[error("Extra rules failed to load: {0}")]
+    Egglog(egglog::Error),
+}
+
+impl ExtraRules {
+    pub fn load(path: &Path) -> Result<ExtraRules, ExtraRulesError> {
+        ExtraRules::parse(std::fs::read_to_string(path)?)
+    }
+
+    /// Finds the rulesets declared in `source` and checks that
+    /// the rules load after the prologue.
+    pub fn parse(source: String) -> Result<ExtraRules, ExtraRulesError> {
+        let tokens = tokenize(&source);
+        let rulesets = tokens
+            .windows(3)
+            .filter_map(|window| match window {
+                [(Token::Open, _), (Token::Symbol("ruleset"), _), (Token::Symbol(name), _)] => {
+                    Some(name.to_string())
+                }
+                _ => None,
+            })
+            .collect();
+        let rules = ExtraRules { source, rulesets };
+
+        if let Err(err) = egglog::EGraph::default()
+            .parse_and_run_program(None, &prologue_with_extra_rules(Some(&rules)))
+        {
+            // egglog's error for an unknown constructor doesn't say where it is,
+            // so look for them ourselves to give a better message
+            let unknown = rules.unknown_constructors();
+            if !unknown.is_empty() {
+                return Err(ExtraRulesError::UnknownConstructors(unknown));
+            }
+            return Err(ExtraRulesError::Egglog(err));
+        }
+        Ok(rules)
+    }
+
+    /// Constructors (capitalized names in head position) used by the rules that
+    /// are declared neither by the prologue nor by the rules themselves,
+    /// along with the line they are first used on.
+    fn unknown_constructors(&self) -> Vec<(String, usize)> {
+        let prologue = prologue_with_extra_rules(None);
+        let mut declared = declared_names(&tokenize(&prologue));
+        let tokens = tokenize(&self.source);
+        declared.extend(declared_names(&tokens));
+
+        let mut unknown: Vec<(String, usize)> = vec![];
+        for window in tokens.windows(2) {
+            if let [(Token::Open, _), (Token::Symbol(name), line)] = window {
+                let is_constructor = name.starts_with(|c: char| c.is_ascii_uppercase());
+                if is_constructor
+                    && !declared.contains(*name)
+                    && !unknown.iter().any(|(seen, _)| seen == name)
+                {
+                    unknown.push((name.to_string(), *line));
+                }
+            }
+        }
+        unknown
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Open,
+    Close,
+    Symbol(&'a str),
+}
+
+/// Splits egglog source into parentheses and symbols, each with its line number.
+/// Comments and string literals are dropped.
+fn tokenize(source: &str) -> Vec<(Token, usize)> {
+    let mut tokens = vec![];
+    for (line_index, mut line) in source.lines().enumerate() {
+        let line_number = line_index + 1;
+        loop {
+            line = line.trim_start();
+            let Some(c) = line.chars().next() else {
+                break;
+            };
+            match c {
+                ';' => break,
+                '(' => {
+                    tokens.push((Token::Open, line_number));
+                    line = &line[1..];
+                }
+                ')' => {
+                    tokens.push((Token::Close, line_number));
+                    line = &line[1..];
+                }
+                '"' => {
+                    // strings in rules don't span lines
+                    line = match line[1..].find('"') {
+                        Some(end) => &line[end + 2..],
+                        None => "",
+                    };
+                }
+                _ => {
+                    let end = line
+                        .find(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | ';' | '"'))
+                        .unwrap_or(line.len());
+                    tokens.push((Token::Symbol(&line[..end]), line_number));
+                    line = &line[end..];
+                }
+            }
+        }
+    }
+    tokens
+}
+
+/// Names of the functions, relations, sorts and datatype variants declared in `tokens`.
+fn declared_names(tokens: &[(Token, usize)]) -> IndexSet<String> {
+    let mut declared = IndexSet::new();
+    // depth of the datatype declaration we are inside of, if any
+    let mut datatype_depth: Option<usize> = None;
+    let mut depth = 0;
+    for (i, (token, _line)) in tokens.iter().enumerate() {
+        match token {
+            Token::Open => {
+                depth += 1;
+                let head = tokens.get(i + 1).map(|(token, _)| *token);
+                let name = tokens.get(i + 2).map(|(token, _)| *token);
+                match (head, name) {
+                    (
+                        Some(Token::Symbol(
+                            "function" | "relation" | "constructor" | "sort" | "datatype",
+                        )),
+                        Some(Token::Symbol(name)),
+                    ) => {
+                        declared.insert(name.to_string());
+                    }
+                    // variants of a datatype
+                    (Some(Token::Symbol(name)), _) if datatype_depth.is_some() => {
+                        declared.insert(name.to_string());
+                    }
+                    _ => {}
+                }
+                if datatype_depth.is_none()
+                    && matches!(head, Some(Token::Symbol("datatype" | "datatype*")))
+                {
+                    datatype_depth = Some(depth);
+                }
+            }
+            Token::Close => {
+                if datatype_depth == Some(depth) {
+                    datatype_depth = None;
+                }
+                depth -= 1;
+            }
+            Token::Symbol(_) => {}
+        }
+    }
+    declared
+}
+
+#[test]
+fn test_extra_rules() {
+    let rules = ExtraRules::parse(
+        "
+(ruleset my-peepholes)
+(rewrite (Bop (Mul) x (Const (Int 2) ty ctx))
+         (Bop (Add) x x)
+         :ruleset my-peepholes)
+"
+        .to_string(),
+    )
+    .unwrap();
+    assert_eq!(rules.rulesets, vec!["my-peepholes".to_string()]);
+    assert!(prologue_with_extra_rules(Some(&rules)).contains("my-peepholes"));
+
+    let err = ExtraRules::parse(
+        "
+(ruleset my-peepholes)
+(rewrite (Bop (Mull) x y)
+         (Bop (Mul) y x)
+         :ruleset my-peepholes)
+"
+        .to_string(),
+    )
+    .unwrap_err();
+    match err {
+        ExtraRulesError::UnknownConstructors(unknown) => {
+            assert_eq!(unknown, vec![("Mull".to_string(), 3)]);
+        }
+        _ => panic!("Expected an unknown constructor error, got {err}"),
+    }
+
+    let err = ExtraRules::parse("(rewrite (Bop (Mul) x y)".to_string()).unwrap_err();
+    assert!(matches!(err, ExtraRulesError::Egglog(_)));
+}
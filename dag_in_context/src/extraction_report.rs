@@ -0,0 +1,350 @@
+//! Explains the choices made by the greedy dag extractor.
+//! For each extracted function, the report breaks the extracted cost
+//! down by region and by operator, and lists the alternative enodes
+//! that were available for the function body and its most expensive loops.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use egraph_serialize::{ClassId, NodeId};
+use serde::Serialize;
+
+use crate::greedy_dag_extractor::{
+    enode_children, node_cost_in_region, CostSet, EgraphInfo, EnodeChild, Extractor,
+};
+
+/// How many loops to report alternatives for, per function.
+const MAX_REPORTED_LOOPS: usize = 5;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ExtractionReport {
+    pub functions: Vec<FunctionCostReport>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FunctionCostReport {
+    pub name: String,
+    /// Total cost of the extracted function, taking sharing into account.
+    pub total_cost: f64,
+    /// Cost attributed to each region, in the order regions were visited.
+    /// Costs of nested regions are not included in their parent's cost.
+    pub regions: Vec<RegionCost>,
+    /// Cost attributed to each operator, summed over all regions.
+    pub cost_by_op: BTreeMap<String, f64>,
+    /// Alternatives for the function body and the most expensive loops.
+    pub alternatives: Vec<EclassAlternatives>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RegionCost {
+    /// The eclass of the region root.
+    pub eclass: String,
+    /// The operator chosen for the region root.
+    pub root_op: String,
+    /// How many times the region is expected to run,
+    /// e.g. the loop iteration guess for loop bodies.
+    pub multiplier: f64,
+    /// Cost of the nodes in this region, already multiplied by `multiplier`.
+    pub cost: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EclassAlternatives {
+    /// What this eclass is, either "function body" or "loop".
+    pub kind: String,
+    pub eclass: String,
+    /// The operator that extraction chose for this eclass.
+    pub chosen_op: String,
+    pub candidates: Vec<Candidate>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Candidate {
+    pub node: String,
+    pub op: String,
+    /// Cost of the term rooted at this enode, or `None` when no term
+    /// could be built for it (a cycle, an unextractable op, or unextracted children).
+    pub cost: Option<f64>,
+}
+
+impl<'a> Extractor<'a> {
+    /// Builds a report explaining the cost of `root_costset`.
+    /// Must be called after extraction of `func` has finished.
+    pub(crate) fn function_cost_report(
+        &mut self,
+        func: &str,
+        info: &EgraphInfo,
+        func_root: ClassId,
+        root_costset: &CostSet,
+    ) -> FunctionCostReport {
+        let mut report = FunctionCostReport {
+            name: func.to_string(),
+            total_cost: root_costset.total.into_inner(),
+            regions: vec![],
+            cost_by_op: BTreeMap::new(),
+            alternatives: vec![],
+        };
+        // (loop region, loop eclass, cost of the loop)
+        let mut loops: Vec<(ClassId, ClassId, f64)> = vec![];
+        self.region_breakdown(info, func_root, root_costset, 1.0, &mut report, &mut loops);
+
+        // the body of the function is the only subregion of the function node
+        let func_node = &info.egraph[&self.term_node(&root_costset.term)];
+        if let Some(body) = enode_children(info.egraph, func_node)
+            .into_iter()
+            .find(|child| child.is_subregion)
+        {
+            let alternatives =
+                self.eclass_alternatives(info, "function body", body.child.clone(), body.child);
+            report.alternatives.extend(alternatives);
+        }
+
+        loops.sort_by(|(_, _, a), (_, _, b)| b.total_cmp(a));
+        for (region, eclass, _cost) in loops.into_iter().take(MAX_REPORTED_LOOPS) {
+            let alternatives = self.eclass_alternatives(info, "loop", region, eclass);
+            report.alternatives.extend(alternatives);
+        }
+
+        report
+    }
+
+    /// Attributes the costs in `costset` (the cost set of the region rooted at `region`)
+    /// to operators, recurring into subregions.
+    fn region_breakdown(
+        &self,
+        info: &EgraphInfo,
+        region: ClassId,
+        costset: &CostSet,
+        multiplier: f64,
+        report: &mut FunctionCostReport,
+        loops: &mut Vec<(ClassId, ClassId, f64)>,
+    ) {
+        let region_index = report.regions.len();
+        report.regions.push(RegionCost {
+            eclass: region.to_string(),
+            root_op: info.egraph[&self.term_node(&costset.term)].op.clone(),
+            multiplier,
+            cost: 0.0,
+        });
+
+        // sort by eclass so that the report is deterministic
+        let mut entries = costset.costs.iter().collect::<Vec<_>>();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut region_cost = 0.0;
+        for (eclass, (term, unshared_cost)) in entries {
+            let nodeid = self.term_node(term);
+            let node = &info.egraph[&nodeid];
+            let mut own_cost = unshared_cost.into_inner();
+
+            if !info.cm.ignore_children(&node.op) {
+                for EnodeChild { child, .. } in enode_children(info.egraph, node)
+                    .into_iter()
+                    .filter(|child| child.is_subregion)
+                {
+                    let Some(child_index) = self.costs.get(&child).and_then(|c| c.get(&child))
+                    else {
+                        continue;
+                    };
+                    let child_set = &self.costsets[*child_index];
+                    let subregion_cost = self.subregion_cost(info, nodeid.clone(), child_set);
+                    own_cost -= subregion_cost.into_inner();
+                    let child_multiplier = if child_set.total.into_inner() > 0.0 {
+                        subregion_cost.into_inner() / child_set.total.into_inner()
+                    } else {
+                        1.0
+                    };
+                    self.region_breakdown(
+                        info,
+                        child.clone(),
+                        child_set,
+                        multiplier * child_multiplier,
+                        report,
+                        loops,
+                    );
+                }
+            }
+
+            if node.op == "DoWhile" {
+                loops.push((
+                    region.clone(),
+                    eclass.clone(),
+                    unshared_cost.into_inner() * multiplier,
+                ));
+            }
+
+            *report.cost_by_op.entry(node.op.clone()).or_default() += own_cost * multiplier;
+            region_cost += own_cost * multiplier;
+        }
+        report.regions[region_index].cost = region_cost;
+    }
+
+    /// Computes the cost of every enode in `eclass` within `region`,
+    /// using the cost sets found during extraction for the children.
+    fn eclass_alternatives(
+        &mut self,
+        info: &EgraphInfo,
+        kind: &str,
+        region: ClassId,
+        eclass: ClassId,
+    ) -> Option<EclassAlternatives> {
+        let chosen_index = *self.costs.get(&region)?.get(&eclass)?;
+        let chosen_op = info.egraph[&self.term_node(&self.costsets[chosen_index].term)]
+            .op
+            .clone();
+
+        let nodes: Vec<NodeId> = info.egraph.classes()[&eclass].nodes.clone();
+        let mut candidates = vec![];
+        for nodeid in nodes {
+            let op = info.egraph[&nodeid].op.clone();
+            let cost = if info.unextractables.contains(&op) {
+                None
+            } else {
+                node_cost_in_region(region.clone(), nodeid.clone(), self, info)
+                    .map(|index| self.costsets[index].total.into_inner())
+                    .filter(|cost| cost.is_finite())
+            };
+            candidates.push(Candidate {
+                node: nodeid.to_string(),
+                op,
+                cost,
+            });
+        }
+        candidates.sort_by(|a, b| match (a.cost, b.cost) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        });
+
+        Some(EclassAlternatives {
+            kind: kind.to_string(),
+            eclass: eclass.to_string(),
+            chosen_op,
+            candidates,
+        })
+    }
+}
+
+impl Display for ExtractionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for func in &self.functions {
+            writeln!(
+                f,
+                "function {}: total cost {:.2}",
+                func.name, func.total_cost
+            )?;
+
+            writeln!(f, "  cost by region:")?;
+            for region in &func.regions {
+                writeln!(
+                    f,
+                    "    {} ({}, x{}): {:.2}",
+                    region.eclass, region.root_op, region.multiplier, region.cost
+                )?;
+            }
+
+            writeln!(f, "  cost by operator:")?;
+            let mut ops = func.cost_by_op.iter().collect::<Vec<_>>();
+            ops.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+            for (op, cost) in ops {
+                if *cost != 0.0 {
+                    writeln!(f, "    {op}: {cost:.2}")?;
+                }
+            }
+
+            for alternatives in &func.alternatives {
+                writeln!(
+                    f,
+                    "  alternatives for {} {} (chose {}):",
+                    alternatives.kind, alternatives.eclass, alternatives.chosen_op
+                )?;
+                for candidate in &alternatives.candidates {
+                    match candidate.cost {
+                        Some(cost) => writeln!(f, "    {}: {:.2}", candidate.op, cost)?,
+                        None => writeln!(f, "    {}: not extractable", candidate.op)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_extraction_report() {
+    use crate::ast::*;
+    use crate::greedy_dag_extractor::{extract_with_report, serialized_egraph, TestCostModel};
+    use crate::{print_with_intermediate_vars, prologue};
+
+    let prog = program!(function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        parallel!(
+            add(
+                int(10),
+                get(
+                    dowhile(
+                        parallel!(getat(0)),
+                        push(
+                            add(getat(0), int(10)),
+                            single(less_than(add(getat(0), int(10)), int(10)))
+                        )
+                    ),
+                    0
+                )
+            ),
+            getat(1)
+        )
+    ),);
+    let string_prog = {
+        let (term, termdag) = prog.to_egglog();
+        let printed = print_with_intermediate_vars(&termdag, term);
+        format!("{}\n{printed}\n", prologue(),)
+    };
+
+    let mut egraph = egglog::EGraph::default();
+    egraph.parse_and_run_program(None, &string_prog).unwrap();
+    let (serialized_egraph, unextractables) = serialized_egraph(egraph);
+    let mut termdag = egglog::TermDag::default();
+
+    let (cost, _extracted, report) = extract_with_report(
+        &prog,
+        prog.fns(),
+        serialized_egraph,
+        unextractables,
+        &mut termdag,
+        TestCostModel,
+        true,
+        false,
+        true,
+    );
+    let report = report.unwrap();
+    assert_eq!(report.functions.len(), 1);
+    let main = &report.functions[0];
+    assert_eq!(main.name, "main");
+    assert_eq!(main.total_cost, cost.into_inner());
+
+    // every bit of cost is attributed to exactly one region and one operator
+    let region_total: f64 = main.regions.iter().map(|region| region.cost).sum();
+    let op_total: f64 = main.cost_by_op.values().sum();
+    assert!((region_total - main.total_cost).abs() < 1e-6);
+    assert!((op_total - main.total_cost).abs() < 1e-6);
+    // the loop body runs the default guess of 1000 times
+    assert!(main.regions.iter().any(|region| region.multiplier == 1000.));
+
+    let kinds = main
+        .alternatives
+        .iter()
+        .map(|alternatives| alternatives.kind.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(kinds, vec!["function body", "loop"]);
+    let loop_alternatives = &main.alternatives[1];
+    assert_eq!(loop_alternatives.chosen_op, "DoWhile");
+    assert!(loop_alternatives
+        .candidates
+        .iter()
+        .any(|candidate| candidate.op == "DoWhile" && candidate.cost.is_some()));
+
+    assert!(report.to_string().contains("function main: total cost"));
+}
@@ -11,9 +11,11 @@ use std::{
 use strum::IntoEnumIterator;
 
 use crate::{
+    extraction_report::ExtractionReport,
     from_egglog::FromEgglog,
     schema::{Expr, RcExpr, TreeProgram, Type},
     schema_helpers::Sort,
+    to_egglog::TreeToEgglog,
     typechecker::TypeChecker,
 };
 
@@ -31,17 +33,20 @@ pub(crate) struct EgraphInfo<'a> {
     /// This is found by looking at LoopNumItersGuess in the database.
     pub(crate) loop_iteration_estimates: IndexMap<(RootId, RootId), i64>,
     /// A set of names of functions that are unextractable
-    unextractables: IndexSet<String>,
+    pub(crate) unextractables: IndexSet<String>,
     /// A set of (func args) of calls that have been inlined, to indicate we shouldn't
     /// extract the corresponding (Call func args).
     inlined_calls: IndexSet<(ClassId, ClassId)>,
+    /// Enodes that extraction must not pick.
+    /// Used to find alternative programs, see `extract_top_k` and `extract_eclass_alternatives`.
+    pub(crate) banned_nodes: IndexSet<NodeId>,
 }
 
 pub(crate) struct Extractor<'a> {
     pub(crate) termdag: &'a mut TermDag,
-    costsets: Vec<CostSet>,
+    pub(crate) costsets: Vec<CostSet>,
     costsetmemo: IndexMap<(NodeId, Vec<CostSetIndex>), CostSetIndex>,
-    costs: IndexMap<ClassId, IndexMap<ClassId, CostSetIndex>>,
+    pub(crate) costs: IndexMap<ClassId, IndexMap<ClassId, CostSetIndex>>,
 
     // use to get the type of an expression
     pub(crate) typechecker: TypeChecker<'a>,
@@ -232,6 +237,7 @@ impl<'a> EgraphInfo<'a> {
             roots,
             loop_iteration_estimates,
             inlined_calls,
+            banned_nodes: IndexSet::new(),
         }
     }
 }
@@ -400,8 +406,8 @@ pub fn serialized_egraph(
     (egraph, get_unextractables(&egglog_egraph))
 }
 
-type Cost = NotNan<f64>;
-type CostSetIndex = usize;
+pub(crate) type Cost = NotNan<f64>;
+pub(crate) type CostSetIndex = usize;
 
 #[derive(Clone, Debug)]
 pub struct CostSet {
@@ -516,7 +522,12 @@ impl<'a> Extractor<'a> {
 
     // Get the cost of a subregion
     // For DoWhile nodes, use special logic to calculate the cost based on iteration count
-    fn subregion_cost(&self, info: &EgraphInfo, nodeid: NodeId, child_set: &CostSet) -> Cost {
+    pub(crate) fn subregion_cost(
+        &self,
+        info: &EgraphInfo,
+        nodeid: NodeId,
+        child_set: &CostSet,
+    ) -> Cost {
         let node = info.egraph.nodes.get(&nodeid).unwrap();
 
         if node.op == "DoWhile" {
@@ -667,7 +678,7 @@ impl<'a> Extractor<'a> {
 /// This function handles finding children cost sets for a node in a particular region.
 /// It then calculates the resulting cost set using `calculate_cost_set`.
 /// Returns `None` when a cycle is found.
-fn node_cost_in_region(
+pub(crate) fn node_cost_in_region(
     rootid: ClassId,
     node_id: NodeId,
     extractor: &mut Extractor,
@@ -704,6 +715,8 @@ fn node_cost_in_region(
     extractor.calculate_cost_set(node_id, child_cost_sets, info)
 }
 
+/// Extracts `func`, returning `None` when it can't be extracted
+/// (or can't be extracted linearly, when `should_maintain_linearity` is set).
 #[allow(clippy::too_many_arguments)]
 fn extract_fn(
     original_prog: &TreeProgram,
@@ -714,36 +727,26 @@ fn extract_fn(
     termdag: &mut TermDag,
     cost_model: &impl CostModel,
     should_maintain_linearity: bool,
-) -> (CostSet, RcExpr) {
+    report: Option<&mut ExtractionReport>,
+) -> Option<(CostSet, RcExpr)> {
     log::info!("Building extraction info");
     let egraph_info = EgraphInfo::new(func, rootid.clone(), cost_model, &egraph, unextractables);
-    let extractor_not_linear = &mut Extractor::new(original_prog, termdag);
-
-    let (cost_res, res) = extract_with_paths(
+    let extractor = &mut Extractor::new(original_prog, termdag);
+    let (cost_res, res) = try_extract_fn(
         func,
         rootid.clone(),
-        extractor_not_linear,
+        extractor,
         &egraph_info,
-        None,
-    );
+        should_maintain_linearity,
+    )?;
 
-    if !should_maintain_linearity {
-        (cost_res, res)
-    } else {
-        let effectful_nodes_along_path =
-            extractor_not_linear.find_effectful_nodes_in_function(&res, &egraph_info);
-        extractor_not_linear.costs.clear();
-        let (cost_res, res) = extract_with_paths(
-            func,
-            rootid,
-            extractor_not_linear,
-            &egraph_info,
-            Some(&effectful_nodes_along_path),
-        );
-        extractor_not_linear.check_function_is_linear(&res).unwrap();
-
-        (cost_res, res)
+    if let Some(report) = report {
+        log::info!("Building extraction report for {}", func);
+        let fn_report = extractor.function_cost_report(func, &egraph_info, rootid, &cost_res);
+        report.functions.push(fn_report);
     }
+
+    Some((cost_res, res))
 }
 
 /// Returns the roots of DebugExpr relation and fresh names
@@ -776,6 +779,68 @@ pub fn extract(
     should_maintain_linearity: bool,
     extract_debug_exprs: bool,
 ) -> (Cost, TreeProgram) {
+    let (cost, prog, _report) = extract_with_report(
+        original_prog,
+        fns,
+        egraph,
+        unextractables,
+        termdag,
+        cost_model,
+        should_maintain_linearity,
+        extract_debug_exprs,
+        false,
+    );
+    (cost, prog)
+}
+
+/// Like `extract`, but when `build_report` is true also returns an
+/// `ExtractionReport` explaining the cost of each extracted function.
+#[allow(clippy::too_many_arguments)]
+pub fn extract_with_report(
+    original_prog: &TreeProgram,
+    fns: Vec<String>,
+    egraph: egraph_serialize::EGraph,
+    unextractables: IndexSet<String>,
+    termdag: &mut TermDag,
+    cost_model: impl CostModel,
+    should_maintain_linearity: bool,
+    extract_debug_exprs: bool,
+    build_report: bool,
+) -> (Cost, TreeProgram, Option<ExtractionReport>) {
+    let (cost, prog, report, failed) = extract_with_fallback(
+        original_prog,
+        fns,
+        egraph,
+        unextractables,
+        termdag,
+        cost_model,
+        should_maintain_linearity,
+        extract_debug_exprs,
+        build_report,
+    );
+    if let Some(func) = failed.first() {
+        panic!("Failed to extract function {}!", func);
+    }
+    (cost, prog, report)
+}
+
+/// Like `extract_with_report`, but functions that can't be extracted keep
+/// their version from `original_prog` instead of panicking.
+/// Also returns the functions that kept their original version,
+/// whose cost is not included in the total.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn extract_with_fallback(
+    original_prog: &TreeProgram,
+    fns: Vec<String>,
+    egraph: egraph_serialize::EGraph,
+    unextractables: IndexSet<String>,
+    termdag: &mut TermDag,
+    cost_model: impl CostModel,
+    should_maintain_linearity: bool,
+    extract_debug_exprs: bool,
+    build_report: bool,
+) -> (Cost, TreeProgram, Option<ExtractionReport>, Vec<String>) {
+    let mut report = build_report.then(ExtractionReport::default);
     if extract_debug_exprs {
         log::info!("Extracting debug expressions.");
         let debug_roots = find_debug_roots(egraph.clone());
@@ -792,7 +857,9 @@ pub fn extract(
                 termdag,
                 &cost_model,
                 false,
-            );
+                report.as_mut(),
+            )
+            .unwrap_or_else(|| panic!("Failed to extract debug expression {}!", name));
             total_cost += cost.total;
             let output_ty = typechecker
                 .add_arg_types_to_expr(extracted.clone(), &None)
@@ -807,12 +874,13 @@ pub fn extract(
             entry: extracted_fns[0].clone(),
             functions: extracted_fns[1..].to_vec(),
         };
-        (total_cost, new_prog)
+        (total_cost, new_prog, report, vec![])
     } else {
         let mut new_prog = original_prog.clone();
         let mut cost = NotNan::new(0.).unwrap();
+        let mut failed = vec![];
         for func in fns {
-            let (fn_cost, extracted) = extract_fn(
+            let Some((fn_cost, extracted)) = extract_fn(
                 &new_prog,
                 &func,
                 egraph.nid_to_cid(&get_root(&egraph, &func)).clone(),
@@ -821,12 +889,228 @@ pub fn extract(
                 termdag,
                 &cost_model,
                 should_maintain_linearity,
-            );
+                report.as_mut(),
+            ) else {
+                failed.push(func);
+                continue;
+            };
             new_prog.replace_fn(&func, extracted);
             cost += fn_cost.total;
         }
-        (cost, new_prog)
+        (cost, new_prog, report, failed)
+    }
+}
+
+/// Extracts `func` using an existing `info`, returning `None` instead of
+/// panicking when the function can't be extracted, or when
+/// the result doesn't maintain linearity.
+fn try_extract_fn(
+    func: &str,
+    rootid: ClassId,
+    extractor: &mut Extractor,
+    info: &EgraphInfo,
+    should_maintain_linearity: bool,
+) -> Option<(CostSet, RcExpr)> {
+    let (cost_res, res) = try_extract_with_paths(func, rootid.clone(), extractor, info, None)?;
+    if !should_maintain_linearity {
+        return Some((cost_res, res));
+    }
+
+    let effectful_nodes_along_path = extractor.find_effectful_nodes_in_function(&res, info);
+    extractor.costs.clear();
+    let (cost_res, res) = try_extract_with_paths(
+        func,
+        rootid,
+        extractor,
+        info,
+        Some(&effectful_nodes_along_path),
+    )?;
+    extractor.check_function_is_linear(&res).ok()?;
+    Some((cost_res, res))
+}
+
+impl<'a> Extractor<'a> {
+    /// Collects the enodes chosen in `costset`, including the ones in subregions,
+    /// along with their cost (excluding children).
+    fn chosen_nodes(&self, info: &EgraphInfo, costset: &CostSet, res: &mut IndexMap<NodeId, Cost>) {
+        for (_eclass, (term, cost)) in costset.costs.iter() {
+            let nodeid = self.term_node(term);
+            if res.contains_key(&nodeid) {
+                continue;
+            }
+            res.insert(nodeid.clone(), *cost);
+            for EnodeChild { child, .. } in enode_children(info.egraph, &info.egraph[&nodeid])
+                .into_iter()
+                .filter(|child| child.is_subregion)
+            {
+                if let Some(index) = self.costs.get(&child).and_then(|c| c.get(&child)) {
+                    self.chosen_nodes(info, &self.costsets[*index], res);
+                }
+            }
+        }
+    }
+}
+
+/// Like `extract`, but finds up to `k` distinct programs for each function
+/// in `fns` instead of just one.
+/// Alternatives are found by banning one enode of the best program at a time,
+/// most expensive first, and extracting again.
+/// Returns the program made of the best candidates, along with all the candidates
+/// for each function sorted by cost (the best candidate is always first).
+/// Functions that can't be extracted keep their version from `original_prog`
+/// and have no candidates.
+#[allow(clippy::too_many_arguments)]
+pub fn extract_top_k(
+    original_prog: &TreeProgram,
+    fns: Vec<String>,
+    egraph: egraph_serialize::EGraph,
+    unextractables: IndexSet<String>,
+    termdag: &mut TermDag,
+    cost_model: impl CostModel,
+    should_maintain_linearity: bool,
+    k: usize,
+) -> (TreeProgram, IndexMap<String, Vec<(Cost, RcExpr)>>) {
+    let mut new_prog = original_prog.clone();
+    let mut all_candidates = IndexMap::new();
+    for func in fns {
+        let rootid = egraph.nid_to_cid(&get_root(&egraph, &func)).clone();
+        let mut info = EgraphInfo::new(
+            &func,
+            rootid.clone(),
+            &cost_model,
+            &egraph,
+            unextractables.clone(),
+        );
+
+        let best = {
+            let extractor = &mut Extractor::new(&new_prog, termdag);
+            try_extract_fn(
+                &func,
+                rootid.clone(),
+                extractor,
+                &info,
+                should_maintain_linearity,
+            )
+            .map(|(best_cost, best)| {
+                let mut chosen = IndexMap::new();
+                extractor.chosen_nodes(&info, &best_cost, &mut chosen);
+                (best_cost, best, chosen)
+            })
+        };
+        let Some((best_cost, best, chosen)) = best else {
+            log::info!("Failed to extract function {}, keeping the original", func);
+            continue;
+        };
+        let mut chosen = chosen
+            .into_iter()
+            .filter(|(nodeid, _cost)| info.egraph[nodeid].op != "Function")
+            .collect::<Vec<_>>();
+        // most expensive first, breaking ties by node id for determinism
+        chosen.sort_by(|(n1, c1), (n2, c2)| c2.cmp(c1).then_with(|| n1.cmp(n2)));
+
+        // used to check candidates are distinct
+        let mut converter = TreeToEgglog::new();
+        let mut seen = IndexSet::new();
+        seen.insert(best.to_egglog_with(&mut converter));
+        let mut candidates = vec![(best_cost.total, best.clone())];
+
+        // bounds the number of extra extractions when many alternatives are identical
+        let max_attempts = 4 * k;
+        for (banned, _cost) in chosen.into_iter().take(max_attempts) {
+            if candidates.len() >= k {
+                break;
+            }
+            info.banned_nodes = IndexSet::from([banned]);
+            let extractor = &mut Extractor::new(&new_prog, termdag);
+            let Some((cost, candidate)) = try_extract_fn(
+                &func,
+                rootid.clone(),
+                extractor,
+                &info,
+                should_maintain_linearity,
+            ) else {
+                continue;
+            };
+            if seen.insert(candidate.to_egglog_with(&mut converter)) {
+                candidates.push((cost.total, candidate));
+            }
+        }
+        log::info!("Found {} candidates for {}", candidates.len(), func);
+        candidates[1..].sort_by(|(c1, _), (c2, _)| c1.cmp(c2));
+
+        new_prog.replace_fn(&func, best);
+        all_candidates.insert(func, candidates);
     }
+    (new_prog, all_candidates)
+}
+
+/// Extracts the best program for `func`, along with alternatives that use
+/// other enodes in the eclasses of the best program.
+/// For each of those enodes, the rest of its eclass is banned, so that
+/// extraction has to use it (or avoid the eclass altogether).
+/// Eclasses that only alternatives use are not explored.
+/// Returns at most `max` distinct alternatives, or `None` if `func` can't be extracted.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn extract_eclass_alternatives(
+    original_prog: &TreeProgram,
+    func: &str,
+    egraph: &egraph_serialize::EGraph,
+    unextractables: IndexSet<String>,
+    termdag: &mut TermDag,
+    cost_model: impl CostModel,
+    should_maintain_linearity: bool,
+    max: usize,
+) -> Option<(RcExpr, Vec<RcExpr>)> {
+    let rootid = egraph.nid_to_cid(&get_root(egraph, func)).clone();
+    let mut info = EgraphInfo::new(func, rootid.clone(), &cost_model, egraph, unextractables);
+
+    let extractor = &mut Extractor::new(original_prog, termdag);
+    let (best_cost, best) = try_extract_fn(
+        func,
+        rootid.clone(),
+        extractor,
+        &info,
+        should_maintain_linearity,
+    )?;
+    let mut chosen = IndexMap::new();
+    extractor.chosen_nodes(&info, &best_cost, &mut chosen);
+    let eclasses = chosen
+        .keys()
+        .map(|nodeid| egraph.nid_to_cid(nodeid).clone())
+        .collect::<IndexSet<_>>();
+
+    // used to check alternatives are distinct
+    let mut converter = TreeToEgglog::new();
+    let mut seen = IndexSet::new();
+    seen.insert(best.to_egglog_with(&mut converter));
+    let mut alternatives = vec![];
+    for eclass in eclasses {
+        let nodes = &egraph.classes()[&eclass].nodes;
+        for node in nodes.iter().filter(|node| !chosen.contains_key(*node)) {
+            if alternatives.len() >= max {
+                return Some((best, alternatives));
+            }
+            info.banned_nodes = nodes
+                .iter()
+                .filter(|other| *other != node)
+                .cloned()
+                .collect();
+            let extractor = &mut Extractor::new(original_prog, termdag);
+            let Some((_cost, alternative)) = try_extract_fn(
+                func,
+                rootid.clone(),
+                extractor,
+                &info,
+                should_maintain_linearity,
+            ) else {
+                continue;
+            };
+            if seen.insert(alternative.to_egglog_with(&mut converter)) {
+                alternatives.push(alternative);
+            }
+        }
+    }
+    Some((best, alternatives))
 }
 
 /// Extract the function specified by `func` from the egraph.
@@ -840,6 +1124,33 @@ pub fn extract_with_paths(
     // effectful nodes that are in effectful_path[rootid]
     effectful_paths: Option<&IndexMap<ClassId, IndexSet<NodeId>>>,
 ) -> (CostSet, RcExpr) {
+    try_extract_with_paths(func, func_root.clone(), extractor, info, effectful_paths)
+        .unwrap_or_else(|| {
+            if !extractor.costs.contains_key(&func_root) {
+                panic!("Failed to extract function {}!", func);
+            } else if effectful_paths.is_some() {
+                panic!(
+                    "Failed to extract function {} after linear path is found!",
+                    func
+                );
+            } else {
+                panic!(
+                    "Failed to extract function {} during initial extraction!",
+                    func
+                );
+            }
+        })
+}
+
+/// Like `extract_with_paths`, but returns `None` when no term
+/// could be found for the root of the function.
+pub(crate) fn try_extract_with_paths(
+    func: &str,
+    func_root: ClassId,
+    extractor: &mut Extractor,
+    info: &EgraphInfo,
+    effectful_paths: Option<&IndexMap<ClassId, IndexSet<NodeId>>>,
+) -> Option<(CostSet, RcExpr)> {
     if effectful_paths.is_some() {
         log::info!("Re-extracting program after linear path is found.");
     } else {
@@ -855,7 +1166,7 @@ pub fn extract_with_paths(
     while let Some((rootid, nodeid)) = worklist.pop() {
         let classid = info.n2c(&nodeid);
         let node = info.egraph.nodes.get(&nodeid).unwrap();
-        if info.unextractables.contains(&node.op) {
+        if info.unextractables.contains(&node.op) || info.banned_nodes.contains(&nodeid) {
             continue;
         }
 
@@ -918,24 +1229,7 @@ pub fn extract_with_paths(
         }
     }
 
-    let root_costset_index = *extractor
-        .costs
-        .get(&func_root)
-        .unwrap_or_else(|| panic!("Failed to extract function {}!", func))
-        .get(&func_root)
-        .unwrap_or_else(|| {
-            if effectful_paths.is_some() {
-                panic!(
-                    "Failed to extract function {} after linear path is found!",
-                    func
-                );
-            } else {
-                panic!(
-                    "Failed to extract function {} during initial extraction!",
-                    func
-                );
-            }
-        });
+    let root_costset_index = *extractor.costs.get(&func_root)?.get(&func_root)?;
     let root_costset = extractor.costsets[root_costset_index].clone();
 
     // now run translation to expressions
@@ -951,7 +1245,7 @@ pub fn extract_with_paths(
 
     log::info!("extracted with cost {}", root_cost);
 
-    (root_costset, resulting_prog)
+    Some((root_costset, resulting_prog))
 }
 
 pub trait CostModel {
@@ -1092,10 +1386,10 @@ where
     }
 }
 
-struct EnodeChild {
-    child: ClassId,
-    is_subregion: bool,
-    is_assumption: bool,
+pub(crate) struct EnodeChild {
+    pub(crate) child: ClassId,
+    pub(crate) is_subregion: bool,
+    pub(crate) is_assumption: bool,
 }
 
 impl EnodeChild {
@@ -1110,7 +1404,7 @@ impl EnodeChild {
 
 /// For a given enode, returns a vector of children eclasses.
 /// Also, for each child returns if the child is a region root.
-fn enode_children(
+pub(crate) fn enode_children(
     egraph: &egraph_serialize::EGraph,
     enode: &egraph_serialize::Node,
 ) -> Vec<EnodeChild> {
@@ -1610,3 +1904,63 @@ pub(crate) fn has_debug_exprs(serialized_egraph: &egraph_serialize::EGraph) -> b
     }
     false
 }
+
+#[test]
+fn test_extract_top_k() {
+    use crate::ast::*;
+    use crate::interpreter::{interpret_dag_prog, Value};
+    use crate::schedule::parallel_schedule;
+    use crate::schema::Constant;
+    use crate::{are_progs_eq, build_program};
+
+    let prog = program!(function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        parallel!(add(int(1), int(2)), getat(1))
+    ),);
+    let string_prog = build_program(
+        &prog,
+        None,
+        &prog.fns(),
+        parallel_schedule()[0].egglog_schedule(),
+    );
+    let mut egraph = egglog::EGraph::default();
+    egraph.parse_and_run_program(None, &string_prog).unwrap();
+    let (serialized_egraph, unextractables) = serialized_egraph(egraph);
+    let mut termdag = TermDag::default();
+
+    let (best, candidates) = extract_top_k(
+        &prog,
+        prog.fns(),
+        serialized_egraph,
+        unextractables,
+        &mut termdag,
+        TestCostModel,
+        true,
+        3,
+    );
+    let main_candidates = &candidates["main"];
+    // constant folding gives at least one alternative to (Add 1 2)
+    assert!(main_candidates.len() >= 2);
+    assert!(main_candidates.len() <= 3);
+    assert!(are_progs_eq(
+        best.clone(),
+        TreeProgram {
+            entry: main_candidates[0].1.clone(),
+            functions: vec![],
+        }
+    ));
+    // candidates are sorted by cost after the best one
+    for window in main_candidates[1..].windows(2) {
+        assert!(window[0].0 <= window[1].0);
+    }
+
+    let arg = Value::Tuple(vec![Value::Const(Constant::Int(0)), Value::StateV]);
+    let expected = interpret_dag_prog(&prog, &arg).unwrap();
+    for (_cost, candidate) in main_candidates {
+        let mut candidate_prog = prog.clone();
+        candidate_prog.replace_fn("main", candidate.clone());
+        assert_eq!(interpret_dag_prog(&candidate_prog, &arg).unwrap(), expected);
+    }
+}
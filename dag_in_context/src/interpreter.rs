@@ -5,19 +5,25 @@
 //! The invariant is maintained by translation from RVSDG, type checking, and translation from egglog.
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Display,
     ops::{Shl, Shr},
     rc::Rc,
 };
 
+use thiserror::Error;
+
 use crate::{
+    dynamic_cost::Profile,
     schema::{BinaryOp, Constant, Expr, RcExpr, TernaryOp, TreeProgram, UnaryOp},
     tuplev,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Pointer {
+    // which allocation this pointer points into,
+    // numbered in allocation order like brilirs does
+    region: usize,
     // start address of this pointer
     start_addr: usize,
     // how many elements are in the allocated region
@@ -27,21 +33,22 @@ pub struct Pointer {
 }
 
 impl Pointer {
-    pub(crate) fn new(addr: usize, size: usize, offset: i64) -> Self {
+    pub(crate) fn new(region: usize, addr: usize, size: usize, offset: i64) -> Self {
         Pointer {
+            region,
             start_addr: addr,
             size,
             offset,
         }
     }
 
-    // gets the address of this pointer, panicking
-    // if the pointer is out of bounds
-    fn addr(&self) -> usize {
+    // gets the address of this pointer,
+    // or an error if the pointer is out of bounds
+    fn addr(&self) -> Result<usize, RuntimeErrorKind> {
         if self.offset < 0 || self.offset as usize >= self.size {
-            panic!("Pointer out of bounds {:?}", self);
+            return Err(RuntimeErrorKind::PointerOutOfBounds(self.clone()));
         }
-        self.start_addr + self.offset as usize
+        Ok(self.start_addr + self.offset as usize)
     }
 }
 
@@ -70,7 +77,10 @@ impl Value {
                     format!("{:.17}", f)
                 }
             }
-            Ptr(Pointer { .. }) => todo!("How does bril print pointers?"),
+            // same format as brilirs, so that outputs can be compared
+            Ptr(Pointer { region, offset, .. }) => {
+                format!("Pointer {{ base: {region}, offset: {offset} }}")
+            }
             Tuple(_vs) => {
                 panic!("Tried to print tuple as Bril value. There are no tuples in Bril.");
             }
@@ -88,11 +98,12 @@ impl Display for Value {
         match self {
             Const(constant) => write!(f, "{}", constant),
             Ptr(Pointer {
+                region,
                 start_addr: addr,
                 size,
                 offset,
             }) => {
-                write!(f, "Pointer::new({addr}, {size}, {offset})")
+                write!(f, "Pointer::new({region}, {addr}, {size}, {offset})")
             }
             Tuple(vs) => {
                 write!(f, "(")?;
@@ -109,18 +120,130 @@ impl Display for Value {
 use ordered_float::OrderedFloat;
 use Value::{Const, Ptr, Tuple};
 
+/// The ways a program can fail at runtime.
+/// Ill-typed programs fail with `TypeMismatch` instead of
+/// crashing the interpreter.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum RuntimeErrorKind {
+    #[error("Pointer out of bounds: {0:?}")]
+    PointerOutOfBounds(Pointer),
+    #[error("No value bound at memory address {0}")]
+    UninitializedRead(usize),
+    #[error("Switch index {index} out of bounds for {branches} branches")]
+    SwitchOutOfBounds { index: i64, branches: usize },
+    #[error("Get index {index} out of bounds for a tuple of length {len}")]
+    GetOutOfBounds { index: usize, len: usize },
+    #[error("Expected {expected}, got {found}")]
+    TypeMismatch {
+        expected: &'static str,
+        found: Value,
+    },
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Shift amount {0} out of range")]
+    ShiftOutOfRange(i64),
+    #[error("Use of freed memory: {0:?}")]
+    UseAfterFree(Pointer),
+    #[error("Double free of {0:?}")]
+    DoubleFree(Pointer),
+    #[error("Free of a pointer that is not the start of its allocation: {0:?}")]
+    InvalidFree(Pointer),
+    #[error("{0} allocations were not freed by the end of execution")]
+    MemoryLeak(usize),
+    #[error("Cannot allocate {0} entries")]
+    InvalidAllocationSize(i64),
+    #[error("Loop body produced {found} values for {expected} loop variables and a predicate")]
+    LoopArity { expected: usize, found: usize },
+    #[error("Call to unknown function {0}")]
+    UnknownFunction(String),
+    #[error("Cannot interpret {0}")]
+    Uninterpretable(&'static str),
+    #[error("Did not terminate within {0} steps")]
+    OutOfFuel(u64),
+}
+
+/// The default step budget of `interpret_dag_prog`.
+pub const DEFAULT_FUEL: u64 = 100_000_000;
+
+/// How strictly the interpreter checks uses of memory,
+/// beyond bounds checks which always happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryCheck {
+    /// Freed memory can be read and freed again.
+    Unchecked,
+    /// Loads, writes and frees of freed memory are errors,
+    /// as are frees of pointers with an offset.
+    #[default]
+    Safety,
+    /// Like `Safety`, and allocations that are still live
+    /// when the program returns are errors, like in brilirs.
+    Leaks,
+}
+
+/// Options for `interpret_dag_prog_with_config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterpretConfig {
+    pub memory_check: MemoryCheck,
+    /// How many steps the program may take before it is stopped
+    /// with `RuntimeErrorKind::OutOfFuel`, or `None` for no limit.
+    /// Evaluating an expression and running an iteration of a loop are steps.
+    pub fuel: Option<u64>,
+}
+
+impl Default for InterpretConfig {
+    fn default() -> Self {
+        InterpretConfig {
+            memory_check: MemoryCheck::default(),
+            fuel: Some(DEFAULT_FUEL),
+        }
+    }
+}
+
+/// A runtime failure, along with where it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    /// The expression that failed.
+    pub expr: RcExpr,
+    /// The functions being called when the failure happened, outermost first.
+    pub call_stack: Vec<String>,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.kind)?;
+        writeln!(f, "Call stack: {}", self.call_stack.join(" -> "))?;
+        write!(f, "In expression:\n{}", self.expr)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
 /// Keeps track of state while running
 /// the given TreeProgram.
 pub(crate) struct VirtualMachine<'a> {
     program: &'a TreeProgram,
     /// Next address for allocating memory.
     next_addr: usize,
+    /// Number of allocations so far, which is the region of the next allocation.
+    next_region: usize,
     /// All of memory
     memory: HashMap<usize, Value>,
     /// Values for already evaluated expressions
     eval_cache: HashMap<*const Expr, Value>,
     /// Print log
     log: Vec<String>,
+    /// Names of the functions currently being called
+    call_stack: Vec<String>,
+    config: InterpretConfig,
+    /// Steps taken so far
+    steps: u64,
+    /// Evaluation counts, if the program is being profiled
+    profile: Option<Profile>,
+    /// Regions of the allocations that have not been freed
+    live_allocations: HashSet<usize>,
+    /// Regions of freed allocations
+    freed_allocations: HashSet<usize>,
 }
 
 /// Represents the result of running a
@@ -138,89 +261,176 @@ pub struct BrilState {
 /// returned by the program and the print log.
 /// The interpreter relies on the invariant that common subexpressions are
 /// shared as the same Rc pointer. Otherwise, effects may be executed multiple times.
-pub fn interpret_dag_prog(prog: &TreeProgram, arg: &Value) -> (Value, Vec<String>) {
-    let mut vm = VirtualMachine {
-        program: prog,
-        next_addr: 0,
-        memory: HashMap::new(),
-        eval_cache: HashMap::new(),
-        log: vec![],
-    };
-    let ret_val = vm.interpret_call(&prog.entry.func_name().unwrap(), arg);
-    (ret_val, vm.log)
+/// Uses of freed memory are errors, but leaks are not.
+/// Programs that take more than `DEFAULT_FUEL` steps are stopped.
+pub fn interpret_dag_prog(
+    prog: &TreeProgram,
+    arg: &Value,
+) -> Result<(Value, Vec<String>), RuntimeError> {
+    interpret_dag_prog_with_config(prog, arg, &InterpretConfig::default())
+}
+
+/// Like `interpret_dag_prog`, with the memory checks and step budget from `config`.
+pub fn interpret_dag_prog_with_config(
+    prog: &TreeProgram,
+    arg: &Value,
+    config: &InterpretConfig,
+) -> Result<(Value, Vec<String>), RuntimeError> {
+    let (value, log, _profile) = interpret_dag_prog_internal(prog, arg, config, None)?;
+    Ok((value, log))
+}
+
+/// Interprets a program, filling in `profile` if it is given.
+pub(crate) fn interpret_dag_prog_internal(
+    prog: &TreeProgram,
+    arg: &Value,
+    config: &InterpretConfig,
+    profile: Option<Profile>,
+) -> Result<(Value, Vec<String>, Option<Profile>), RuntimeError> {
+    let mut vm = VirtualMachine::new(prog, *config);
+    vm.profile = profile;
+    let ret_val = vm.interpret_call(&prog.entry, arg)?;
+    if config.memory_check == MemoryCheck::Leaks && !vm.live_allocations.is_empty() {
+        let kind = RuntimeErrorKind::MemoryLeak(vm.live_allocations.len());
+        return Err(vm.error(kind, &prog.entry));
+    }
+    Ok((ret_val, vm.log, vm.profile))
 }
 
 /// Interprets an expression, returning the value
-pub fn interpret_expr(expr: &RcExpr, func_arg: &Value) -> BrilState {
-    let mut vm = VirtualMachine {
-        program: &TreeProgram {
-            // expr should be call-free so this doesn't matter
-            entry: expr.clone(),
-            functions: vec![],
-        },
-        next_addr: 0,
-        eval_cache: HashMap::new(),
-        memory: HashMap::new(),
-        log: vec![],
+pub fn interpret_expr(expr: &RcExpr, func_arg: &Value) -> Result<BrilState, RuntimeError> {
+    let program = TreeProgram {
+        // expr should be call-free so this doesn't matter
+        entry: expr.clone(),
+        functions: vec![],
     };
-    let value = vm.interpret_expr(expr, func_arg);
-    BrilState {
+    let mut vm = VirtualMachine::new(&program, InterpretConfig::default());
+    let value = vm.interpret_expr(expr, func_arg)?;
+    Ok(BrilState {
         mem: vm.memory,
         log: vm.log,
         value,
-    }
+    })
 }
 
 impl<'a> VirtualMachine<'a> {
-    fn interp_int_expr(&mut self, e: &RcExpr, arg: &Value) -> i64 {
-        match self.interpret_expr(e, arg) {
-            Const(Constant::Int(n)) => n,
-            other => panic!("Expected integer. Got {:?} from expr {:?}", other, e),
+    fn new(program: &'a TreeProgram, config: InterpretConfig) -> Self {
+        VirtualMachine {
+            program,
+            next_addr: 0,
+            next_region: 0,
+            memory: HashMap::new(),
+            eval_cache: HashMap::new(),
+            log: vec![],
+            call_stack: vec![],
+            config,
+            steps: 0,
+            profile: None,
+            live_allocations: HashSet::new(),
+            freed_allocations: HashSet::new(),
         }
     }
 
-    fn interp_float_expr(&mut self, e: &RcExpr, arg: &Value) -> OrderedFloat<f64> {
-        match self.interpret_expr(e, arg) {
-            Const(Constant::Float(n)) => n,
-            other => panic!("Expected integer. Got {:?} from expr {:?}", other, e),
+    fn error(&self, kind: RuntimeErrorKind, expr: &RcExpr) -> RuntimeError {
+        RuntimeError {
+            kind,
+            expr: expr.clone(),
+            call_stack: self.call_stack.clone(),
         }
     }
 
-    fn interp_bool_expr(&mut self, e: &RcExpr, arg: &Value) -> bool {
-        match self.interpret_expr(e, arg) {
-            Const(Constant::Bool(b)) => b,
-            other => panic!("Expected boolean. Got {:?} from expr {:?}", other, e),
+    /// Takes a step, blaming `expr` if the program is out of fuel.
+    fn step(&mut self, expr: &RcExpr) -> Result<(), RuntimeError> {
+        self.steps += 1;
+        match self.config.fuel {
+            Some(fuel) if self.steps > fuel => {
+                Err(self.error(RuntimeErrorKind::OutOfFuel(fuel), expr))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn mismatch(&self, expected: &'static str, found: Value, expr: &RcExpr) -> RuntimeError {
+        self.error(RuntimeErrorKind::TypeMismatch { expected, found }, expr)
+    }
+
+    fn interp_int_expr(&mut self, e: &RcExpr, arg: &Value) -> Result<i64, RuntimeError> {
+        match self.interpret_expr(e, arg)? {
+            Const(Constant::Int(n)) => Ok(n),
+            other => Err(self.mismatch("an integer", other, e)),
         }
     }
 
-    fn interp_pointer_expr(&mut self, e: &RcExpr, arg: &Value) -> Pointer {
-        match self.interpret_expr(e, arg) {
-            Ptr(ptr) => ptr,
-            other => panic!("Expected pointer. Got {:?} from expr {:?}", other, e),
+    fn interp_float_expr(
+        &mut self,
+        e: &RcExpr,
+        arg: &Value,
+    ) -> Result<OrderedFloat<f64>, RuntimeError> {
+        match self.interpret_expr(e, arg)? {
+            Const(Constant::Float(n)) => Ok(n),
+            other => Err(self.mismatch("a float", other, e)),
         }
     }
 
+    fn interp_bool_expr(&mut self, e: &RcExpr, arg: &Value) -> Result<bool, RuntimeError> {
+        match self.interpret_expr(e, arg)? {
+            Const(Constant::Bool(b)) => Ok(b),
+            other => Err(self.mismatch("a boolean", other, e)),
+        }
+    }
+
+    fn interp_pointer_expr(&mut self, e: &RcExpr, arg: &Value) -> Result<Pointer, RuntimeError> {
+        match self.interpret_expr(e, arg)? {
+            Ptr(ptr) => Ok(ptr),
+            other => Err(self.mismatch("a pointer", other, e)),
+        }
+    }
+
+    fn interp_tuple_expr(&mut self, e: &RcExpr, arg: &Value) -> Result<Vec<Value>, RuntimeError> {
+        match self.interpret_expr(e, arg)? {
+            Tuple(vals) => Ok(vals),
+            other => Err(self.mismatch("a tuple", other, e)),
+        }
+    }
+
+    fn interp_state_expr(&mut self, e: &RcExpr, arg: &Value) -> Result<(), RuntimeError> {
+        match self.interpret_expr(e, arg)? {
+            Value::StateV => Ok(()),
+            other => Err(self.mismatch("the state", other, e)),
+        }
+    }
+
+    /// The address `ptr` points to, blaming `expr` if it is out of bounds
+    /// or its allocation was freed.
+    fn addr(&self, ptr: &Pointer, expr: &RcExpr) -> Result<usize, RuntimeError> {
+        if self.config.memory_check != MemoryCheck::Unchecked
+            && self.freed_allocations.contains(&ptr.region)
+        {
+            return Err(self.error(RuntimeErrorKind::UseAfterFree(ptr.clone()), expr));
+        }
+        ptr.addr().map_err(|kind| self.error(kind, expr))
+    }
+
     fn interpret_top(
         &mut self,
+        expr: &RcExpr,
         top: &TernaryOp,
         e1: &RcExpr,
         e2: &RcExpr,
         e3: &RcExpr,
         arg: &Value,
-    ) -> Value {
-        let get_pointer = |e: &RcExpr, vm: &mut Self| vm.interp_pointer_expr(e, arg);
+    ) -> Result<Value, RuntimeError> {
         match top {
             TernaryOp::Write => {
-                let pointer = get_pointer(e1, self);
-                let val = self.interpret_expr(e2, arg).clone();
-                let state_val = self.interpret_expr(e3, arg);
-                assert_eq!(state_val, Value::StateV);
-                self.memory.insert(pointer.addr(), val);
-                Value::StateV
+                let pointer = self.interp_pointer_expr(e1, arg)?;
+                let val = self.interpret_expr(e2, arg)?;
+                self.interp_state_expr(e3, arg)?;
+                let addr = self.addr(&pointer, expr)?;
+                self.memory.insert(addr, val);
+                Ok(Value::StateV)
             }
             TernaryOp::Select => {
-                let get_bool = |e: &RcExpr, vm: &mut Self| vm.interp_bool_expr(e, arg);
-                if get_bool(e1, self) {
+                if self.interp_bool_expr(e1, arg)? {
                     self.interpret_expr(e2, arg)
                 } else {
                     self.interpret_expr(e3, arg)
@@ -229,131 +439,180 @@ impl<'a> VirtualMachine<'a> {
         }
     }
 
-    fn interpret_bop(&mut self, bop: &BinaryOp, e1: &RcExpr, e2: &RcExpr, arg: &Value) -> Value {
+    fn interpret_bop(
+        &mut self,
+        expr: &RcExpr,
+        bop: &BinaryOp,
+        e1: &RcExpr,
+        e2: &RcExpr,
+        arg: &Value,
+    ) -> Result<Value, RuntimeError> {
         let get_int = |e: &RcExpr, vm: &mut Self| vm.interp_int_expr(e, arg);
         let get_float = |e: &RcExpr, vm: &mut Self| vm.interp_float_expr(e, arg);
         let get_bool = |e: &RcExpr, vm: &mut Self| vm.interp_bool_expr(e, arg);
         let get_pointer = |e: &RcExpr, vm: &mut Self| vm.interp_pointer_expr(e, arg);
-        match bop {
+        Ok(match bop {
             BinaryOp::Add => Const(Constant::Int(
-                get_int(e1, self).wrapping_add(get_int(e2, self)),
+                get_int(e1, self)?.wrapping_add(get_int(e2, self)?),
             )),
             BinaryOp::Sub => Const(Constant::Int(
-                get_int(e1, self).wrapping_sub(get_int(e2, self)),
+                get_int(e1, self)?.wrapping_sub(get_int(e2, self)?),
             )),
             BinaryOp::Mul => Const(Constant::Int(
-                get_int(e1, self).wrapping_mul(get_int(e2, self)),
-            )),
-            BinaryOp::Div => Const(Constant::Int(
-                get_int(e1, self).wrapping_div(get_int(e2, self)),
+                get_int(e1, self)?.wrapping_mul(get_int(e2, self)?),
             )),
+            BinaryOp::Div => {
+                let a = get_int(e1, self)?;
+                let b = get_int(e2, self)?;
+                if b == 0 {
+                    return Err(self.error(RuntimeErrorKind::DivisionByZero, expr));
+                }
+                Const(Constant::Int(a.wrapping_div(b)))
+            }
             BinaryOp::Smax => {
-                let a = get_int(e1, self);
-                let b = get_int(e2, self);
+                let a = get_int(e1, self)?;
+                let b = get_int(e2, self)?;
                 Const(Constant::Int(if a > b { a } else { b }))
             }
             BinaryOp::Smin => {
-                let a = get_int(e1, self);
-                let b = get_int(e2, self);
+                let a = get_int(e1, self)?;
+                let b = get_int(e2, self)?;
                 Const(Constant::Int(if a < b { a } else { b }))
             }
-            BinaryOp::Shl => Const(Constant::Int(get_int(e1, self).shl(get_int(e2, self)))),
-            BinaryOp::Shr => Const(Constant::Int(get_int(e1, self).shr(get_int(e2, self)))),
-            BinaryOp::Eq => Const(Constant::Bool(get_int(e1, self) == get_int(e2, self))),
-            BinaryOp::LessThan => Const(Constant::Bool(get_int(e1, self) < get_int(e2, self))),
-            BinaryOp::GreaterThan => Const(Constant::Bool(get_int(e1, self) > get_int(e2, self))),
-            BinaryOp::LessEq => Const(Constant::Bool(get_int(e1, self) <= get_int(e2, self))),
-            BinaryOp::GreaterEq => Const(Constant::Bool(get_int(e1, self) >= get_int(e2, self))),
-            BinaryOp::Load => {
-                let ptr = self.interp_pointer_expr(e1, arg);
-                let state_val = self.interpret_expr(e2, arg);
-                assert_eq!(state_val, Value::StateV);
-                if let Some(val) = self.memory.get(&ptr.addr()) {
-                    tuplev!(val.clone(), Value::StateV)
+            BinaryOp::Shl | BinaryOp::Shr => {
+                let a = get_int(e1, self)?;
+                let b = get_int(e2, self)?;
+                if !(0..64).contains(&b) {
+                    return Err(self.error(RuntimeErrorKind::ShiftOutOfRange(b), expr));
+                }
+                Const(Constant::Int(if *bop == BinaryOp::Shl {
+                    a.shl(b)
                 } else {
-                    panic!("No value bound at memory address {:?}", ptr.addr())
+                    a.shr(b)
+                }))
+            }
+            BinaryOp::Eq => Const(Constant::Bool(get_int(e1, self)? == get_int(e2, self)?)),
+            BinaryOp::LessThan => Const(Constant::Bool(get_int(e1, self)? < get_int(e2, self)?)),
+            BinaryOp::GreaterThan => Const(Constant::Bool(get_int(e1, self)? > get_int(e2, self)?)),
+            BinaryOp::LessEq => Const(Constant::Bool(get_int(e1, self)? <= get_int(e2, self)?)),
+            BinaryOp::GreaterEq => Const(Constant::Bool(get_int(e1, self)? >= get_int(e2, self)?)),
+            BinaryOp::Load => {
+                let ptr = get_pointer(e1, self)?;
+                self.interp_state_expr(e2, arg)?;
+                let addr = self.addr(&ptr, expr)?;
+                match self.memory.get(&addr) {
+                    Some(val) => tuplev!(val.clone(), Value::StateV),
+                    None => return Err(self.error(RuntimeErrorKind::UninitializedRead(addr), expr)),
                 }
             }
             BinaryOp::Free => {
-                let ptr = get_pointer(e1, self);
-                let state_val = self.interpret_expr(e2, arg);
-                assert_eq!(state_val, Value::StateV);
-                self.memory.remove(&ptr.addr());
+                let ptr = get_pointer(e1, self)?;
+                self.interp_state_expr(e2, arg)?;
+                if self.config.memory_check != MemoryCheck::Unchecked {
+                    if self.freed_allocations.contains(&ptr.region) {
+                        return Err(self.error(RuntimeErrorKind::DoubleFree(ptr), expr));
+                    }
+                    if ptr.offset != 0 {
+                        return Err(self.error(RuntimeErrorKind::InvalidFree(ptr), expr));
+                    }
+                }
+                let addr = self.addr(&ptr, expr)?;
+                self.memory.remove(&addr);
+                self.live_allocations.remove(&ptr.region);
+                self.freed_allocations.insert(ptr.region);
                 Value::StateV
             }
             BinaryOp::Print => {
-                let val = self.interpret_expr(e1, arg);
-                let state_val = self.interpret_expr(e2, arg);
-                assert_eq!(state_val, Value::StateV);
+                let val = self.interpret_expr(e1, arg)?;
+                self.interp_state_expr(e2, arg)?;
                 let v_str = val.bril_print().to_string();
                 self.log.push(v_str.clone());
                 Value::StateV
             }
             BinaryOp::And => {
-                let b1 = get_bool(e1, self);
-                let b2 = get_bool(e2, self);
+                let b1 = get_bool(e1, self)?;
+                let b2 = get_bool(e2, self)?;
                 Const(Constant::Bool(b1 && b2))
             }
             BinaryOp::Or => {
-                let b1 = get_bool(e1, self);
-                let b2 = get_bool(e2, self);
+                let b1 = get_bool(e1, self)?;
+                let b2 = get_bool(e2, self)?;
                 Const(Constant::Bool(b1 || b2))
             }
             BinaryOp::PtrAdd => {
                 let Pointer {
+                    region,
                     start_addr: addr,
                     size,
                     offset,
-                } = get_pointer(e1, self);
-                Ptr(Pointer::new(addr, size, offset + get_int(e2, self)))
-            }
-            BinaryOp::FAdd => Const(Constant::Float(get_float(e1, self) + get_float(e2, self))),
-            BinaryOp::FSub => Const(Constant::Float(get_float(e1, self) - get_float(e2, self))),
-            BinaryOp::FMul => Const(Constant::Float(get_float(e1, self) * (get_float(e2, self)))),
-            BinaryOp::FDiv => Const(Constant::Float(get_float(e1, self) / (get_float(e2, self)))),
-            BinaryOp::FEq => Const(Constant::Bool(get_float(e1, self) == get_float(e2, self))),
-            BinaryOp::FLessThan => Const(Constant::Bool(get_float(e1, self) < get_float(e2, self))),
+                } = get_pointer(e1, self)?;
+                Ptr(Pointer::new(
+                    region,
+                    addr,
+                    size,
+                    offset + get_int(e2, self)?,
+                ))
+            }
+            BinaryOp::FAdd => Const(Constant::Float(get_float(e1, self)? + get_float(e2, self)?)),
+            BinaryOp::FSub => Const(Constant::Float(get_float(e1, self)? - get_float(e2, self)?)),
+            BinaryOp::FMul => Const(Constant::Float(get_float(e1, self)? * get_float(e2, self)?)),
+            BinaryOp::FDiv => Const(Constant::Float(get_float(e1, self)? / get_float(e2, self)?)),
+            BinaryOp::FEq => Const(Constant::Bool(get_float(e1, self)? == get_float(e2, self)?)),
+            BinaryOp::FLessThan => {
+                Const(Constant::Bool(get_float(e1, self)? < get_float(e2, self)?))
+            }
             BinaryOp::FGreaterThan => {
-                Const(Constant::Bool(get_float(e1, self) > get_float(e2, self)))
+                Const(Constant::Bool(get_float(e1, self)? > get_float(e2, self)?))
+            }
+            BinaryOp::FLessEq => {
+                Const(Constant::Bool(get_float(e1, self)? <= get_float(e2, self)?))
             }
-            BinaryOp::FLessEq => Const(Constant::Bool(get_float(e1, self) <= get_float(e2, self))),
             BinaryOp::FGreaterEq => {
-                Const(Constant::Bool(get_float(e1, self) >= get_float(e2, self)))
+                Const(Constant::Bool(get_float(e1, self)? >= get_float(e2, self)?))
             }
             BinaryOp::Fmax => {
-                let a = get_float(e1, self);
-                let b = get_float(e2, self);
+                let a = get_float(e1, self)?;
+                let b = get_float(e2, self)?;
                 Const(Constant::Float(if a > b { a } else { b }))
             }
             BinaryOp::Fmin => {
-                let a = get_float(e1, self);
-                let b = get_float(e2, self);
+                let a = get_float(e1, self)?;
+                let b = get_float(e2, self)?;
                 Const(Constant::Float(if a < b { a } else { b }))
             }
-        }
+        })
     }
 
-    fn interpret_uop(&mut self, uop: &UnaryOp, e: &RcExpr, arg: &Value) -> Value {
-        let get_int = |e: &RcExpr, vm: &mut Self| vm.interp_int_expr(e, arg);
-        match uop {
-            UnaryOp::Not => Const(Constant::Bool(!self.interp_bool_expr(e, arg))),
-            UnaryOp::Abs => Const(Constant::Int(get_int(e, self).abs())),
-        }
+    fn interpret_uop(
+        &mut self,
+        uop: &UnaryOp,
+        e: &RcExpr,
+        arg: &Value,
+    ) -> Result<Value, RuntimeError> {
+        Ok(match uop {
+            UnaryOp::Not => Const(Constant::Bool(!self.interp_bool_expr(e, arg)?)),
+            UnaryOp::Abs => Const(Constant::Int(self.interp_int_expr(e, arg)?.abs())),
+        })
     }
 
-    // TODO: refactor to return a Result<Value, RuntimeError>
-    // struct RuntimeError { BadRead(Value) }
-    // in_contexts e typechecks
-    pub fn interpret_call(&mut self, func_name: &str, arg: &Value) -> Value {
-        let func = self.program.get_function(func_name).unwrap();
-        self.interpret_region(
-            func.func_body()
-                .expect("Expected function in interpret_call"),
-            arg,
-        )
+    /// Calls the function `func` on `arg`.
+    pub fn interpret_call(&mut self, func: &RcExpr, arg: &Value) -> Result<Value, RuntimeError> {
+        let (Some(name), Some(body)) = (func.func_name(), func.func_body()) else {
+            return Err(self.error(
+                RuntimeErrorKind::Uninterpretable("a non-function call"),
+                func,
+            ));
+        };
+        if let Some(profile) = &mut self.profile {
+            profile.record_call(&name);
+        }
+        self.call_stack.push(name);
+        let res = self.interpret_region(body, arg)?;
+        self.call_stack.pop();
+        Ok(res)
     }
 
-    pub fn interpret_region(&mut self, expr: &RcExpr, arg: &Value) -> Value {
+    pub fn interpret_region(&mut self, expr: &RcExpr, arg: &Value) -> Result<Value, RuntimeError> {
         let mut memo_before = HashMap::new();
         // save the memo before, since we are evaluating in a new region
         std::mem::swap(&mut self.eval_cache, &mut memo_before);
@@ -364,105 +623,134 @@ impl<'a> VirtualMachine<'a> {
         res
     }
 
-    pub fn interpret_expr(&mut self, expr: &RcExpr, arg: &Value) -> Value {
+    pub fn interpret_expr(&mut self, expr: &RcExpr, arg: &Value) -> Result<Value, RuntimeError> {
         if let Some(val) = self.eval_cache.get(&Rc::as_ptr(expr)) {
-            return val.clone();
+            return Ok(val.clone());
+        }
+        self.step(expr)?;
+        if let Some(profile) = &mut self.profile {
+            profile.record_eval(expr, self.call_stack.last());
         }
         let res = match expr.as_ref() {
             Expr::Const(c, _ty, _ctx) => Const(c.clone()),
-            Expr::Bop(bop, e1, e2) => self.interpret_bop(bop, e1, e2, arg),
-            Expr::Uop(uop, e) => self.interpret_uop(uop, e, arg),
-            Expr::Top(top, e1, e2, e3) => self.interpret_top(top, e1, e2, e3, arg),
+            Expr::Bop(bop, e1, e2) => self.interpret_bop(expr, bop, e1, e2, arg)?,
+            Expr::Uop(uop, e) => self.interpret_uop(uop, e, arg)?,
+            Expr::Top(top, e1, e2, e3) => self.interpret_top(expr, top, e1, e2, e3, arg)?,
             Expr::Get(e_tuple, i) => {
-                let Tuple(vals) = self.interpret_expr(e_tuple, arg) else {
-                    panic!(
-                        "get expects a tuple as its first argument. Got {:?}",
-                        e_tuple
-                    )
-                };
-                if *i >= vals.len() {
-                    panic!(
-                        "get index out of bounds. Got index {} for tuple {:?}. Expression:\n{}",
-                        i, vals, expr
-                    )
+                let vals = self.interp_tuple_expr(e_tuple, arg)?;
+                match vals.get(*i) {
+                    Some(val) => val.clone(),
+                    None => {
+                        let kind = RuntimeErrorKind::GetOutOfBounds {
+                            index: *i,
+                            len: vals.len(),
+                        };
+                        return Err(self.error(kind, expr));
+                    }
                 }
-                vals[*i].clone()
             }
             // in_context this is type checked, so ignore type
             Expr::Alloc(_id, e_size, state_expr, _ty) => {
-                let size = self.interp_int_expr(e_size, arg);
-                let state_val = self.interpret_expr(state_expr, arg);
-                assert_eq!(state_val, Value::StateV);
+                let size = self.interp_int_expr(e_size, arg)?;
+                self.interp_state_expr(state_expr, arg)?;
+                // like brilirs, allocations must hold at least one entry
+                let Some(size) = usize::try_from(size).ok().filter(|size| *size > 0) else {
+                    return Err(self.error(RuntimeErrorKind::InvalidAllocationSize(size), expr));
+                };
                 let addr = self.next_addr;
-                self.next_addr += usize::try_from(size).unwrap();
+                self.next_addr += size;
+                let region = self.next_region;
+                self.next_region += 1;
+                self.live_allocations.insert(region);
 
                 // make a new pointer at the address, with an initial offset of 0
-                tuplev!(Ptr(Pointer::new(addr, size as usize, 0)), Value::StateV)
+                tuplev!(Ptr(Pointer::new(region, addr, size, 0)), Value::StateV)
             }
             Expr::Empty(_ty, _ctx) => Tuple(vec![]),
-            Expr::Single(e) => Tuple(vec![self.interpret_expr(e, arg)]),
+            Expr::Single(e) => Tuple(vec![self.interpret_expr(e, arg)?]),
             Expr::Concat(e1, e2) => {
-                let Tuple(mut v1) = self.interpret_expr(e1, arg) else {
-                    panic!("expected tuple in extend's first argument in: {:?}", e1)
-                };
-                let Tuple(v2) = self.interpret_expr(e2, arg) else {
-                    panic!("expected tuple in extend's second argument in {:?}", e2)
-                };
+                let mut v1 = self.interp_tuple_expr(e1, arg)?;
+                let v2 = self.interp_tuple_expr(e2, arg)?;
                 v1.extend(v2);
                 Tuple(v1)
             }
             Expr::Switch(pred, input, branches) => {
-                let index = self.interp_int_expr(pred, arg);
+                let index = self.interp_int_expr(pred, arg)?;
                 if index < 0 || index as usize >= branches.len() {
-                    // TODO refactor to return a Result
-                    panic!("switch index out of bounds")
+                    let kind = RuntimeErrorKind::SwitchOutOfBounds {
+                        index,
+                        branches: branches.len(),
+                    };
+                    return Err(self.error(kind, expr));
                 }
-                let input_val = self.interpret_expr(input, arg);
-                self.interpret_region(&branches[index as usize], &input_val)
+                let input_val = self.interpret_expr(input, arg)?;
+                self.interpret_region(&branches[index as usize], &input_val)?
             }
             Expr::If(pred, input, then, els) => {
-                let pred_evaluated = self.interp_bool_expr(pred, arg);
-                let input_evaluated = self.interpret_expr(input, arg);
+                let pred_evaluated = self.interp_bool_expr(pred, arg)?;
+                let input_evaluated = self.interpret_expr(input, arg)?;
                 if pred_evaluated {
-                    self.interpret_region(then, &input_evaluated)
+                    self.interpret_region(then, &input_evaluated)?
                 } else {
-                    self.interpret_region(els, &input_evaluated)
+                    self.interpret_region(els, &input_evaluated)?
                 }
             }
             Expr::DoWhile(input, pred_output) => {
-                let Tuple(mut vals) = self.interpret_expr(input, arg) else {
-                    panic!("expected tuple for input in do-while")
-                };
+                let mut vals = self.interp_tuple_expr(input, arg)?;
 
                 // Because it's a do-while, we always execute the body at least once
-                let mut pred = Const(Constant::Bool(true));
-                while pred == Const(Constant::Bool(true)) {
-                    let Tuple(pred_output_val) =
-                        self.interpret_region(pred_output, &Tuple(vals.clone()))
-                    else {
-                        panic!("expected tuple for pred_output in do-while")
+                let mut pred = true;
+                while pred {
+                    self.step(expr)?;
+                    if let Some(profile) = &mut self.profile {
+                        profile.record_iteration(expr);
+                    }
+                    let pred_output_val =
+                        match self.interpret_region(pred_output, &Tuple(vals.clone()))? {
+                            Tuple(pred_output_val) => pred_output_val,
+                            other => return Err(self.mismatch("a tuple", other, pred_output)),
+                        };
+                    if pred_output_val.len() != 1 + vals.len() {
+                        let kind = RuntimeErrorKind::LoopArity {
+                            expected: vals.len(),
+                            found: pred_output_val.len(),
+                        };
+                        return Err(self.error(kind, expr));
+                    }
+                    pred = match &pred_output_val[0] {
+                        Const(Constant::Bool(b)) => *b,
+                        other => {
+                            return Err(self.mismatch("a boolean", other.clone(), pred_output))
+                        }
                     };
-                    assert_eq!(
-                        pred_output_val.len(),
-                        1 + vals.len(),
-                        "expected pred_output to have one more element than input in {:?}",
-                        pred_output
-                    );
-                    pred = pred_output_val[0].clone();
                     vals = pred_output_val[1..].to_vec();
                 }
                 Tuple(vals)
             }
             Expr::Arg(_ty, _ctx) => arg.clone(),
-            Expr::Function(..) => panic!("Function should not be interpreted as an expression"),
+            Expr::Function(..) => {
+                return Err(self.error(
+                    RuntimeErrorKind::Uninterpretable("a function as an expression"),
+                    expr,
+                ))
+            }
             Expr::Call(func_name, e) => {
-                let e_val = self.interpret_expr(e, arg);
-                self.interpret_call(func_name, &e_val)
+                let e_val = self.interpret_expr(e, arg)?;
+                let Some(func) = self.program.get_function(func_name) else {
+                    let kind = RuntimeErrorKind::UnknownFunction(func_name.clone());
+                    return Err(self.error(kind, expr));
+                };
+                self.interpret_call(func, &e_val)?
+            }
+            Expr::Symbolic(..) => {
+                return Err(self.error(
+                    RuntimeErrorKind::Uninterpretable("a symbolic expression"),
+                    expr,
+                ))
             }
-            Expr::Symbolic(_, _ty) => panic!("found symbolic"),
         };
         self.eval_cache.insert(Rc::as_ptr(expr), res.clone());
-        res
+        Ok(res)
     }
 }
 
@@ -478,7 +766,9 @@ fn test_interpret_calls() {
         ),
         function("func2", base(intt()), base(intt()), add(arg(), int(1))),
     );
-    let res = interpret_dag_prog(&expr, &Const(Constant::Int(5))).0;
+    let res = interpret_dag_prog(&expr, &Const(Constant::Int(5)))
+        .unwrap()
+        .0;
     assert_eq!(res, Const(Constant::Int(10)));
 }
 
@@ -499,7 +789,9 @@ fn test_interpret_recursive() {
             )
         )
     ),);
-    let res = interpret_dag_prog(&expr, &Const(Constant::Int(10))).0;
+    let res = interpret_dag_prog(&expr, &Const(Constant::Int(10)))
+        .unwrap()
+        .0;
     assert_eq!(res, Const(Constant::Int(55)));
 }
 
@@ -518,7 +810,7 @@ fn test_interpreter() {
         ),
         0,
     );
-    let res = interpret_expr(&expr, &statev());
+    let res = interpret_expr(&expr, &statev()).unwrap();
     assert_eq!(res.value, Const(Constant::Int(11)));
     assert_eq!(
         res.log,
@@ -531,3 +823,157 @@ fn test_interpreter() {
 
 #[test]
 fn test_recursive_interp() {}
+
+#[test]
+fn test_interpret_runtime_errors() {
+    use crate::ast::*;
+    let expr = program!(
+        function(
+            "main",
+            base(intt()),
+            base(intt()),
+            add(call("divide", arg()), int(1))
+        ),
+        function("divide", base(intt()), base(intt()), div(int(10), arg())),
+    );
+    assert_eq!(
+        interpret_dag_prog(&expr, &Const(Constant::Int(2)))
+            .unwrap()
+            .0,
+        Const(Constant::Int(6))
+    );
+    let err = interpret_dag_prog(&expr, &Const(Constant::Int(0))).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::DivisionByZero);
+    assert_eq!(err.expr, div(int(10), arg()));
+    assert_eq!(
+        err.call_stack,
+        vec!["main".to_string(), "divide".to_string()]
+    );
+
+    let out_of_bounds = get(alloc(0, int(2), arg(), pointert(intt())), 0);
+    let load_expr = load(ptradd(out_of_bounds, int(2)), arg());
+    let err = interpret_expr(&load_expr, &statev()).err().unwrap();
+    assert!(matches!(err.kind, RuntimeErrorKind::PointerOutOfBounds(_)));
+    assert!(err.call_stack.is_empty());
+}
+
+#[test]
+fn test_interpret_memory_checks() {
+    use crate::ast::*;
+    let alloc_expr = alloc(0, int(1), getat(0), pointert(intt()));
+    let ptr = get(alloc_expr.clone(), 0);
+    let stored = write(ptr.clone(), int(3), get(alloc_expr, 1));
+    let freed = free(ptr.clone(), stored.clone());
+    let body = |state: RcExpr| {
+        program!(function(
+            "main",
+            tuplet!(statet()),
+            tuplet!(statet()),
+            single(state)
+        ),)
+    };
+    let input = tuplev!(statev());
+    let run = |prog: &TreeProgram, memory_check| {
+        let config = InterpretConfig {
+            memory_check,
+            ..Default::default()
+        };
+        interpret_dag_prog_with_config(prog, &input, &config).map(|(_value, log)| log)
+    };
+
+    // freeing once is fine, even with leak checks
+    assert!(run(&body(freed.clone()), MemoryCheck::Leaks).is_ok());
+
+    // loading after freeing is only allowed without checks
+    let use_after_free = body(get(load(ptr.clone(), freed.clone()), 1));
+    assert!(run(&use_after_free, MemoryCheck::Unchecked).is_ok());
+    let err = run(&use_after_free, MemoryCheck::Safety).unwrap_err();
+    assert!(matches!(err.kind, RuntimeErrorKind::UseAfterFree(_)));
+
+    let double_free = body(free(ptr.clone(), freed));
+    let err = run(&double_free, MemoryCheck::Safety).unwrap_err();
+    assert!(matches!(err.kind, RuntimeErrorKind::DoubleFree(_)));
+
+    // leaks are only reported with leak checks
+    let leak = body(get(load(ptr, stored), 1));
+    assert!(run(&leak, MemoryCheck::Safety).is_ok());
+    assert_eq!(
+        run(&leak, MemoryCheck::Leaks).unwrap_err().kind,
+        RuntimeErrorKind::MemoryLeak(1)
+    );
+}
+
+#[test]
+fn test_interpret_allocation_regions() {
+    use crate::ast::*;
+    let run = |state: RcExpr| {
+        let prog = program!(function(
+            "main",
+            tuplet!(statet()),
+            tuplet!(statet()),
+            single(state)
+        ),);
+        let config = InterpretConfig {
+            memory_check: MemoryCheck::Leaks,
+            ..Default::default()
+        };
+        interpret_dag_prog_with_config(&prog, &tuplev!(statev()), &config).map(|(_value, log)| log)
+    };
+
+    // an empty allocation would share its address with the next one
+    let empty = alloc(0, int(0), getat(0), pointert(intt()));
+    let next = alloc(1, int(1), get(empty.clone(), 1), pointert(intt()));
+    let freed = free(get(empty, 0), free(get(next.clone(), 0), get(next, 1)));
+    assert_eq!(
+        run(freed).unwrap_err().kind,
+        RuntimeErrorKind::InvalidAllocationSize(0)
+    );
+
+    // freeing one allocation leaves the one right after it usable
+    let first = alloc(0, int(1), getat(0), pointert(intt()));
+    let second = alloc(1, int(1), get(first.clone(), 1), pointert(intt()));
+    let first_freed = free(get(first, 0), get(second.clone(), 1));
+    let ptr = get(second, 0);
+    let stored = write(ptr.clone(), int(5), first_freed);
+    let loaded = load(ptr.clone(), stored);
+    let printed = tprint(get(loaded.clone(), 0), get(loaded, 1));
+    assert_eq!(run(free(ptr, printed)).unwrap(), vec!["5".to_string()]);
+}
+
+#[test]
+fn test_interpret_print_pointers() {
+    use crate::ast::*;
+    // allocates two regions and prints a pointer into the second one
+    let first = alloc(0, int(4), getat(0), pointert(intt()));
+    let second = alloc(1, int(2), get(first.clone(), 1), pointert(intt()));
+    let ptr = ptradd(get(second.clone(), 0), int(1));
+    let printed = tprint(ptr, get(second.clone(), 1));
+    let freed = free(get(first, 0), free(get(second, 0), printed));
+    let prog = program!(function(
+        "main",
+        tuplet!(statet()),
+        tuplet!(statet()),
+        single(freed)
+    ),);
+    let (_value, log) = interpret_dag_prog(&prog, &tuplev!(statev())).unwrap();
+    assert_eq!(log, vec!["Pointer { base: 1, offset: 1 }".to_string()]);
+}
+
+#[test]
+fn test_interpret_fuel() {
+    use crate::ast::*;
+    // loops forever
+    let expr = program!(function(
+        "main",
+        tuplet!(intt()),
+        tuplet!(intt()),
+        dowhile(arg(), parallel!(ttrue(), add(getat(0), int(1))))
+    ),);
+    let config = InterpretConfig {
+        fuel: Some(1000),
+        ..Default::default()
+    };
+    let err = interpret_dag_prog_with_config(&expr, &tuplev!(intv(0)), &config).unwrap_err();
+    assert_eq!(err.kind, RuntimeErrorKind::OutOfFuel(1000));
+    assert_eq!(err.call_stack, vec!["main".to_string()]);
+}
@@ -1,13 +1,36 @@
+use batching::Batching;
+use cache::OptimizationCache;
 use clap::ValueEnum;
-use egglog::{Term, TermDag};
-use greedy_dag_extractor::{extract, has_debug_exprs, serialized_egraph, DefaultCostModel};
-use indexmap::IndexMap;
+use egglog::{
+    ast::{Action, Command, Literal, Symbol, DUMMY_SPAN},
+    Term, TermDag,
+};
+use egraph_dump::EgraphDump;
+use extra_rules::ExtraRules;
+use extraction_report::ExtractionReport;
+use greedy_dag_extractor::{
+    extract, extract_top_k, extract_with_fallback, has_debug_exprs, serialized_egraph,
+};
+use indexmap::{IndexMap, IndexSet};
 use interpreter::Value;
-use schedule::{rulesets, CompilerPass};
-use schema::TreeProgram;
-use std::{collections::HashSet, fmt::Write, i64};
+use pass_stats::PassStats;
+use schedule::{rulesets_with_extra, CompilerPass};
+use schema::{RcExpr, TreeProgram};
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    i64,
+    ops::Range,
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 use to_egglog::TreeToEgglog;
 
+pub use greedy_dag_extractor::{CostModel, DefaultCostModel};
+
 use crate::{
     dag2svg::tree_to_svg, interpreter::interpret_dag_prog, optimizations::function_inlining,
     schedule::parallel_schedule,
@@ -15,15 +38,24 @@ use crate::{
 
 pub mod add_context;
 pub mod ast;
+pub mod batching;
+mod cache;
 mod config;
 pub mod dag2svg;
 pub mod dag_typechecker;
+pub mod dynamic_cost;
+pub mod egraph_dump;
+pub mod extra_rules;
+pub mod extraction_report;
 pub mod from_egglog;
 mod greedy_dag_extractor;
 pub mod interpreter;
 pub(crate) mod interval_analysis;
-mod linearity;
+pub mod linearity;
 mod optimizations;
+pub mod pass_stats;
+pub mod rng;
+pub mod rule_fuzzer;
 pub mod schema;
 pub mod schema_helpers;
 mod to_egglog;
@@ -33,10 +65,23 @@ pub(crate) mod utility;
 use main_error::MainError;
 pub mod pretty_print;
 pub mod schedule;
+pub mod tree_diff;
+pub mod tree_json;
+pub mod tree_parser;
 
 pub type Result = std::result::Result<(), MainError>;
 
 pub fn prologue() -> String {
+    prologue_with_extra_rules(None)
+}
+
+/// The prologue, with `extra_rules` added after the built-in rules.
+/// Rulesets declared by `extra_rules` join `all-optimizations`.
+pub fn prologue_with_extra_rules(extra_rules: Option<&ExtraRules>) -> String {
+    let (extra_source, extra_rulesets) = match extra_rules {
+        Some(extra_rules) => (extra_rules.source.as_str(), extra_rules.rulesets.as_slice()),
+        None => ("", [].as_slice()),
+    };
     [
         include_str!("schema.egg"),
         include_str!("type_analysis.egg"),
@@ -73,55 +118,80 @@ pub fn prologue() -> String {
         include_str!("optimizations/conditional_invariant_code_motion.egg"),
         include_str!("optimizations/conditional_push_in.egg"),
         include_str!("utility/debug-helper.egg"),
-        &rulesets(),
+        extra_source,
+        &rulesets_with_extra(extra_rulesets),
     ]
     .join("\n")
 }
 
-/// Adds an egglog program to `res` that adds the given term
-/// to the database.
-/// Returns a fresh variable referring to the program.
+/// Egglog actions that add terms to the database,
+/// with a fresh variable for each distinct sub-term so that shared
+/// sub-terms are only added once.
 /// Note that because the cache caches based on a term, which
 /// references the termdag, this cache **cannot** be reused
 /// across different TermDags. Make sure to update the term dag
 /// for a new term (using TreeToEgglog), rather than creating a
 /// new term dag.
-fn print_with_intermediate_helper(
-    termdag: &TermDag,
-    term: Term,
-    cache: &mut IndexMap<Term, String>,
-    res: &mut String,
-) -> String {
-    if let Some(var) = cache.get(&term) {
-        return var.clone();
-    }
-
-    match &term {
-        Term::Lit(_) => termdag.to_string(&term),
-        Term::Var(_) => termdag.to_string(&term),
-        Term::App(head, children) => {
-            let child_vars = children
-                .iter()
-                .map(|child| {
-                    print_with_intermediate_helper(termdag, termdag.get(*child).clone(), cache, res)
-                })
-                .collect::<Vec<String>>()
-                .join(" ");
-            let fresh_var = format!("__tmp{}", cache.len());
-            writeln!(res, "(let {fresh_var} ({head} {child_vars}))").unwrap();
-            cache.insert(term, fresh_var.clone());
+#[derive(Default)]
+pub(crate) struct TermActions {
+    actions: Vec<Action>,
+    vars: IndexMap<Term, Symbol>,
+}
 
-            fresh_var
+impl TermActions {
+    /// Adds `let` actions for the given term and its children.
+    /// Returns an expression referring to the term.
+    pub(crate) fn add_term(&mut self, termdag: &TermDag, term: Term) -> egglog::ast::Expr {
+        if let Some(var) = self.vars.get(&term) {
+            return egglog::ast::Expr::Var(DUMMY_SPAN.clone(), *var);
+        }
+
+        match &term {
+            Term::Lit(lit) => egglog::ast::Expr::Lit(DUMMY_SPAN.clone(), lit.clone()),
+            Term::Var(var) => egglog::ast::Expr::Var(DUMMY_SPAN.clone(), *var),
+            Term::App(head, children) => {
+                let children = children
+                    .iter()
+                    .map(|child| self.add_term(termdag, termdag.get(*child).clone()))
+                    .collect();
+                let fresh_var = Symbol::from(format!("__tmp{}", self.vars.len()));
+                self.actions.push(Action::Let(
+                    DUMMY_SPAN.clone(),
+                    fresh_var,
+                    egglog::ast::Expr::Call(DUMMY_SPAN.clone(), *head, children),
+                ));
+                self.vars.insert(term, fresh_var);
+                egglog::ast::Expr::Var(DUMMY_SPAN.clone(), fresh_var)
+            }
         }
     }
+
+    pub(crate) fn push(&mut self, action: Action) {
+        self.actions.push(action);
+    }
+}
+
+/// A call to the egglog function `head`, for building actions.
+pub(crate) fn egglog_call(head: &str, args: Vec<egglog::ast::Expr>) -> egglog::ast::Expr {
+    egglog::ast::Expr::Call(DUMMY_SPAN.clone(), head.into(), args)
+}
+
+/// An egglog string literal, for building actions.
+pub(crate) fn egglog_string(s: &str) -> egglog::ast::Expr {
+    egglog::ast::Expr::Lit(DUMMY_SPAN.clone(), Literal::String(s.into()))
 }
 
+/// Prints egglog code that adds the term to the database,
+/// binding it to the global variable `PROG`.
 pub fn print_with_intermediate_vars(termdag: &TermDag, term: Term) -> String {
-    let mut printed = String::new();
-    let mut cache = IndexMap::<Term, String>::new();
-    let res = print_with_intermediate_helper(termdag, term, &mut cache, &mut printed);
-    printed.push_str(&format!("(let PROG {res})\n"));
-    printed
+    let mut actions = TermActions::default();
+    let prog = actions.add_term(termdag, term);
+    actions.push(Action::Let(DUMMY_SPAN.clone(), "PROG".into(), prog));
+    actions
+        .actions
+        .iter()
+        .map(|action| format!("{action}\n"))
+        .collect()
 }
 
 // Build an egglog program that optimizes a particular batch of functions `fns`
@@ -135,12 +205,58 @@ pub fn build_program(
     fns: &[String],
     schedule: &str,
 ) -> String {
+    build_program_with_extra_rules(program, inline_program, fns, schedule, None)
+}
+
+/// Like `build_program`, but with `extra_rules` added to the prologue.
+pub fn build_program_with_extra_rules(
+    program: &TreeProgram,
+    inline_program: Option<&TreeProgram>,
+    fns: &[String],
+    schedule: &str,
+    extra_rules: Option<&ExtraRules>,
+) -> String {
+    let prologue = prologue_with_extra_rules(extra_rules);
+    let body = build_program_body(program, inline_program, fns, schedule);
+    format!("\n; Prologue\n{prologue}\n{body}")
+}
+
+/// Like `build_program`, but without the prologue.
+/// The commands from `build_program_commands`, printed as egglog code,
+/// followed by the schedule.
+pub fn build_program_body(
+    program: &TreeProgram,
+    inline_program: Option<&TreeProgram>,
+    fns: &[String],
+    schedule: &str,
+) -> String {
+    let commands = build_program_commands(program, inline_program, fns);
+    format!("{}\n\n; Schedule\n{schedule}\n", print_commands(&commands))
+}
+
+/// Prints egglog commands as egglog code, one per line.
+pub fn print_commands(commands: &[Command]) -> String {
+    commands
+        .iter()
+        .map(|command| command.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The egglog commands that add the functions `fns` of `program` to the egraph,
+/// along with the context and function inlining needed to optimize them.
+/// Run them with `egglog::EGraph::run_program` on an egraph from `egraph_with_prologue`,
+/// which avoids parsing and typechecking the prologue for every batch.
+pub fn build_program_commands(
+    program: &TreeProgram,
+    inline_program: Option<&TreeProgram>,
+    fns: &[String],
+) -> Vec<Command> {
     let (program, mut context_cache) = program.add_context();
-    let mut printed = String::new();
 
     // Create a global cache for generating intermediate variables
     let mut tree_state = TreeToEgglog::new();
-    let mut term_cache = IndexMap::<Term, String>::new();
+    let mut actions = TermActions::default();
 
     // Generate function inlining egglog
     let function_inlining_unions = if let Some(inline_program) = inline_program {
@@ -154,72 +270,101 @@ pub fn build_program(
             ));
         }
 
-        function_inlining::print_function_inlining_pairs(
-            pairs,
-            &mut printed,
-            &mut tree_state,
-            &mut term_cache,
-        )
+        function_inlining::function_inlining_actions(pairs, &mut tree_state, &mut actions)
     } else {
-        "".to_string()
+        vec![]
     };
 
     // Generate program egglog
     for func in fns {
         let func = program.get_function(func).unwrap();
         let term = func.to_egglog_with(&mut tree_state);
-        let _func_var = print_with_intermediate_helper(
-            &tree_state.termdag,
-            term,
-            &mut term_cache,
-            &mut printed,
-        );
+        let _func_var = actions.add_term(&tree_state.termdag, term);
     }
 
     let loop_context_unions =
-        context_cache.get_unions_with_sharing(&mut printed, &mut tree_state, &mut term_cache);
+        context_cache.loop_context_union_actions(&mut tree_state, &mut actions);
 
     // set the type of each function
     for func in program.fns() {
         let func = program.get_function(&func).unwrap();
         let func_name = func.func_name().unwrap();
-        let input_ty = func.func_input_ty().unwrap();
-        let func_ty = func.func_output_ty().unwrap();
-        writeln!(
-            &mut printed,
-            "(FunctionHasType \"{func_name}\" {input_ty} {func_ty})",
-        )
-        .unwrap();
+        let input_ty = func
+            .func_input_ty()
+            .unwrap()
+            .to_egglog_internal(&mut tree_state);
+        let input_ty = actions.add_term(&tree_state.termdag, input_ty);
+        let func_ty = func
+            .func_output_ty()
+            .unwrap()
+            .to_egglog_internal(&mut tree_state);
+        let func_ty = actions.add_term(&tree_state.termdag, func_ty);
+        actions.push(Action::Expr(
+            DUMMY_SPAN.clone(),
+            egglog_call(
+                "FunctionHasType",
+                vec![egglog_string(&func_name), input_ty, func_ty],
+            ),
+        ));
     }
 
-    let prologue = prologue();
-
-    format!(
-        "
-; Prologue
-{prologue}
+    // Program nodes, then loop context unions, then function inlining unions
+    let mut actions = actions.actions;
+    actions.extend(loop_context_unions);
+    actions.extend(function_inlining_unions);
 
-; required by function_inlining_unoins
-; Function inlining unions
-(relation InlinedCall (String Expr))
-
-(ruleset initialization)
-(rule () (
-    ; Program nodes
-    {printed}
-
-    ; Loop context unions
-    {loop_context_unions}
+    let initialization = Symbol::from("initialization");
+    vec![
+        Command::Rule {
+            name: "".into(),
+            ruleset: initialization,
+            rule: egglog::ast::Rule {
+                span: DUMMY_SPAN.clone(),
+                head: egglog::ast::Actions::new(actions),
+                body: vec![],
+            },
+        },
+        Command::RunSchedule(egglog::ast::Schedule::Repeat(
+            DUMMY_SPAN.clone(),
+            1,
+            Box::new(egglog::ast::Schedule::Run(
+                DUMMY_SPAN.clone(),
+                egglog::ast::RunConfig {
+                    ruleset: initialization,
+                    until: None,
+                },
+            )),
+        )),
+    ]
+}
 
-    ; Function inlining unions
-    {function_inlining_unions}
-) :ruleset initialization)
-(run initialization 1) 
+thread_local! {
+    /// Egraphs with the prologue loaded, keyed by the source of the extra rules.
+    /// Egraphs can't be shared between threads, so each thread loads its own.
+    static PROLOGUE_EGRAPHS: RefCell<IndexMap<String, egglog::EGraph>> =
+        RefCell::new(IndexMap::new());
+}
 
-; Schedule
-{schedule}
-"
-    )
+/// A fresh egraph with the prologue (and `extra_rules`) already loaded,
+/// ready to run the commands from `build_program_commands`.
+/// The prologue is only parsed once per thread; later calls clone the loaded egraph.
+/// With multiple jobs, batches run on long-lived `EgglogWorkers`
+/// so that each of them parses the prologue once.
+pub fn egraph_with_prologue(
+    extra_rules: Option<&ExtraRules>,
+) -> std::result::Result<egglog::EGraph, egglog::Error> {
+    let key = extra_rules
+        .map(|extra_rules| extra_rules.source.clone())
+        .unwrap_or_default();
+    PROLOGUE_EGRAPHS.with(|egraphs| {
+        if let Some(egraph) = egraphs.borrow().get(&key) {
+            return Ok(egraph.clone());
+        }
+        let mut egraph = egglog::EGraph::default();
+        egraph.parse_and_run_program(None, &prologue_with_extra_rules(extra_rules))?;
+        egraphs.borrow_mut().insert(key, egraph.clone());
+        Ok(egraph)
+    })
 }
 
 pub fn are_progs_eq(program1: TreeProgram, program2: TreeProgram) -> bool {
@@ -279,6 +424,11 @@ impl Schedule {
 #[derive(Clone, Debug)]
 pub struct EggccConfig {
     pub schedule: Schedule,
+    /// When Some, run these passes instead of the ones from `schedule`.
+    /// See `schedule::load_schedule_file`.
+    pub custom_schedule: Option<Vec<CompilerPass>>,
+    /// User-supplied rules to add to the prologue.
+    pub extra_rules: Option<ExtraRules>,
     /// Stop after this many passes.
     /// If stop_after_n_passes is negative,
     /// run [0 ... schedule.len() + stop_after_n_passes] passes.
@@ -289,9 +439,68 @@ pub struct EggccConfig {
     pub linearity: bool,
     /// When Some, optimize only the functions in this set.
     pub optimize_functions: Option<HashSet<String>>,
+    /// How many distinct programs to extract for each function in the last pass.
+    /// Only `optimize_with_candidates` returns more than the best one.
+    pub top_k: usize,
+    /// When Some, limits the work done on each batch in each pass.
+    /// Batches that go over budget keep their version from before the pass.
+    pub pass_budget: Option<PassBudget>,
+    /// How to split the functions into batches optimized in separate egraphs.
+    pub batching: Batching,
+    /// For `Batching::Clusters`, the largest total size (in expressions)
+    /// of the functions in a batch. Bigger strongly connected components
+    /// get a batch of their own.
+    pub max_batch_size: usize,
+    /// How many threads to use for running egglog on batches of functions.
+    /// The result is the same regardless of the number of threads.
+    /// Ignored when `reuse_egraph` is set.
+    pub jobs: usize,
+    /// When Some, optimized functions are cached in this directory and reused
+    /// by later runs that optimize the same functions in the same way.
+    pub cache_dir: Option<PathBuf>,
+    /// When true, consecutive passes continue on the same egraph
+    /// instead of extracting and rebuilding it after every pass.
+    pub reuse_egraph: bool,
+    /// When reusing egraphs, also extract after these passes (counting from 0)
+    /// and continue from a fresh egraph.
+    pub checkpoints: Vec<usize>,
+    /// Collect statistics for each pass and batch, returned by `optimize_with_stats`.
+    pub collect_stats: bool,
+    /// Write the egraph of each pass and batch to this directory before extracting from it.
+    /// See `egraph_dump`.
+    pub dump_egraphs: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PassBudget {
+    /// Maximum number of tuples in the egraph (`egglog::EGraph::num_tuples`).
+    /// This is egglog's node limit, so egglog stops applying rules
+    /// once the egraph grows past it.
+    pub max_nodes: Option<usize>,
+    /// Maximum time spent running the schedule.
+    /// egglog has no time limit of its own, so this is checked
+    /// after the schedule finishes.
+    pub max_time: Option<Duration>,
+}
+
+impl PassBudget {
+    fn is_exceeded(&self, egraph: &egglog::EGraph, schedule_time: Duration) -> bool {
+        self.max_nodes
+            .is_some_and(|max_nodes| egraph.num_tuples() > max_nodes)
+            || self
+                .max_time
+                .is_some_and(|max_time| schedule_time > max_time)
+    }
 }
 
 impl EggccConfig {
+    pub fn get_schedule_list(&self) -> Vec<CompilerPass> {
+        match &self.custom_schedule {
+            Some(passes) => passes.clone(),
+            None => self.schedule.get_schedule_list(),
+        }
+    }
+
     pub fn get_normalized_cutoff(&self, schedule_len: usize) -> usize {
         if self.stop_after_n_passes < 0 {
             (schedule_len as i64 + self.stop_after_n_passes) as usize
@@ -307,9 +516,21 @@ impl Default for EggccConfig {
     fn default() -> Self {
         Self {
             schedule: Schedule::default(),
+            custom_schedule: None,
+            extra_rules: None,
             stop_after_n_passes: i64::MAX,
             linearity: true,
             optimize_functions: None,
+            top_k: 1,
+            pass_budget: None,
+            batching: Batching::default(),
+            max_batch_size: 10_000,
+            jobs: 1,
+            cache_dir: None,
+            reuse_egraph: false,
+            checkpoints: vec![],
+            collect_stats: false,
+            dump_egraphs: None,
         }
     }
 }
@@ -320,9 +541,127 @@ pub fn optimize(
     program: &TreeProgram,
     eggcc_config: &EggccConfig,
 ) -> std::result::Result<TreeProgram, egglog::Error> {
-    let schedule_list = eggcc_config.schedule.get_schedule_list();
+    optimize_internal(program, eggcc_config, false).map(|(res, _extraction)| res)
+}
+
+/// Like `optimize`, but also explains the extraction of the last pass,
+/// which is the one that determines the resulting program.
+pub fn optimize_with_report(
+    program: &TreeProgram,
+    eggcc_config: &EggccConfig,
+) -> std::result::Result<(TreeProgram, ExtractionReport), egglog::Error> {
+    optimize_internal(program, eggcc_config, true)
+        .map(|(res, extraction)| (res, extraction.report.unwrap_or_default()))
+}
+
+/// Like `optimize`, but also returns up to `eggcc_config.top_k` distinct
+/// candidates for each function found by the last pass, best (by the cost model) first.
+/// The returned program uses the best candidate for every function.
+pub fn optimize_with_candidates(
+    program: &TreeProgram,
+    eggcc_config: &EggccConfig,
+) -> std::result::Result<(TreeProgram, IndexMap<String, Vec<RcExpr>>), egglog::Error> {
+    optimize_internal(program, eggcc_config, false)
+        .map(|(res, extraction)| (res, extraction.candidates))
+}
+
+/// Like `optimize`, but also returns the size of the egraph
+/// for each batch after each pass.
+pub fn optimize_with_egraph_sizes(
+    program: &TreeProgram,
+    eggcc_config: &EggccConfig,
+) -> std::result::Result<(TreeProgram, Vec<EgraphSize>), egglog::Error> {
+    optimize_internal(program, eggcc_config, false).map(|(res, info)| (res, info.egraph_sizes))
+}
+
+/// Like `optimize`, but also returns statistics for each pass and batch
+/// when `eggcc_config.collect_stats` is set.
+pub fn optimize_with_stats(
+    program: &TreeProgram,
+    eggcc_config: &EggccConfig,
+) -> std::result::Result<(TreeProgram, Vec<PassStats>), egglog::Error> {
+    optimize_internal(program, eggcc_config, false).map(|(res, info)| (res, info.pass_stats))
+}
+
+/// Extra information collected by `optimize_internal`.
+#[derive(Default)]
+struct OptimizeInfo {
+    /// The report for the extraction in the last pass.
+    report: Option<ExtractionReport>,
+    /// Candidates from the last pass, only filled in when `top_k` is greater than one.
+    candidates: IndexMap<String, Vec<RcExpr>>,
+    egraph_sizes: Vec<EgraphSize>,
+    /// Only filled in when collecting statistics.
+    pass_stats: Vec<PassStats>,
+}
+
+impl OptimizeInfo {
+    fn record_egraph_size(&mut self, pass: usize, batch: &[String], tuples: usize) {
+        log::info!(
+            "Egraph for batch {:?} has {} tuples after pass {}",
+            batch,
+            tuples,
+            pass
+        );
+        self.egraph_sizes.push(EgraphSize {
+            pass,
+            batch: batch.to_vec(),
+            tuples,
+        });
+    }
+
+    fn record_stats(&mut self, stats: Option<PassStats>, pass: usize, batch: &[String]) {
+        if let Some(stats) = stats {
+            self.pass_stats.push(PassStats {
+                pass,
+                batch: batch.to_vec(),
+                ..stats
+            });
+        }
+    }
+
+    /// Adds the extraction to the statistics recorded last, if any.
+    fn record_extraction(&mut self, extract_time: Duration, cost: Option<f64>) {
+        if let Some(stats) = self.pass_stats.last_mut() {
+            stats.extract_secs = extract_time.as_secs_f64();
+            stats.extracted_cost = cost;
+        }
+    }
+}
+
+/// The size of the egraph for a batch of functions after running a pass.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EgraphSize {
+    pub pass: usize,
+    pub batch: Vec<String>,
+    /// Number of tuples (enodes and analysis facts) in the egraph.
+    pub tuples: usize,
+}
+
+fn optimize_internal(
+    program: &TreeProgram,
+    eggcc_config: &EggccConfig,
+    build_report: bool,
+) -> std::result::Result<(TreeProgram, OptimizeInfo), egglog::Error> {
+    if eggcc_config.reuse_egraph {
+        return optimize_reusing_egraph(program, eggcc_config, build_report);
+    }
+    let schedule_list = eggcc_config.get_schedule_list();
     let mut res = program.clone();
 
+    let mut info = OptimizeInfo::default();
+    let cache = eggcc_config
+        .cache_dir
+        .as_deref()
+        .map(OptimizationCache::new);
+    let workers = (eggcc_config.jobs > 1).then(|| {
+        EgglogWorkers::new(
+            eggcc_config.jobs,
+            eggcc_config.extra_rules.as_ref(),
+            eggcc_config.pass_budget.as_ref(),
+            eggcc_config.collect_stats,
+        )
+    });
     let cutoff = eggcc_config.get_normalized_cutoff(schedule_list.len());
     for (i, schedule) in schedule_list[..cutoff].iter().enumerate() {
         let mut should_maintain_linearity = true;
@@ -331,81 +670,599 @@ pub fn optimize(
         }
 
         log::info!("Running pass {}...", i);
-        let fns = res.fns();
 
-        // if we are inlining, save the program
         // TODO we inline on the first pass, but this should be configurable from the schedule
-        let inline_program = match schedule {
-            schedule::CompilerPass::Schedule(_) => None,
-            schedule::CompilerPass::InlineWithSchedule(_) => Some(res.clone()),
-        };
+        let inlining = matches!(schedule, schedule::CompilerPass::InlineWithSchedule(_));
+
+        let fns_to_optimize = fns_to_optimize(&res, eggcc_config);
+        let batches = batching::batches(
+            &res,
+            &fns_to_optimize,
+            eggcc_config.batching,
+            eggcc_config.max_batch_size,
+        );
 
-        // TODO experiment with different batches of optimizing functions together
-        // currently we use the whole program
-        let batches = match &eggcc_config.optimize_functions {
-            Some(allowed_fns) => {
-                // check that all allowed_fns are in fns
-                for allowed_fn in allowed_fns {
-                    if !fns.contains(allowed_fn) {
-                        panic!(
-                            "Told to optimize function {}, but not found in program",
-                            allowed_fn
-                        );
+        let is_last_pass = i == cutoff - 1;
+        let top_k = if is_last_pass { eggcc_config.top_k } else { 1 };
+        let mut report = (build_report && is_last_pass).then(ExtractionReport::default);
+        // the cache only stores the best program for each function
+        let cache = cache.as_ref().filter(|_| top_k == 1 && report.is_none());
+        let mut dumped = 0;
+        for level in batching::batch_levels(&res, batches) {
+            // Inline from the program as it is now, so that callers
+            // see the callees optimized by earlier levels of this pass.
+            let inline_program = inlining.then(|| res.clone());
+            // Trees can't be shared between threads, so build the egglog programs here
+            // and only run egglog on the worker threads.
+            let mut to_run = vec![];
+            let mut egglog_progs = vec![];
+            for batch in level {
+                let egglog_prog = BatchProgram {
+                    commands: build_program_commands(&res, inline_program.as_ref(), &batch),
+                    schedule: schedule.egglog_schedule().to_string(),
+                };
+                let cache_key = cache.map(|cache| {
+                    cache.key(
+                        eggcc_config.extra_rules.as_ref(),
+                        &format!(
+                            "{}\n{}",
+                            print_commands(&egglog_prog.commands),
+                            egglog_prog.schedule
+                        ),
+                        should_maintain_linearity,
+                    )
+                });
+                if let Some(cached) = cache
+                    .zip(cache_key.as_ref())
+                    .and_then(|(cache, key)| cache.load(key))
+                {
+                    log::info!("Using cached result for pass {} on batch {:?}", i, batch);
+                    for (name, func) in cached {
+                        res.replace_fn(&name, func);
                     }
+                    continue;
+                }
+                to_run.push((batch, cache_key));
+                egglog_progs.push(egglog_prog);
+            }
+
+            log::info!(
+                "Running egglog programs for {} batches on {} threads...",
+                to_run.len(),
+                eggcc_config.jobs.min(to_run.len())
+            );
+            let results = run_egglog_batches(
+                egglog_progs,
+                workers.as_ref(),
+                eggcc_config.extra_rules.as_ref(),
+                eggcc_config.pass_budget.as_ref(),
+                eggcc_config.collect_stats,
+            );
+
+            // extract in batch order, so the result doesn't depend on the number of threads
+            for ((batch, cache_key), result) in to_run.into_iter().zip(results) {
+                log::info!("Extracting pass {} on batch {:?}", i, batch);
+                log::info!("Schedule: {:?}", schedule);
+                let Some(EgglogRun {
+                    serialized,
+                    unextractables,
+                    tuples,
+                    stats,
+                }) = result?
+                else {
+                    eprintln!(
+                        "Warning: pass {} went over budget on batch {:?}, keeping the functions from before the pass.",
+                        i, batch
+                    );
+                    continue;
+                };
+                info.record_egraph_size(i, &batch, tuples);
+                info.record_stats(stats, i, &batch);
+                if let Some(dir) = &eggcc_config.dump_egraphs {
+                    EgraphDump::new(
+                        i,
+                        &batch,
+                        &res,
+                        &serialized,
+                        &unextractables,
+                        should_maintain_linearity,
+                    )
+                    .store(dir, dumped);
+                    dumped += 1;
                 }
+                let extract_start = Instant::now();
 
-                vec![allowed_fns.iter().cloned().collect()]
+                let has_debug_exprs = has_debug_exprs(&serialized);
+                if has_debug_exprs {
+                    log::info!(
+                        "Program has debug expressions, extracting them instead of original program."
+                    );
+                }
+
+                let cost;
+                (res, cost) = extract_fns(
+                    &res,
+                    i,
+                    batch.clone(),
+                    serialized,
+                    unextractables,
+                    should_maintain_linearity,
+                    has_debug_exprs,
+                    top_k,
+                    report.as_mut(),
+                    &mut info.candidates,
+                );
+                info.record_extraction(extract_start.elapsed(), cost);
+
+                if has_debug_exprs {
+                    log::info!("Program has debug expressions, stopping pass {}.", i);
+                    info.report = report;
+                    return Ok((res, info));
+                }
+                // don't cache the fallback, so that the warning shows up on every run
+                if cost.is_some() {
+                    cache_batch(cache, cache_key, &res, &batch);
+                }
             }
-            None => vec![fns.clone()],
+        }
+
+        if report.is_some() {
+            info.report = report;
+        }
+
+        debug_verify_linear_state(&res, i, should_maintain_linearity);
+
+        // now add context to res again for the next pass, since context might be less specific
+        res = res.add_context().0;
+    }
+    Ok((res, info))
+}
+
+/// In debug builds, checks that the program still uses state linearly after a pass.
+/// Programs extracted without linearity are expected to break it, so those are only reported.
+fn debug_verify_linear_state(prog: &TreeProgram, pass: usize, should_maintain_linearity: bool) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let Err(violations) = linearity::verify_linear_state(prog) else {
+        return;
+    };
+    let violations = violations
+        .iter()
+        .map(|violation| violation.to_string())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if should_maintain_linearity {
+        panic!("Pass {pass} broke linearity:\n{violations}");
+    }
+    eprintln!("Warning: pass {pass} was extracted without linearity, and the result is unsound:\n{violations}");
+}
+
+/// The functions of `res` that `eggcc_config` says to optimize, in program order.
+fn fns_to_optimize(res: &TreeProgram, eggcc_config: &EggccConfig) -> Vec<String> {
+    let fns = res.fns();
+    match &eggcc_config.optimize_functions {
+        Some(allowed_fns) => {
+            // check that all allowed_fns are in fns
+            for allowed_fn in allowed_fns {
+                if !fns.contains(allowed_fn) {
+                    panic!(
+                        "Told to optimize function {}, but not found in program",
+                        allowed_fn
+                    );
+                }
+            }
+
+            fns.into_iter()
+                .filter(|func| allowed_fns.contains(func))
+                .collect()
+        }
+        None => fns,
+    }
+}
+
+/// Like `optimize_internal`, but each batch keeps its egraph across consecutive passes
+/// instead of extracting and rebuilding it after every pass.
+/// Extraction happens at the end of each segment of passes (see `egraph_segments`).
+/// Pass budgets, the cache and multiple jobs are not supported in this mode:
+/// batches are optimized one at a time on the calling thread.
+fn optimize_reusing_egraph(
+    program: &TreeProgram,
+    eggcc_config: &EggccConfig,
+    build_report: bool,
+) -> std::result::Result<(TreeProgram, OptimizeInfo), egglog::Error> {
+    let ignored = [
+        (eggcc_config.pass_budget.is_some(), "pass budgets"),
+        (eggcc_config.cache_dir.is_some(), "the cache"),
+        (eggcc_config.jobs > 1, "multiple jobs"),
+    ]
+    .into_iter()
+    .filter_map(|(is_set, setting)| is_set.then_some(setting))
+    .collect::<Vec<_>>();
+    if !ignored.is_empty() {
+        eprintln!(
+            "Warning: ignoring {} when reusing egraphs.",
+            ignored.join(", ")
+        );
+    }
+    let schedule_list = eggcc_config.get_schedule_list();
+    let cutoff = eggcc_config.get_normalized_cutoff(schedule_list.len());
+    let mut res = program.clone();
+    let mut info = OptimizeInfo::default();
+
+    for segment in egraph_segments(&schedule_list[..cutoff], &eggcc_config.checkpoints) {
+        log::info!("Running passes {:?} on one egraph...", segment);
+        let is_last_segment = segment.end == cutoff;
+        let should_maintain_linearity = !is_last_segment || eggcc_config.linearity;
+        let top_k = if is_last_segment {
+            eggcc_config.top_k
+        } else {
+            1
         };
+        let mut report = (build_report && is_last_segment).then(ExtractionReport::default);
+        let first_pass = &schedule_list[segment.start];
+        let inlining = matches!(first_pass, CompilerPass::InlineWithSchedule(_));
 
-        for batch in batches {
-            log::info!("Running pass {} on batch {:?}", i, batch);
-            log::info!("Schedule: {:?}", schedule);
-            // only inline functions on the first pass
-            let egglog_prog = build_program(
+        let batches = batching::batches(
+            &res,
+            &fns_to_optimize(&res, eggcc_config),
+            eggcc_config.batching,
+            eggcc_config.max_batch_size,
+        );
+        for (batch_index, batch) in batches.into_iter().enumerate() {
+            // callees in earlier batches have already been extracted
+            let inline_program = inlining.then(|| res.clone());
+            let mut egraph = egraph_with_prologue(eggcc_config.extra_rules.as_ref())?;
+            egraph.run_program(build_program_commands(
                 &res,
                 inline_program.as_ref(),
                 &batch,
-                schedule.egglog_schedule(),
-            );
-
-            log::info!("Running egglog program...");
-            let mut egraph = egglog::EGraph::default();
-            egraph.parse_and_run_program(None, &egglog_prog)?;
+            ))?;
+            let mut stats = None;
+            for pass in segment.clone() {
+                stats = eggcc_config
+                    .collect_stats
+                    .then(|| PassStats::before_schedule(&egraph));
+                let schedule_start = Instant::now();
+                egraph.parse_and_run_program(None, schedule_list[pass].egglog_schedule())?;
+                if let Some(stats) = &mut stats {
+                    stats.after_schedule(&egraph, schedule_start.elapsed());
+                }
+                info.record_egraph_size(pass, &batch, egraph.num_tuples());
+                if pass + 1 < segment.end {
+                    info.record_stats(stats.take(), pass, &batch);
+                }
+            }
 
+            // serialization and extraction count towards the last pass of the segment
+            let serialize_start = Instant::now();
             let (serialized, unextractables) = serialized_egraph(egraph);
-
-            let mut termdag = egglog::TermDag::default();
-            let has_debug_exprs = has_debug_exprs(&serialized);
-            if has_debug_exprs {
-                log::info!(
-                    "Program has debug expressions, extracting them instead of original program."
-                );
+            if let Some(stats) = &mut stats {
+                stats.after_serialize(serialize_start.elapsed());
+            }
+            info.record_stats(stats, segment.end - 1, &batch);
+            if let Some(dir) = &eggcc_config.dump_egraphs {
+                EgraphDump::new(
+                    segment.end - 1,
+                    &batch,
+                    &res,
+                    &serialized,
+                    &unextractables,
+                    should_maintain_linearity,
+                )
+                .store(dir, batch_index);
             }
-            let (_res_cost, iter_result) = extract(
+            let has_debug_exprs = has_debug_exprs(&serialized);
+            let extract_start = Instant::now();
+            let cost;
+            (res, cost) = extract_fns(
                 &res,
+                segment.end - 1,
                 batch,
                 serialized,
                 unextractables,
-                &mut termdag,
-                DefaultCostModel,
                 should_maintain_linearity,
                 has_debug_exprs,
+                top_k,
+                report.as_mut(),
+                &mut info.candidates,
             );
-
-            res = iter_result;
-
+            info.record_extraction(extract_start.elapsed(), cost);
             if has_debug_exprs {
-                log::info!("Program has debug expressions, stopping pass {}.", i);
-                return Ok(res);
+                log::info!(
+                    "Program has debug expressions, stopping after passes {:?}.",
+                    segment
+                );
+                info.report = report;
+                return Ok((res, info));
             }
         }
 
-        // now add context to res again for the next pass, since context might be less specific
+        if report.is_some() {
+            info.report = report;
+        }
+        debug_verify_linear_state(&res, segment.end - 1, should_maintain_linearity);
         res = res.add_context().0;
     }
-    Ok(res)
+    Ok((res, info))
+}
+
+/// Splits `passes` into ranges of passes that run on the same egraph.
+/// A segment ends after each checkpoint, and a new one starts at every
+/// inlining pass, since inlining is set up when the program is added to the egraph.
+fn egraph_segments(passes: &[CompilerPass], checkpoints: &[usize]) -> Vec<Range<usize>> {
+    let mut segments = vec![];
+    let mut start = 0;
+    for (i, pass) in passes.iter().enumerate() {
+        if i > start && matches!(pass, CompilerPass::InlineWithSchedule(_)) {
+            segments.push(start..i);
+            start = i;
+        }
+        if checkpoints.contains(&i) {
+            segments.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < passes.len() {
+        segments.push(start..passes.len());
+    }
+    segments
+}
+
+/// Stores the optimized functions of `batch` in the cache, if caching is on.
+fn cache_batch(
+    cache: Option<&OptimizationCache>,
+    cache_key: Option<String>,
+    res: &TreeProgram,
+    batch: &[String],
+) {
+    if let Some((cache, key)) = cache.zip(cache_key) {
+        let fns = batch
+            .iter()
+            .map(|func| (func.clone(), res.get_function(func).unwrap().clone()))
+            .collect::<Vec<_>>();
+        cache.store(&key, &fns);
+    }
+}
+
+/// The egglog program for one batch, with the schedule separate
+/// so that it can be timed on its own.
+struct BatchProgram {
+    /// From `build_program_commands`.
+    commands: Vec<Command>,
+    schedule: String,
+}
+
+/// The result of running a `BatchProgram`.
+struct EgglogRun {
+    serialized: egraph_serialize::EGraph,
+    unextractables: IndexSet<String>,
+    /// Number of tuples in the egraph after running the schedule.
+    tuples: usize,
+    /// Only collected when asked for, and without the extraction filled in.
+    stats: Option<PassStats>,
+}
+
+/// `None` if the program went over budget.
+type EgglogRunResult = std::result::Result<Option<EgglogRun>, egglog::Error>;
+
+/// Runs the egglog program after the prologue and serializes the resulting egraph.
+/// Returns `None` if the schedule went over `budget`.
+fn run_egglog_with_budget(
+    egglog_prog: BatchProgram,
+    extra_rules: Option<&ExtraRules>,
+    budget: Option<&PassBudget>,
+    collect_stats: bool,
+) -> EgglogRunResult {
+    let mut egraph = egraph_with_prologue(extra_rules)?;
+    egraph.run_program(egglog_prog.commands)?;
+    if let Some(max_nodes) = budget.and_then(|budget| budget.max_nodes) {
+        egraph.node_limit = max_nodes;
+    }
+    let mut stats = collect_stats.then(|| PassStats::before_schedule(&egraph));
+
+    let schedule_start = Instant::now();
+    egraph.parse_and_run_program(None, &egglog_prog.schedule)?;
+    let schedule_time = schedule_start.elapsed();
+    if budget.is_some_and(|budget| budget.is_exceeded(&egraph, schedule_time)) {
+        return Ok(None);
+    }
+    if let Some(stats) = &mut stats {
+        stats.after_schedule(&egraph, schedule_time);
+    }
+    let tuples = egraph.num_tuples();
+
+    let serialize_start = Instant::now();
+    let (serialized, unextractables) = serialized_egraph(egraph);
+    if let Some(stats) = &mut stats {
+        stats.after_serialize(serialize_start.elapsed());
+    }
+    Ok(Some(EgglogRun {
+        serialized,
+        unextractables,
+        tuples,
+        stats,
+    }))
+}
+
+/// Threads that run egglog programs with `run_egglog_with_budget`.
+/// They live as long as the optimization, so each one only loads the prologue
+/// (see `egraph_with_prologue`) once for all passes and levels of batches.
+struct EgglogWorkers {
+    /// Dropped to tell the workers to stop.
+    jobs: Option<mpsc::Sender<(usize, BatchProgram)>>,
+    results: mpsc::Receiver<(usize, std::thread::Result<EgglogRunResult>)>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl EgglogWorkers {
+    fn new(
+        jobs: usize,
+        extra_rules: Option<&ExtraRules>,
+        budget: Option<&PassBudget>,
+        collect_stats: bool,
+    ) -> Self {
+        let (job_sender, job_receiver) = mpsc::channel::<(usize, BatchProgram)>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, results) = mpsc::channel();
+        let handles = (0..jobs)
+            .map(|_| {
+                let job_receiver = job_receiver.clone();
+                let result_sender = result_sender.clone();
+                let extra_rules = extra_rules.cloned();
+                let budget = budget.cloned();
+                std::thread::spawn(move || loop {
+                    // release the queue before running egglog
+                    let next = job_receiver.lock().unwrap().recv();
+                    let Ok((index, egglog_prog)) = next else {
+                        break;
+                    };
+                    // panics are passed on to the thread waiting for the results
+                    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        run_egglog_with_budget(
+                            egglog_prog,
+                            extra_rules.as_ref(),
+                            budget.as_ref(),
+                            collect_stats,
+                        )
+                    }));
+                    if result_sender.send((index, result)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        EgglogWorkers {
+            jobs: Some(job_sender),
+            results,
+            handles,
+        }
+    }
+
+    /// Runs each egglog program on the workers.
+    /// The results are in the same order as `egglog_progs`.
+    fn run(&self, egglog_progs: Vec<BatchProgram>) -> Vec<EgglogRunResult> {
+        let num_progs = egglog_progs.len();
+        let jobs = self.jobs.as_ref().expect("Workers are running");
+        for job in egglog_progs.into_iter().enumerate() {
+            jobs.send(job).expect("Workers are running");
+        }
+        let mut results = (0..num_progs).map(|_| None).collect::<Vec<_>>();
+        for _ in 0..num_progs {
+            let (index, result) = self.results.recv().expect("Workers are running");
+            match result {
+                Ok(result) => results[index] = Some(result),
+                Err(panic) => std::panic::resume_unwind(panic),
+            }
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("Every egglog program was run"))
+            .collect()
+    }
+}
+
+impl Drop for EgglogWorkers {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for handle in self.handles.drain(..) {
+            // panics were already passed on by `run`
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Runs each egglog program with `run_egglog_with_budget`,
+/// on `workers` if there are any and on this thread otherwise.
+/// The results are in the same order as `egglog_progs`.
+fn run_egglog_batches(
+    egglog_progs: Vec<BatchProgram>,
+    workers: Option<&EgglogWorkers>,
+    extra_rules: Option<&ExtraRules>,
+    budget: Option<&PassBudget>,
+    collect_stats: bool,
+) -> Vec<EgglogRunResult> {
+    match workers {
+        Some(workers) => workers.run(egglog_progs),
+        None => egglog_progs
+            .into_iter()
+            .map(|egglog_prog| {
+                run_egglog_with_budget(egglog_prog, extra_rules, budget, collect_stats)
+            })
+            .collect(),
+    }
+}
+
+/// Extracts `fns` from the serialized egraph, returning the new program
+/// and the cost of the extracted functions.
+/// Functions that can't be extracted keep their version from `res` with a warning,
+/// and then no cost is returned.
+/// When `top_k` is greater than one, candidates for each function are added to `candidates`.
+/// When `report` is given, the extraction of each function is added to it.
+#[allow(clippy::too_many_arguments)]
+fn extract_fns(
+    res: &TreeProgram,
+    pass: usize,
+    fns: Vec<String>,
+    serialized: egraph_serialize::EGraph,
+    unextractables: IndexSet<String>,
+    should_maintain_linearity: bool,
+    has_debug_exprs: bool,
+    top_k: usize,
+    report: Option<&mut ExtractionReport>,
+    candidates: &mut IndexMap<String, Vec<RcExpr>>,
+) -> (TreeProgram, Option<f64>) {
+    let mut termdag = egglog::TermDag::default();
+    let (extracted, cost, failed) = if top_k > 1 && !has_debug_exprs {
+        let (extracted, fn_candidates) = extract_top_k(
+            res,
+            fns.clone(),
+            serialized,
+            unextractables,
+            &mut termdag,
+            DefaultCostModel,
+            should_maintain_linearity,
+            top_k,
+        );
+        let failed = fns
+            .into_iter()
+            .filter(|func| !fn_candidates.contains_key(func))
+            .collect::<Vec<_>>();
+        let mut cost = 0.0;
+        for (func, func_candidates) in fn_candidates {
+            if let Some((best_cost, _best)) = func_candidates.first() {
+                cost += best_cost.into_inner();
+            }
+            let exprs = func_candidates.into_iter().map(|(_cost, e)| e).collect();
+            candidates.insert(func, exprs);
+        }
+        (extracted, cost, failed)
+    } else {
+        let (res_cost, extracted, fns_report, failed) = extract_with_fallback(
+            res,
+            fns,
+            serialized,
+            unextractables,
+            &mut termdag,
+            DefaultCostModel,
+            should_maintain_linearity,
+            has_debug_exprs,
+            report.is_some(),
+        );
+        if let (Some(report), Some(fns_report)) = (report, fns_report) {
+            report.functions.extend(fns_report.functions);
+        }
+        (extracted, res_cost.into_inner(), failed)
+    };
+
+    if !failed.is_empty() {
+        eprintln!(
+            "Warning: pass {} failed to extract {:?}, keeping the versions from before the pass.",
+            pass, failed
+        );
+        return (extracted, None);
+    }
+    (extracted, Some(cost))
 }
 
 fn check_program_gets_type(program: TreeProgram) -> Result {
@@ -495,7 +1352,8 @@ fn egglog_test_internal(
 ) -> Result {
     // first interpret the programs on the value
     for prog in progs {
-        let (result_val, print_log) = interpret_dag_prog(&prog, &input);
+        let (result_val, print_log) = interpret_dag_prog(&prog, &input)
+            .unwrap_or_else(|err| panic!("Program {:?}\nfailed: {}", prog, err));
         assert_eq!(
             result_val, expected,
             "Program {:?}\nproduced:\n{}\ninstead of expected:\n{}",
@@ -545,3 +1403,307 @@ fn egglog_test_internal(
 
     Ok(res?)
 }
+
+/// `main` calls `count`, which counts up from its argument to 10 in a loop.
+/// The call and the loop give the passes something to do: inlining,
+/// loop optimizations and constant folding all grow the egraph.
+#[cfg(test)]
+fn count_program() -> TreeProgram {
+    use crate::ast::*;
+
+    let ty = tuplet!(intt(), statet());
+    program!(
+        function("main", ty.clone(), ty.clone(), call("count", arg())),
+        function(
+            "count",
+            ty.clone(),
+            ty.clone(),
+            parallel!(
+                get(
+                    dowhile(
+                        parallel!(getat(0)),
+                        push(
+                            add(getat(0), int(1)),
+                            single(less_than(add(getat(0), int(1)), int(10)))
+                        )
+                    ),
+                    0
+                ),
+                getat(1)
+            )
+        ),
+    )
+}
+
+#[test]
+fn test_pass_budget_falls_back() {
+    let prog = count_program();
+
+    // every pass goes over a budget of zero nodes or no time at all, so nothing changes
+    let empty_budgets = [
+        PassBudget {
+            max_nodes: Some(0),
+            max_time: None,
+        },
+        PassBudget {
+            max_nodes: None,
+            max_time: Some(Duration::ZERO),
+        },
+    ];
+    for budget in empty_budgets {
+        let config = EggccConfig {
+            pass_budget: Some(budget),
+            ..Default::default()
+        };
+        let res = optimize(&prog, &config).unwrap();
+        assert!(are_progs_eq(res.add_dummy_ctx().0, prog.add_dummy_ctx().0));
+    }
+
+    // a budget just under the largest egraph of an unbudgeted run stops the pass
+    // that first grows the egraph that large, and the passes before it run unchanged
+    let (_res, sizes) = optimize_with_egraph_sizes(&prog, &EggccConfig::default()).unwrap();
+    let tuples = sizes.iter().map(|size| size.tuples).collect::<Vec<_>>();
+    let largest = *tuples.iter().max().unwrap();
+    let blown_up = tuples.iter().position(|size| *size == largest).unwrap();
+    let config = EggccConfig {
+        pass_budget: Some(PassBudget {
+            max_nodes: Some(largest - 1),
+            max_time: None,
+        }),
+        ..Default::default()
+    };
+    let (_res, budgeted_sizes) = optimize_with_egraph_sizes(&prog, &config).unwrap();
+    assert_eq!(
+        budgeted_sizes[..blown_up]
+            .iter()
+            .map(|size| size.tuples)
+            .collect::<Vec<_>>(),
+        tuples[..blown_up]
+    );
+    assert!(budgeted_sizes.iter().all(|size| size.pass != blown_up));
+
+    // a generous budget still optimizes the program
+    let config = EggccConfig {
+        pass_budget: Some(PassBudget {
+            max_nodes: Some(usize::MAX),
+            max_time: Some(Duration::from_secs(600)),
+        }),
+        ..Default::default()
+    };
+    let res = optimize(&prog, &config).unwrap();
+    assert!(!are_progs_eq(res.add_dummy_ctx().0, prog.add_dummy_ctx().0));
+}
+
+#[test]
+fn test_extraction_falls_back() {
+    use crate::ast::*;
+
+    let prog = program!(
+        function(
+            "main",
+            tuplet!(intt(), statet()),
+            tuplet!(intt(), statet()),
+            parallel!(add(int(1), int(2)), getat(1))
+        ),
+        function(
+            "other",
+            tuplet!(intt(), statet()),
+            tuplet!(intt(), statet()),
+            parallel!(mul(getat(0), int(1)), getat(1))
+        ),
+    );
+    let fns = prog.fns();
+    let mut egraph = egglog::EGraph::default();
+    egraph
+        .parse_and_run_program(None, &build_program(&prog, None, &fns, ""))
+        .unwrap();
+    let (serialized, mut unextractables) = serialized_egraph(egraph);
+    // no function can be extracted without its `Function` node
+    unextractables.insert("Function".to_string());
+
+    let (res, cost) = extract_fns(
+        &prog,
+        0,
+        fns,
+        serialized,
+        unextractables,
+        true,
+        false,
+        1,
+        None,
+        &mut IndexMap::new(),
+    );
+    assert_eq!(cost, None);
+    assert!(are_progs_eq(res, prog));
+}
+
+#[test]
+fn test_parallel_batches_are_deterministic() {
+    use crate::{ast::*, batching::Batching};
+
+    let ty = tuplet!(intt(), statet());
+    let double = |name: &str| {
+        function(
+            name,
+            ty.clone(),
+            ty.clone(),
+            parallel!(add(getat(0), getat(0)), getat(1)),
+        )
+    };
+    let prog = program!(
+        function(
+            "main",
+            ty.clone(),
+            ty.clone(),
+            call("f", call("g", call("h", arg())))
+        ),
+        double("f"),
+        double("g"),
+        double("h"),
+    );
+
+    let config = |jobs| EggccConfig {
+        batching: Batching::Sccs,
+        jobs,
+        ..Default::default()
+    };
+    let sequential = optimize(&prog, &config(1)).unwrap();
+    let parallel = optimize(&prog, &config(4)).unwrap();
+    assert!(are_progs_eq(sequential, parallel));
+}
+
+#[test]
+fn test_cached_prologue_matches_full_program() {
+    // inlining `count` and its loop exercise the function inlining
+    // and loop context actions, not just the program terms
+    let prog = count_program();
+    let fns = prog.fns();
+    let schedule = parallel_schedule()[0].egglog_schedule().to_string();
+    let extract_prog = |egraph| {
+        let (serialized, unextractables) = serialized_egraph(egraph);
+        extract(
+            &prog,
+            fns.clone(),
+            serialized,
+            unextractables,
+            &mut TermDag::default(),
+            DefaultCostModel,
+            true,
+            false,
+        )
+    };
+
+    let mut full = egglog::EGraph::default();
+    full.parse_and_run_program(None, &build_program(&prog, Some(&prog), &fns, &schedule))
+        .unwrap();
+    let (full_cost, full_res) = extract_prog(full);
+    assert!(!are_progs_eq(full_res.clone(), prog.clone()));
+
+    // the second egraph reuses the prologue loaded for the first
+    for _ in 0..2 {
+        let mut cached = egraph_with_prologue(None).unwrap();
+        cached
+            .run_program(build_program_commands(&prog, Some(&prog), &fns))
+            .unwrap();
+        cached.parse_and_run_program(None, &schedule).unwrap();
+        let (cached_cost, cached_res) = extract_prog(cached);
+        assert_eq!(cached_cost, full_cost);
+        assert!(are_progs_eq(cached_res, full_res.clone()));
+    }
+}
+
+#[test]
+fn test_reuse_egraph() {
+    use crate::ast::*;
+    use crate::interpreter::interpret_dag_prog;
+
+    let prog = count_program();
+    let passes = parallel_schedule();
+    // the inlining pass starts a second egraph, and the checkpoint a third
+    let checkpoints = vec![0];
+    assert_eq!(egraph_segments(&passes, &checkpoints).len(), 3);
+
+    let config = EggccConfig {
+        reuse_egraph: true,
+        checkpoints,
+        ..Default::default()
+    };
+    let (res, sizes) = optimize_with_egraph_sizes(&prog, &config).unwrap();
+    // one size per pass, all for the same batch
+    assert_eq!(sizes.len(), passes.len());
+    assert!(sizes.iter().all(|size| size.batch == prog.fns()));
+    assert!(!are_progs_eq(res.add_dummy_ctx().0, prog.add_dummy_ctx().0));
+
+    // the program extracted at the end of each segment feeds the next one unchanged
+    let input = tuplev!(intv(3), statev());
+    assert_eq!(
+        interpret_dag_prog(&res, &input).unwrap(),
+        interpret_dag_prog(&prog, &input).unwrap()
+    );
+
+    // without reuse, the size is also tracked for every pass
+    let (_res, sizes) = optimize_with_egraph_sizes(&prog, &EggccConfig::default()).unwrap();
+    assert_eq!(sizes.len(), passes.len());
+}
+
+#[test]
+fn test_egraph_segments() {
+    let pass = CompilerPass::Schedule("".to_string());
+    let inline = CompilerPass::InlineWithSchedule("".to_string());
+    let passes = vec![inline.clone(), pass.clone(), inline, pass.clone(), pass];
+    assert_eq!(egraph_segments(&passes, &[]), vec![0..2, 2..5]);
+    assert_eq!(
+        egraph_segments(&passes, &[0, 3]),
+        vec![0..1, 1..2, 2..4, 4..5]
+    );
+    assert_eq!(egraph_segments(&passes, &[4]), vec![0..2, 2..5]);
+}
+
+#[test]
+fn test_pass_stats() {
+    let prog = count_program();
+    let config = EggccConfig {
+        collect_stats: true,
+        ..Default::default()
+    };
+    let (_res, stats) = optimize_with_stats(&prog, &config).unwrap();
+    assert_eq!(stats.len(), parallel_schedule().len());
+    for (pass, stats) in stats.iter().enumerate() {
+        assert_eq!(stats.pass, pass);
+        assert_eq!(stats.batch, prog.fns());
+        assert!(stats.tuples_before > 0);
+        assert!(stats.tuples_after > 0);
+        assert!(stats.enodes_before > 0 && stats.enodes_after > 0);
+        assert!(stats.eclasses_before > 0 && stats.eclasses_after > 0);
+        assert!(stats.extracted_cost.is_some());
+        assert!(!stats.rulesets.is_empty());
+    }
+    // the loop and the call give the rules something to match
+    assert!(stats
+        .iter()
+        .any(|stats| stats.enodes_after > stats.enodes_before));
+    assert!(stats
+        .iter()
+        .flat_map(|stats| &stats.rules)
+        .any(|rule| rule.applications > 0));
+
+    // when reusing the egraph, only the last pass of each segment is serialized for extraction
+    let config = EggccConfig {
+        collect_stats: true,
+        reuse_egraph: true,
+        ..Default::default()
+    };
+    let segment_ends = egraph_segments(&parallel_schedule(), &[])
+        .into_iter()
+        .map(|segment| segment.end - 1)
+        .collect::<Vec<_>>();
+    let (_res, stats) = optimize_with_stats(&prog, &config).unwrap();
+    assert_eq!(stats.len(), parallel_schedule().len());
+    for (pass, stats) in stats.iter().enumerate() {
+        assert!(stats.tuples_after > 0);
+        assert_eq!(stats.serialize_secs > 0.0, segment_ends.contains(&pass));
+    }
+
+    let (_res, stats) = optimize_with_stats(&prog, &EggccConfig::default()).unwrap();
+    assert!(stats.is_empty());
+}
@@ -1,16 +1,20 @@
 //! This file contains helpers for making the extracted
 //! program use memory linearly.
 //! In particular, it finds all the effectful e-nodes in an extracted term that are along the state edge path.
+//! It also has `verify_linear_state`, which checks that any tree program uses state linearly.
 
-use std::{collections::HashSet, rc::Rc};
+use std::{collections::HashSet, fmt::Display, rc::Rc};
 
 use egglog::Term;
 use egraph_serialize::{ClassId, NodeId};
 use indexmap::{IndexMap, IndexSet};
+use thiserror::Error;
 
 use crate::{
     greedy_dag_extractor::{EgraphInfo, Extractor},
+    pretty_print::PrettyPrinter,
     schema::{Expr, *},
+    typechecker::{TypeCache, TypeError},
 };
 
 type EffectfulNodes = IndexMap<ClassId, IndexSet<*const Expr>>;
@@ -312,3 +316,233 @@ impl Expr {
         }
     }
 }
+
+/// The ways a program can fail to use state linearly.
+#[derive(Debug, Clone, Error)]
+pub enum LinearityViolationKind {
+    #[error("The state edge is consumed by {consumers} effects, so their effects are duplicated")]
+    DuplicatedState { consumers: usize },
+    #[error("The state edge is never consumed, so its effects are dropped")]
+    DroppedState,
+    #[error("The effect takes {inputs} state edges instead of one")]
+    WrongStateInputs { inputs: usize },
+    #[error("The program is ill-typed: {}", .0.kind)]
+    IllTyped(TypeError),
+}
+
+/// An expression that breaks linearity, along with the function it is in.
+#[derive(Debug, Clone)]
+pub struct LinearityViolation {
+    pub kind: LinearityViolationKind,
+    pub function: String,
+    pub expr: RcExpr,
+}
+
+impl Display for LinearityViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Linearity violation in function {}: {}",
+            self.function, self.kind
+        )?;
+        let (log, _binding) = PrettyPrinter::default().to_egglog_default(&self.expr);
+        write!(f, "{}", log.trim())
+    }
+}
+
+/// Checks that every state edge in the program is consumed by exactly one effect
+/// in its region, so no effect is duplicated or dropped along any path.
+/// Each branch of an `If` or `Switch` and the body of a `DoWhile` is its own region,
+/// which passes on the state edge it is given when its result holds state.
+/// Regions whose result holds no state may ignore their state argument,
+/// since its other uses are checked in the parent region.
+/// Unlike the linearity checks during extraction, this works on any well-typed program,
+/// for example one extracted with linearity disabled.
+pub fn verify_linear_state(prog: &TreeProgram) -> Result<(), Vec<LinearityViolation>> {
+    let (typed, types) = prog.try_with_arg_types_and_cache().map_err(|err| {
+        vec![LinearityViolation {
+            function: err.function.clone().unwrap_or_default(),
+            expr: err.expr.clone(),
+            kind: LinearityViolationKind::IllTyped(err),
+        }]
+    })?;
+
+    let mut violations = vec![];
+    for func in std::iter::once(&typed.entry).chain(&typed.functions) {
+        let Expr::Function(name, _in_ty, _out_ty, body) = func.as_ref() else {
+            panic!("Expected function, got {:?}", func);
+        };
+        let mut verifier = LinearityVerifier {
+            types: &types,
+            function: name,
+            checked_regions: Default::default(),
+            violations: &mut violations,
+        };
+        verifier.check_region(body);
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+struct LinearityVerifier<'a> {
+    types: &'a TypeCache,
+    function: &'a str,
+    checked_regions: HashSet<*const Expr>,
+    violations: &'a mut Vec<LinearityViolation>,
+}
+
+impl LinearityVerifier<'_> {
+    fn is_effectful(&self, expr: &RcExpr) -> bool {
+        self.types[&Rc::as_ptr(expr)].contains_state()
+    }
+
+    /// All arguments of a region are the same state edge,
+    /// so they share a key even if they aren't shared pointers.
+    fn key(expr: &RcExpr) -> *const Expr {
+        match expr.as_ref() {
+            Expr::Arg(..) => std::ptr::null(),
+            _ => Rc::as_ptr(expr),
+        }
+    }
+
+    fn violation(&mut self, kind: LinearityViolationKind, expr: &RcExpr) {
+        self.violations.push(LinearityViolation {
+            kind,
+            function: self.function.to_string(),
+            expr: expr.clone(),
+        });
+    }
+
+    /// Checks the region rooted at `root`, and then the regions nested in it.
+    fn check_region(&mut self, root: &RcExpr) {
+        if !self.checked_regions.insert(Rc::as_ptr(root)) {
+            return;
+        }
+
+        // find the expressions in this region, and the regions nested in it
+        let mut exprs = IndexMap::<*const Expr, RcExpr>::new();
+        let mut nested = vec![];
+        let mut todo = vec![root.clone()];
+        while let Some(expr) = todo.pop() {
+            if exprs.insert(Rc::as_ptr(&expr), expr.clone()).is_some() {
+                continue;
+            }
+            match expr.as_ref() {
+                Expr::If(_pred, _input, then_branch, else_branch) => {
+                    nested.push(then_branch.clone());
+                    nested.push(else_branch.clone());
+                }
+                Expr::Switch(_pred, _input, branches) => nested.extend(branches.iter().cloned()),
+                Expr::DoWhile(_input, body) => nested.push(body.clone()),
+                _ => {}
+            }
+            todo.extend(expr.children_same_scope());
+        }
+
+        // every effect other than the argument takes exactly one state edge
+        let mut consumers = IndexMap::<*const Expr, (RcExpr, usize)>::new();
+        for expr in exprs.values() {
+            if !self.is_effectful(expr) {
+                continue;
+            }
+            consumers
+                .entry(Self::key(expr))
+                .or_insert_with(|| (expr.clone(), 0));
+            if matches!(expr.as_ref(), Expr::Arg(..)) {
+                continue;
+            }
+            let state_inputs: Vec<RcExpr> = expr
+                .children_same_scope()
+                .into_iter()
+                .filter(|child| self.is_effectful(child))
+                .collect();
+            if state_inputs.len() != 1 {
+                let kind = LinearityViolationKind::WrongStateInputs {
+                    inputs: state_inputs.len(),
+                };
+                self.violation(kind, expr);
+            }
+            for input in state_inputs {
+                consumers
+                    .entry(Self::key(&input))
+                    .or_insert_with(|| (input.clone(), 0))
+                    .1 += 1;
+            }
+        }
+
+        // and every state edge other than the region's result is consumed exactly once
+        // a region without state in its result ignores its argument's state edge
+        let root_key = Self::key(root);
+        let ignores_arg = !self.is_effectful(root);
+        for (key, (expr, count)) in consumers {
+            match count {
+                0 if key != root_key && !(ignores_arg && key.is_null()) => {
+                    self.violation(LinearityViolationKind::DroppedState, &expr)
+                }
+                0 | 1 => {}
+                consumers => {
+                    let kind = LinearityViolationKind::DuplicatedState { consumers };
+                    self.violation(kind, &expr)
+                }
+            }
+        }
+
+        for region in nested {
+            self.check_region(&region);
+        }
+    }
+}
+
+#[test]
+fn test_verify_linear_state() {
+    use crate::ast::*;
+
+    let check = |out_ty: Type, body: RcExpr| {
+        let prog = program!(function("main", tuplet!(intt(), statet()), out_ty, body),);
+        verify_linear_state(&prog)
+    };
+
+    let linear = tif(
+        less_than(getat(0), int(0)),
+        arg(),
+        parallel!(getat(0), tprint(getat(0), getat(1))),
+        parallel!(getat(0), getat(1)),
+    );
+    assert!(check(tuplet!(intt(), statet()), linear).is_ok());
+
+    // both prints use the state from the allocation,
+    // and the load's state edge is never used
+    let ptr_and_state = alloc(0, int(1), getat(1), pointert(intt()));
+    let state = get(ptr_and_state.clone(), 1);
+    let loaded = load(get(ptr_and_state, 0), tprint(int(1), state.clone()));
+    let unsound = parallel!(get(loaded, 0), tprint(int(2), state));
+    let violations = check(tuplet!(intt(), statet()), unsound).unwrap_err();
+    assert_eq!(violations.len(), 2);
+    assert!(violations.iter().any(|violation| matches!(
+        violation.kind,
+        LinearityViolationKind::DuplicatedState { consumers: 2 }
+    )));
+    assert!(violations.iter().any(|violation| {
+        matches!(violation.kind, LinearityViolationKind::DroppedState)
+            && matches!(violation.expr.as_ref(), Expr::Bop(BinaryOp::Load, ..))
+    }));
+    assert!(violations[0].to_string().contains("in function main"));
+
+    // a branch that passes its argument through is its own result
+    let pass_through = tif(less_than(getat(0), int(0)), arg(), arg(), arg());
+    assert!(check(tuplet!(intt(), statet()), pass_through).is_ok());
+
+    // a pure branch doesn't need to use the state in its input
+    let pure_if = tif(
+        less_than(getat(0), int(0)),
+        arg(),
+        single(getat(0)),
+        single(int(1)),
+    );
+    let uses_pure_if = parallel!(get(pure_if, 0), getat(1));
+    assert!(check(tuplet!(intt(), statet()), uses_pure_if).is_ok());
+}
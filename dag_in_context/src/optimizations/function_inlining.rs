@@ -1,13 +1,14 @@
 use std::{rc::Rc, vec};
 
-use egglog::Term;
+use egglog::ast::{Action, Change, DUMMY_SPAN};
 use indexmap::{IndexMap, IndexSet};
 
 use crate::{
     add_context::ContextCache,
-    print_with_intermediate_helper,
+    egglog_call, egglog_string,
     schema::{Expr, RcExpr, TreeProgram},
     to_egglog::TreeToEgglog,
+    TermActions,
 };
 
 #[derive(Clone, PartialEq, PartialOrd, Eq, Ord)]
@@ -125,53 +126,51 @@ pub fn function_inlining_pairs(
     inlined_calls
 }
 
-// Returns a formatted string of (union call body) for each pair
-pub fn print_function_inlining_pairs(
+/// Actions that union each call with the inlined body, adding the terms to `actions`.
+/// Inlined calls are also marked for extraction purposes.
+pub(crate) fn function_inlining_actions(
     function_inlining_pairs: Vec<CallBody>,
-    printed: &mut String,
     tree_state: &mut TreeToEgglog,
-    term_cache: &mut IndexMap<Term, String>,
-) -> String {
-    let inlined_calls = "";
-    // Get unions and mark each call as inlined for extraction purposes
-    let printed_pairs = function_inlining_pairs
+    actions: &mut TermActions,
+) -> Vec<Action> {
+    function_inlining_pairs
         .iter()
-        .map(|cb| {
+        .flat_map(|cb| {
             let Expr::Call(callee, _) = cb.call.as_ref() else {
                 panic!("Tried to inline non-call")
             };
             let call_term = cb.call.to_egglog_with(tree_state);
-            let call_with_intermed = print_with_intermediate_helper(
-                &tree_state.termdag,
-                call_term.clone(),
-                term_cache,
-                printed,
-            );
+            let call_with_intermed = actions.add_term(&tree_state.termdag, call_term);
 
             let body_term = cb.body.to_egglog_with(tree_state);
-            let inlined_with_intermed =
-                print_with_intermediate_helper(&tree_state.termdag, body_term, term_cache, printed);
+            let inlined_with_intermed = actions.add_term(&tree_state.termdag, body_term);
 
             let call_args = cb.call.children_exprs()[0].to_egglog_with(tree_state);
-            let call_args_with_intermed = print_with_intermediate_helper(
-                &tree_state.termdag,
-                call_args.clone(),
-                term_cache,
-                printed,
-            );
-            format!(
+            let call_args_with_intermed = actions.add_term(&tree_state.termdag, call_args);
+            [
+                Action::Union(
+                    DUMMY_SPAN.clone(),
+                    call_with_intermed,
+                    inlined_with_intermed,
+                ),
+                Action::Expr(
+                    DUMMY_SPAN.clone(),
+                    egglog_call(
+                        "InlinedCall",
+                        vec![egglog_string(callee), call_args_with_intermed.clone()],
+                    ),
+                ),
                 // We need to subsume, otherwise the Call in the original program could get
                 // substituted into another context during optimization and no longer match InlinedCall.
-                "
-(union {call_with_intermed} {inlined_with_intermed})
-(InlinedCall \"{callee}\" {call_args_with_intermed})
-(subsume (Call \"{callee}\" {call_args_with_intermed}))
-",
-            )
+                Action::Change(
+                    DUMMY_SPAN.clone(),
+                    Change::Subsume,
+                    "Call".into(),
+                    vec![egglog_string(callee), call_args_with_intermed],
+                ),
+            ]
         })
-        .collect::<Vec<_>>()
-        .join("\n");
-    format!("{inlined_calls} {printed_pairs}")
+        .collect()
 }
 
 // Check that function inling pairs produces the right number of pairs for
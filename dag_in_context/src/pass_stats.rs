@@ -0,0 +1,192 @@
+//! Statistics about each pass and batch in `optimize`,
+//! used to find out which passes and rules are expensive.
+//! Counting e-nodes and e-classes serializes the egraph,
+//! so collecting statistics slows optimization down.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use egglog::{RunReport, SerializeConfig};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PassStats {
+    pub pass: usize,
+    pub batch: Vec<String>,
+    /// Number of tuples in the egraph after adding the program, before running the schedule.
+    pub tuples_before: usize,
+    /// Number of tuples in the egraph after running the schedule.
+    pub tuples_after: usize,
+    /// Size of the egraph before running the schedule.
+    pub enodes_before: usize,
+    pub eclasses_before: usize,
+    /// Size of the egraph after running the schedule.
+    pub enodes_after: usize,
+    pub eclasses_after: usize,
+    /// Time spent running the egglog schedule, in seconds.
+    pub schedule_secs: f64,
+    /// Time spent serializing the egraph for extraction, in seconds.
+    /// Zero for passes that continue on the same egraph without extracting
+    /// (see `EggccConfig::reuse_egraph`).
+    pub serialize_secs: f64,
+    /// Time spent extracting the batch, in seconds.
+    pub extract_secs: f64,
+    /// Total cost of the extracted functions,
+    /// or `None` if the batch was not extracted.
+    pub extracted_cost: Option<f64>,
+    /// Sorted by total time, most expensive first.
+    pub rules: Vec<RuleStats>,
+    /// Sorted by total time, most expensive first.
+    pub rulesets: Vec<RulesetStats>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RuleStats {
+    pub rule: String,
+    pub matches: usize,
+    /// How many times the actions of the rule ran.
+    /// egglog runs them once for each match it finds,
+    /// so this counts the matches of runs that applied the rule.
+    pub applications: usize,
+    pub search_secs: f64,
+    pub apply_secs: f64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RulesetStats {
+    pub ruleset: String,
+    pub search_secs: f64,
+    pub apply_secs: f64,
+    pub rebuild_secs: f64,
+}
+
+impl PassStats {
+    /// Starts collecting statistics for a schedule about to run on `egraph`.
+    pub(crate) fn before_schedule(egraph: &egglog::EGraph) -> PassStats {
+        let (enodes_before, eclasses_before) = egraph_size(egraph);
+        PassStats {
+            tuples_before: egraph.num_tuples(),
+            enodes_before,
+            eclasses_before,
+            ..Default::default()
+        }
+    }
+
+    /// Records the schedule that just ran on `egraph`, including its run report.
+    pub(crate) fn after_schedule(&mut self, egraph: &egglog::EGraph, schedule_time: Duration) {
+        self.schedule_secs = schedule_time.as_secs_f64();
+        self.tuples_after = egraph.num_tuples();
+        (self.enodes_after, self.eclasses_after) = egraph_size(egraph);
+        if let Some(report) = egraph.get_run_report() {
+            self.add_run_report(report);
+        }
+    }
+
+    pub(crate) fn after_serialize(&mut self, serialize_time: Duration) {
+        self.serialize_secs = serialize_time.as_secs_f64();
+    }
+
+    /// Adds the matches and times in `report` to the ones recorded so far.
+    fn add_run_report(&mut self, report: &RunReport) {
+        let secs = |time: &Duration| time.as_secs_f64();
+
+        // egglog keys these by symbol, so convert to strings to sort by name
+        let mut rules = std::mem::take(&mut self.rules)
+            .into_iter()
+            .map(|stats| (stats.rule.clone(), stats))
+            .collect::<BTreeMap<_, _>>();
+        for (rule, matches) in &report.num_matches_per_rule {
+            let stats = stats_for(&mut rules, rule);
+            stats.matches += matches;
+            stats.applications += matches;
+        }
+        for (rule, time) in &report.search_time_per_rule {
+            stats_for(&mut rules, rule).search_secs += secs(time);
+        }
+        for (rule, time) in &report.apply_time_per_rule {
+            stats_for(&mut rules, rule).apply_secs += secs(time);
+        }
+        self.rules = rules
+            .into_iter()
+            .map(|(rule, stats)| RuleStats { rule, ..stats })
+            .collect();
+        self.rules.sort_by(|a, b| {
+            (b.search_secs + b.apply_secs).total_cmp(&(a.search_secs + a.apply_secs))
+        });
+
+        let mut rulesets = std::mem::take(&mut self.rulesets)
+            .into_iter()
+            .map(|stats| (stats.ruleset.clone(), stats))
+            .collect::<BTreeMap<_, _>>();
+        for (ruleset, time) in &report.search_time_per_ruleset {
+            stats_for(&mut rulesets, ruleset).search_secs += secs(time);
+        }
+        for (ruleset, time) in &report.apply_time_per_ruleset {
+            stats_for(&mut rulesets, ruleset).apply_secs += secs(time);
+        }
+        for (ruleset, time) in &report.rebuild_time_per_ruleset {
+            stats_for(&mut rulesets, ruleset).rebuild_secs += secs(time);
+        }
+        self.rulesets = rulesets
+            .into_iter()
+            .map(|(ruleset, stats)| RulesetStats { ruleset, ..stats })
+            .collect();
+        let total =
+            |stats: &RulesetStats| stats.search_secs + stats.apply_secs + stats.rebuild_secs;
+        self.rulesets.sort_by(|a, b| total(b).total_cmp(&total(a)));
+    }
+}
+
+/// Number of e-nodes and e-classes in `egraph`,
+/// counted the same way as the serialized egraph used for extraction.
+fn egraph_size(egraph: &egglog::EGraph) -> (usize, usize) {
+    let serialized = egraph.serialize(SerializeConfig::default());
+    (serialized.nodes.len(), serialized.classes().len())
+}
+
+/// The stats for `name`, starting from zero if there are none yet.
+fn stats_for<'a, T: Default>(stats: &'a mut BTreeMap<String, T>, name: impl ToString) -> &'a mut T {
+    stats.entry(name.to_string()).or_default()
+}
+
+#[test]
+fn test_pass_stats_counts() {
+    let mut egraph = egglog::EGraph::default();
+    egraph
+        .parse_and_run_program(
+            None,
+            "
+(datatype Math (Num i64) (Add Math Math))
+(ruleset commute)
+(rule ((= e (Add a b))) ((union e (Add b a))) :ruleset commute)
+(let x (Add (Num 1) (Num 2)))",
+        )
+        .unwrap();
+
+    let mut stats = PassStats::before_schedule(&egraph);
+    egraph
+        .parse_and_run_program(None, "(run commute 1)")
+        .unwrap();
+    stats.after_schedule(&egraph, Duration::from_secs(1));
+
+    // the commuted `Add` is a new e-node in the same e-class
+    assert_eq!(stats.tuples_after, stats.tuples_before + 1);
+    assert_eq!(stats.enodes_after, stats.enodes_before + 1);
+    assert_eq!(stats.eclasses_after, stats.eclasses_before);
+    assert_eq!(stats.schedule_secs, 1.0);
+
+    assert_eq!(stats.rules.len(), 1);
+    assert_eq!(stats.rules[0].matches, 1);
+    assert_eq!(stats.rules[0].applications, 1);
+    assert!(stats
+        .rulesets
+        .iter()
+        .any(|ruleset| ruleset.ruleset == "commute"));
+
+    // a second run adds to the counts of the first
+    egraph
+        .parse_and_run_program(None, "(run commute 1)")
+        .unwrap();
+    stats.after_schedule(&egraph, Duration::from_secs(1));
+    assert_eq!(stats.rules.len(), 1);
+    assert!(stats.rules[0].matches > 1);
+}
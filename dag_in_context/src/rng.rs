@@ -0,0 +1,46 @@
+//! A small random number generator for the fuzzers (see `rule_fuzzer`
+//! and the Bril fuzzer in eggcc), so that the same seed always
+//! generates the same program.
+
+/// SplitMix64.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// A small integer, so that interesting comparisons happen often.
+    pub fn int(&mut self) -> i64 {
+        self.below(17) as i64 - 8
+    }
+}
+
+#[test]
+fn test_same_seed_same_numbers() {
+    let numbers = |seed| {
+        let mut rng = Rng::new(seed);
+        (0..10).map(|_| rng.next_u64()).collect::<Vec<_>>()
+    };
+    assert_eq!(numbers(1), numbers(1));
+    assert_ne!(numbers(1), numbers(2));
+
+    let mut rng = Rng::new(0);
+    for _ in 0..100 {
+        assert!(rng.below(3) < 3);
+        assert!((-8..=8).contains(&rng.int()));
+    }
+}
@@ -0,0 +1,884 @@
+//! Differential testing of the rewrite rules.
+//! Random well-typed programs are put in an egraph and one ruleset is run on them.
+//! For each function, the best program is extracted along with alternatives that use
+//! the other enodes of its eclasses (see `extract_eclass_alternatives`).
+//! Each of them is interpreted and has to produce the same value and print log as the original.
+//! Failing programs are shrunk before being reported.
+//!
+//! Only the eclasses of the best program are explored, and at most
+//! `FuzzConfig::alternatives` alternatives are checked for each function.
+//!
+//! The generated programs have a `main` function that takes two integers and the state,
+//! and a pure `helper` function of two integers that `main` can call.
+//! They use integer and boolean operations that can't panic, `If`s, loops with a
+//! bounded number of iterations, `print`s, and an array that `main` allocates,
+//! writes, reads and frees, so the original always terminates without errors.
+//! There are no loops over the state or floats, so rules about those aren't exercised.
+
+use std::{fmt::Display, panic::AssertUnwindSafe};
+
+use egglog::TermDag;
+
+use crate::{
+    ast::*,
+    build_program_commands, egraph_with_prologue,
+    greedy_dag_extractor::{extract_eclass_alternatives, serialized_egraph, DefaultCostModel},
+    interpreter::{interpret_dag_prog, Value},
+    rng::Rng,
+    schedule::{helpers, optimizations},
+    schema::{RcExpr, TreeProgram},
+    typechecker::TypeError,
+};
+
+pub struct FuzzConfig {
+    /// Program `i` is generated from `seed + i`.
+    pub seed: u64,
+    /// Number of random programs to check.
+    pub programs: usize,
+    /// Maximum nesting depth of the generated expressions.
+    pub max_depth: usize,
+    /// Number of random inputs each program is run on.
+    pub inputs: usize,
+    /// Maximum number of alternatives extracted for each function, besides the best one.
+    pub alternatives: usize,
+    pub rulesets: Vec<String>,
+}
+
+impl Default for FuzzConfig {
+    fn default() -> Self {
+        FuzzConfig {
+            seed: 0,
+            programs: 10,
+            max_depth: 4,
+            inputs: 3,
+            alternatives: 16,
+            rulesets: fuzzed_rulesets(),
+        }
+    }
+}
+
+/// The optimization rulesets, along with the rulesets
+/// that the schedules run on their own in the first passes.
+pub fn fuzzed_rulesets() -> Vec<String> {
+    [
+        "passthrough",
+        "state-edge-passthrough",
+        "swap-if",
+        "rec-to-loop",
+        "loop-inversion",
+    ]
+    .iter()
+    .map(|ruleset| ruleset.to_string())
+    .chain(optimizations())
+    .collect()
+}
+
+/// A (shrunk) program that `ruleset` optimizes incorrectly.
+#[derive(Debug)]
+pub struct Counterexample {
+    /// The seed the program was generated from, before shrinking.
+    pub seed: u64,
+    pub ruleset: String,
+    pub input: Value,
+    pub program: TreeProgram,
+    /// The extracted program that behaved differently,
+    /// or `None` if running egglog or extraction failed.
+    pub alternative: Option<TreeProgram>,
+    pub problem: String,
+}
+
+impl Display for Counterexample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Ruleset {} is unsound on the program from seed {} (shrunk), with input {}: {}",
+            self.ruleset, self.seed, self.input, self.problem
+        )?;
+        writeln!(f, "Program:\n{}", self.program.pretty_print_to_rust())?;
+        if let Some(alternative) = &self.alternative {
+            writeln!(f, "Alternative:\n{}", alternative.pretty_print_to_rust())?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks every ruleset in `config` on `config.programs` random programs,
+/// returning the first counterexample found.
+pub fn fuzz_rulesets(config: &FuzzConfig) -> Result<(), Box<Counterexample>> {
+    for i in 0..config.programs {
+        let seed = config.seed.wrapping_add(i as u64);
+        let mut rng = Rng::new(seed);
+        let program = FuzzProgram::random(&mut rng, config.max_depth);
+        if let Err(err) = program.to_program() {
+            panic!("Generated an ill-typed program from seed {seed}: {err}");
+        }
+        let inputs = (0..config.inputs)
+            .map(|_| tuplev_vec(vec![intv(rng.int()), intv(rng.int()), statev()]))
+            .collect::<Vec<_>>();
+
+        for ruleset in &config.rulesets {
+            log::info!("Fuzzing ruleset {} on seed {}", ruleset, seed);
+            let check = |program: &FuzzProgram| {
+                check_ruleset(program, ruleset, &inputs, config.alternatives)
+            };
+            if check(&program).is_none() {
+                continue;
+            }
+            let shrunk = shrink(&program, |candidate| check(candidate).is_some());
+            let failure = check(&shrunk).expect("shrinking only keeps failing programs");
+            return Err(Box::new(Counterexample {
+                seed,
+                ruleset: ruleset.clone(),
+                input: failure.input,
+                program: shrunk.to_program(),
+                alternative: failure.alternative,
+                problem: failure.problem,
+            }));
+        }
+    }
+    Ok(())
+}
+
+struct Failure {
+    input: Value,
+    alternative: Option<TreeProgram>,
+    problem: String,
+}
+
+/// Runs `ruleset` on `program` and compares the extracted alternatives against it.
+fn check_ruleset(
+    program: &FuzzProgram,
+    ruleset: &str,
+    inputs: &[Value],
+    alternatives: usize,
+) -> Option<Failure> {
+    let failure = |input: &Value, alternative: Option<TreeProgram>, problem: String| {
+        Some(Failure {
+            input: input.clone(),
+            alternative,
+            problem,
+        })
+    };
+    // `fuzz_rulesets` checks that generated programs typecheck, and shrinking keeps them well-typed
+    let tree = program
+        .to_program()
+        .expect("shrinking keeps programs well-typed");
+    let fns = tree.fns();
+    let helpers = helpers();
+    let schedule = format!("(run-schedule {helpers} (saturate ivt-analysis) {ruleset} {helpers})");
+
+    let mut egraph = egraph_with_prologue(None).expect("failed to load the prologue");
+    if let Err(err) = egraph
+        .run_program(build_program_commands(&tree, None, &fns))
+        .and_then(|_| egraph.parse_and_run_program(None, &schedule))
+    {
+        return failure(&inputs[0], None, format!("egglog failed: {err}"));
+    }
+    let (serialized, unextractables) = serialized_egraph(egraph);
+    let extracted = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        fns.iter()
+            .map(|func| {
+                extract_eclass_alternatives(
+                    &tree,
+                    func,
+                    &serialized,
+                    unextractables.clone(),
+                    &mut TermDag::default(),
+                    DefaultCostModel,
+                    true,
+                    alternatives,
+                )
+                .map(|candidates| (func.clone(), candidates))
+            })
+            .collect::<Option<Vec<_>>>()
+    }));
+    let extracted = match extracted {
+        Ok(Some(extracted)) => extracted,
+        Ok(None) => return failure(&inputs[0], None, "extraction failed".to_string()),
+        Err(_) => return failure(&inputs[0], None, "extraction panicked".to_string()),
+    };
+
+    for (func, (best, func_alternatives)) in extracted {
+        for candidate in std::iter::once(best).chain(func_alternatives) {
+            let mut alternative = tree.clone();
+            alternative.replace_fn(&func, candidate);
+            for input in inputs {
+                // any behavior is allowed when the original program traps
+                let Ok(expected) = interpret_dag_prog(&tree, input) else {
+                    continue;
+                };
+                let actual = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    interpret_dag_prog(&alternative, input)
+                }));
+                let problem = match actual {
+                    Ok(Ok(actual)) if actual == expected => continue,
+                    Ok(Ok((value, log))) => format!(
+                        "expected {} with log {:?}, got {} with log {:?}",
+                        expected.0, expected.1, value, log
+                    ),
+                    Ok(Err(err)) => format!("the alternative fails at runtime: {err}"),
+                    Err(_) => "interpreting the alternative panicked".to_string(),
+                };
+                return failure(input, Some(alternative), problem);
+            }
+        }
+    }
+    None
+}
+
+/// Repeatedly replaces `program` with the first of its shrinks that still fails,
+/// until none of them do.
+fn shrink(program: &FuzzProgram, mut fails: impl FnMut(&FuzzProgram) -> bool) -> FuzzProgram {
+    let mut program = program.clone();
+    'shrink: loop {
+        for candidate in program.shrinks() {
+            if fails(&candidate) {
+                program = candidate;
+                continue 'shrink;
+            }
+        }
+        return program;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IntOp {
+    Add,
+    Sub,
+    Mul,
+    Smax,
+    Smin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CmpOp {
+    LessThan,
+    LessEq,
+    Eq,
+    GreaterThan,
+}
+
+/// An integer expression over the integer arguments in scope.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum IntTerm {
+    Const(i64),
+    Arg(usize),
+    Bin(IntOp, Box<IntTerm>, Box<IntTerm>),
+    Select(Box<BoolTerm>, Box<IntTerm>, Box<IntTerm>),
+    /// The branches only see `inputs` as their arguments.
+    If {
+        cond: Box<BoolTerm>,
+        inputs: Vec<IntTerm>,
+        then_case: Box<IntTerm>,
+        else_case: Box<IntTerm>,
+    },
+    /// Runs `body` `iters` times on a counter (argument 0) and an accumulator
+    /// (argument 1) starting at `init`, evaluating to the final accumulator.
+    Loop {
+        init: Box<IntTerm>,
+        iters: i64,
+        body: Box<IntTerm>,
+    },
+    /// A call to `helper`, which only `main` makes.
+    Call(Box<IntTerm>, Box<IntTerm>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum BoolTerm {
+    Const(bool),
+    Cmp(CmpOp, Box<IntTerm>, Box<IntTerm>),
+    And(Box<BoolTerm>, Box<BoolTerm>),
+    Or(Box<BoolTerm>, Box<BoolTerm>),
+    Not(Box<BoolTerm>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum MemoryOp {
+    Write(usize, IntTerm),
+    Read(usize),
+}
+
+/// An array that `main` allocates, writes, reads and frees.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FuzzMemory {
+    /// The value first written to each element, so that every read is initialized.
+    init: Vec<IntTerm>,
+    /// The operations after the first writes, with the values read added to the result.
+    ops: Vec<MemoryOp>,
+}
+
+/// The function `main`, which uses `memory`, prints each of `prints` in order
+/// and returns `result` plus the values read from memory.
+/// All of them see the two integer arguments, and can call `helper`,
+/// a function of two integers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FuzzProgram {
+    helper: IntTerm,
+    result: IntTerm,
+    prints: Vec<IntTerm>,
+    memory: Option<FuzzMemory>,
+}
+
+impl IntTerm {
+    /// A random term over `arity` arguments, which only calls `helper` when `calls` is set.
+    fn random(rng: &mut Rng, depth: usize, arity: usize, calls: bool) -> IntTerm {
+        if depth == 0 || rng.below(4) == 0 {
+            return if arity > 0 && rng.below(2) == 0 {
+                IntTerm::Arg(rng.below(arity))
+            } else {
+                IntTerm::Const(rng.int())
+            };
+        }
+        let sub =
+            |rng: &mut Rng, arity: usize| Box::new(IntTerm::random(rng, depth - 1, arity, calls));
+        match rng.below(9) {
+            0..=3 => {
+                let op =
+                    [IntOp::Add, IntOp::Sub, IntOp::Mul, IntOp::Smax, IntOp::Smin][rng.below(5)];
+                IntTerm::Bin(op, sub(rng, arity), sub(rng, arity))
+            }
+            4 => IntTerm::Select(
+                Box::new(BoolTerm::random(rng, depth - 1, arity, calls)),
+                sub(rng, arity),
+                sub(rng, arity),
+            ),
+            5 | 6 => {
+                let cond = Box::new(BoolTerm::random(rng, depth - 1, arity, calls));
+                let inputs = (0..1 + rng.below(2))
+                    .map(|_| *sub(rng, arity))
+                    .collect::<Vec<_>>();
+                let then_case = sub(rng, inputs.len());
+                let else_case = sub(rng, inputs.len());
+                IntTerm::If {
+                    cond,
+                    inputs,
+                    then_case,
+                    else_case,
+                }
+            }
+            8 if calls => IntTerm::Call(sub(rng, arity), sub(rng, arity)),
+            _ => IntTerm::Loop {
+                init: sub(rng, arity),
+                iters: 1 + rng.below(4) as i64,
+                body: sub(rng, 2),
+            },
+        }
+    }
+
+    fn to_expr(&self) -> RcExpr {
+        match self {
+            IntTerm::Const(n) => int(*n),
+            IntTerm::Arg(i) => getat(*i),
+            IntTerm::Bin(op, a, b) => {
+                let (a, b) = (a.to_expr(), b.to_expr());
+                match op {
+                    IntOp::Add => add(a, b),
+                    IntOp::Sub => sub(a, b),
+                    IntOp::Mul => mul(a, b),
+                    IntOp::Smax => smax(a, b),
+                    IntOp::Smin => smin(a, b),
+                }
+            }
+            IntTerm::Select(cond, a, b) => select(cond.to_expr(), a.to_expr(), b.to_expr()),
+            IntTerm::If {
+                cond,
+                inputs,
+                then_case,
+                else_case,
+            } => get(
+                tif(
+                    cond.to_expr(),
+                    parallel_vec(inputs.iter().map(IntTerm::to_expr).collect::<Vec<_>>()),
+                    single(then_case.to_expr()),
+                    single(else_case.to_expr()),
+                ),
+                0,
+            ),
+            IntTerm::Loop { init, iters, body } => {
+                let counter = add(getat(0), int(1));
+                get(
+                    dowhile(
+                        parallel_vec([int(0), init.to_expr()]),
+                        parallel_vec([
+                            less_than(counter.clone(), int(*iters)),
+                            counter,
+                            body.to_expr(),
+                        ]),
+                    ),
+                    1,
+                )
+            }
+            IntTerm::Call(a, b) => call("helper", parallel_vec([a.to_expr(), b.to_expr()])),
+        }
+    }
+
+    /// Simpler terms to try in place of this one, over the same arguments.
+    fn shrinks(&self) -> Vec<IntTerm> {
+        let mut shrinks = vec![];
+        if *self != IntTerm::Const(0) {
+            shrinks.push(IntTerm::Const(0));
+        }
+        match self {
+            IntTerm::Const(n) => {
+                if n / 2 != 0 {
+                    shrinks.push(IntTerm::Const(n / 2));
+                }
+            }
+            IntTerm::Arg(_) => {}
+            IntTerm::Bin(op, a, b) => {
+                shrinks.push(*a.clone());
+                shrinks.push(*b.clone());
+                shrinks.extend(
+                    a.shrinks()
+                        .into_iter()
+                        .map(|a| IntTerm::Bin(*op, Box::new(a), b.clone())),
+                );
+                shrinks.extend(
+                    b.shrinks()
+                        .into_iter()
+                        .map(|b| IntTerm::Bin(*op, a.clone(), Box::new(b))),
+                );
+            }
+            IntTerm::Select(cond, a, b) => {
+                shrinks.push(*a.clone());
+                shrinks.push(*b.clone());
+                shrinks.extend(
+                    cond.shrinks()
+                        .into_iter()
+                        .map(|cond| IntTerm::Select(Box::new(cond), a.clone(), b.clone())),
+                );
+                shrinks.extend(
+                    a.shrinks()
+                        .into_iter()
+                        .map(|a| IntTerm::Select(cond.clone(), Box::new(a), b.clone())),
+                );
+                shrinks.extend(
+                    b.shrinks()
+                        .into_iter()
+                        .map(|b| IntTerm::Select(cond.clone(), a.clone(), Box::new(b))),
+                );
+            }
+            IntTerm::If {
+                cond,
+                inputs,
+                then_case,
+                else_case,
+            } => {
+                // the branches are over different arguments, so only the inputs can replace the `If`
+                shrinks.extend(inputs.iter().cloned());
+                let with =
+                    |cond: &BoolTerm, inputs: Vec<IntTerm>, then_case, else_case| IntTerm::If {
+                        cond: Box::new(cond.clone()),
+                        inputs,
+                        then_case: Box::new(then_case),
+                        else_case: Box::new(else_case),
+                    };
+                for shrunk in cond.shrinks() {
+                    shrinks.push(with(
+                        &shrunk,
+                        inputs.clone(),
+                        *then_case.clone(),
+                        *else_case.clone(),
+                    ));
+                }
+                for (i, input) in inputs.iter().enumerate() {
+                    for shrunk in input.shrinks() {
+                        let mut inputs = inputs.clone();
+                        inputs[i] = shrunk;
+                        shrinks.push(with(cond, inputs, *then_case.clone(), *else_case.clone()));
+                    }
+                }
+                for shrunk in then_case.shrinks() {
+                    shrinks.push(with(cond, inputs.clone(), shrunk, *else_case.clone()));
+                }
+                for shrunk in else_case.shrinks() {
+                    shrinks.push(with(cond, inputs.clone(), *then_case.clone(), shrunk));
+                }
+            }
+            IntTerm::Loop { init, iters, body } => {
+                shrinks.push(*init.clone());
+                if *iters > 1 {
+                    shrinks.push(IntTerm::Loop {
+                        init: init.clone(),
+                        iters: iters - 1,
+                        body: body.clone(),
+                    });
+                }
+                shrinks.extend(init.shrinks().into_iter().map(|init| IntTerm::Loop {
+                    init: Box::new(init),
+                    iters: *iters,
+                    body: body.clone(),
+                }));
+                shrinks.extend(body.shrinks().into_iter().map(|body| IntTerm::Loop {
+                    init: init.clone(),
+                    iters: *iters,
+                    body: Box::new(body),
+                }));
+            }
+            IntTerm::Call(a, b) => {
+                shrinks.push(*a.clone());
+                shrinks.push(*b.clone());
+                shrinks.extend(
+                    a.shrinks()
+                        .into_iter()
+                        .map(|a| IntTerm::Call(Box::new(a), b.clone())),
+                );
+                shrinks.extend(
+                    b.shrinks()
+                        .into_iter()
+                        .map(|b| IntTerm::Call(a.clone(), Box::new(b))),
+                );
+            }
+        }
+        shrinks
+    }
+}
+
+impl BoolTerm {
+    fn random(rng: &mut Rng, depth: usize, arity: usize, calls: bool) -> BoolTerm {
+        if depth == 0 {
+            return BoolTerm::Const(rng.below(2) == 0);
+        }
+        let sub = |rng: &mut Rng| Box::new(BoolTerm::random(rng, depth - 1, arity, calls));
+        match rng.below(6) {
+            0..=2 => {
+                let op = [
+                    CmpOp::LessThan,
+                    CmpOp::LessEq,
+                    CmpOp::Eq,
+                    CmpOp::GreaterThan,
+                ][rng.below(4)];
+                BoolTerm::Cmp(
+                    op,
+                    Box::new(IntTerm::random(rng, depth - 1, arity, calls)),
+                    Box::new(IntTerm::random(rng, depth - 1, arity, calls)),
+                )
+            }
+            3 => BoolTerm::And(sub(rng), sub(rng)),
+            4 => BoolTerm::Or(sub(rng), sub(rng)),
+            _ => BoolTerm::Not(sub(rng)),
+        }
+    }
+
+    fn to_expr(&self) -> RcExpr {
+        match self {
+            BoolTerm::Const(true) => ttrue(),
+            BoolTerm::Const(false) => tfalse(),
+            BoolTerm::Cmp(op, a, b) => {
+                let (a, b) = (a.to_expr(), b.to_expr());
+                match op {
+                    CmpOp::LessThan => less_than(a, b),
+                    CmpOp::LessEq => less_eq(a, b),
+                    CmpOp::Eq => eq(a, b),
+                    CmpOp::GreaterThan => greater_than(a, b),
+                }
+            }
+            BoolTerm::And(a, b) => and(a.to_expr(), b.to_expr()),
+            BoolTerm::Or(a, b) => or(a.to_expr(), b.to_expr()),
+            BoolTerm::Not(a) => not(a.to_expr()),
+        }
+    }
+
+    /// Simpler terms to try in place of this one.
+    /// Constants only shrink towards `false`, so that shrinking terminates.
+    fn shrinks(&self) -> Vec<BoolTerm> {
+        match self {
+            BoolTerm::Const(false) => vec![],
+            BoolTerm::Const(true) => vec![BoolTerm::Const(false)],
+            _ => {
+                let mut shrinks = vec![BoolTerm::Const(false), BoolTerm::Const(true)];
+                match self {
+                    BoolTerm::Const(_) => unreachable!(),
+                    BoolTerm::Cmp(op, a, b) => {
+                        shrinks.extend(
+                            a.shrinks()
+                                .into_iter()
+                                .map(|a| BoolTerm::Cmp(*op, Box::new(a), b.clone())),
+                        );
+                        shrinks.extend(
+                            b.shrinks()
+                                .into_iter()
+                                .map(|b| BoolTerm::Cmp(*op, a.clone(), Box::new(b))),
+                        );
+                    }
+                    BoolTerm::And(a, b) | BoolTerm::Or(a, b) => {
+                        let rebuild = |a, b| match self {
+                            BoolTerm::And(..) => BoolTerm::And(Box::new(a), Box::new(b)),
+                            _ => BoolTerm::Or(Box::new(a), Box::new(b)),
+                        };
+                        shrinks.push(*a.clone());
+                        shrinks.push(*b.clone());
+                        for shrunk in a.shrinks() {
+                            shrinks.push(rebuild(shrunk, *b.clone()));
+                        }
+                        for shrunk in b.shrinks() {
+                            shrinks.push(rebuild(*a.clone(), shrunk));
+                        }
+                    }
+                    BoolTerm::Not(a) => {
+                        shrinks.push(*a.clone());
+                        shrinks.extend(a.shrinks().into_iter().map(|a| BoolTerm::Not(Box::new(a))));
+                    }
+                }
+                shrinks
+            }
+        }
+    }
+}
+
+impl FuzzMemory {
+    fn random(rng: &mut Rng, depth: usize) -> FuzzMemory {
+        let len = 1 + rng.below(3);
+        let init = (0..len)
+            .map(|_| IntTerm::random(rng, depth, 2, true))
+            .collect();
+        let ops = (0..1 + rng.below(4))
+            .map(|_| {
+                if rng.below(2) == 0 {
+                    MemoryOp::Write(rng.below(len), IntTerm::random(rng, depth, 2, true))
+                } else {
+                    MemoryOp::Read(rng.below(len))
+                }
+            })
+            .collect();
+        FuzzMemory { init, ops }
+    }
+
+    /// The array doesn't change size, so that the indices stay in bounds.
+    fn shrinks(&self) -> Vec<FuzzMemory> {
+        let mut shrinks = vec![];
+        for i in 0..self.ops.len() {
+            let mut ops = self.ops.clone();
+            ops.remove(i);
+            shrinks.push(FuzzMemory {
+                init: self.init.clone(),
+                ops,
+            });
+        }
+        for (i, value) in self.init.iter().enumerate() {
+            for shrunk in value.shrinks() {
+                let mut init = self.init.clone();
+                init[i] = shrunk;
+                shrinks.push(FuzzMemory {
+                    init,
+                    ops: self.ops.clone(),
+                });
+            }
+        }
+        for (i, op) in self.ops.iter().enumerate() {
+            let MemoryOp::Write(index, value) = op else {
+                continue;
+            };
+            for shrunk in value.shrinks() {
+                let mut ops = self.ops.clone();
+                ops[i] = MemoryOp::Write(*index, shrunk);
+                shrinks.push(FuzzMemory {
+                    init: self.init.clone(),
+                    ops,
+                });
+            }
+        }
+        shrinks
+    }
+}
+
+impl FuzzProgram {
+    fn random(rng: &mut Rng, max_depth: usize) -> FuzzProgram {
+        let helper = IntTerm::random(rng, max_depth, 2, false);
+        let result = IntTerm::random(rng, max_depth, 2, true);
+        let prints = (0..rng.below(3))
+            .map(|_| IntTerm::random(rng, max_depth.saturating_sub(1), 2, true))
+            .collect();
+        let memory =
+            (rng.below(2) == 0).then(|| FuzzMemory::random(rng, max_depth.saturating_sub(1)));
+        FuzzProgram {
+            helper,
+            result,
+            prints,
+            memory,
+        }
+    }
+
+    /// The program with `main` as its entry, typechecked.
+    fn to_program(&self) -> Result<TreeProgram, TypeError> {
+        let mut state = getat(2);
+        let mut result = self.result.to_expr();
+        if let Some(memory) = &self.memory {
+            let len = memory.init.len() as i64;
+            let ptr_and_state = alloc(0, int(len), state, pointert(intt()));
+            let ptr = get(ptr_and_state.clone(), 0);
+            state = get(ptr_and_state, 1);
+            let element = |index: usize| ptradd(ptr.clone(), int(index as i64));
+            let init = memory
+                .init
+                .iter()
+                .enumerate()
+                .map(|(index, value)| MemoryOp::Write(index, value.clone()));
+            for op in init.chain(memory.ops.iter().cloned()) {
+                match op {
+                    MemoryOp::Write(index, value) => {
+                        state = write(element(index), value.to_expr(), state);
+                    }
+                    MemoryOp::Read(index) => {
+                        let value_and_state = load(element(index), state);
+                        result = add(result, get(value_and_state.clone(), 0));
+                        state = get(value_and_state, 1);
+                    }
+                }
+            }
+            state = free(ptr, state);
+        }
+        let state = self
+            .prints
+            .iter()
+            .fold(state, |state, print| tprint(print.to_expr(), state));
+        let main = function(
+            "main",
+            tuplet_vec(vec![intt(), intt(), statet()]),
+            tuplet_vec(vec![intt(), statet()]),
+            parallel_vec([result, state]),
+        );
+        let helper = function(
+            "helper",
+            tuplet_vec(vec![intt(), intt()]),
+            base(intt()),
+            self.helper.to_expr(),
+        );
+        TreeProgram {
+            entry: main,
+            functions: vec![helper],
+        }
+        .try_with_arg_types()
+    }
+
+    /// Removing memory and prints comes first, since it makes the other checks cheaper.
+    fn shrinks(&self) -> Vec<FuzzProgram> {
+        let mut shrinks = vec![];
+        if self.memory.is_some() {
+            shrinks.push(FuzzProgram {
+                memory: None,
+                ..self.clone()
+            });
+        }
+        for i in 0..self.prints.len() {
+            let mut prints = self.prints.clone();
+            prints.remove(i);
+            shrinks.push(FuzzProgram {
+                prints,
+                ..self.clone()
+            });
+        }
+        shrinks.extend(self.result.shrinks().into_iter().map(|result| FuzzProgram {
+            result,
+            ..self.clone()
+        }));
+        shrinks.extend(self.helper.shrinks().into_iter().map(|helper| FuzzProgram {
+            helper,
+            ..self.clone()
+        }));
+        for (i, print) in self.prints.iter().enumerate() {
+            for shrunk in print.shrinks() {
+                let mut prints = self.prints.clone();
+                prints[i] = shrunk;
+                shrinks.push(FuzzProgram {
+                    prints,
+                    ..self.clone()
+                });
+            }
+        }
+        if let Some(memory) = &self.memory {
+            shrinks.extend(memory.shrinks().into_iter().map(|memory| FuzzProgram {
+                memory: Some(memory),
+                ..self.clone()
+            }));
+        }
+        shrinks
+    }
+}
+
+#[test]
+fn test_random_programs_interpret() {
+    let mut uses_memory = false;
+    let mut uses_calls = false;
+    for seed in 0..100 {
+        let program = FuzzProgram::random(&mut Rng::new(seed), 4);
+        uses_memory |= program.memory.is_some();
+        uses_calls |= format!("{:?}", program.result).contains("Call");
+        let program = program
+            .to_program()
+            .unwrap_or_else(|err| panic!("Program from seed {seed} is ill-typed: {err}"));
+        let input = tuplev_vec(vec![intv(3), intv(-2), statev()]);
+        let (value, _log) = interpret_dag_prog(&program, &input)
+            .unwrap_or_else(|err| panic!("Program from seed {seed} failed: {err}"));
+        assert!(matches!(value, Value::Tuple(values) if values.len() == 2));
+    }
+    assert!(uses_memory && uses_calls);
+}
+
+#[test]
+fn test_shrink() {
+    // stands in for a miscompilation of multiplying by the second argument
+    fn multiplies_by_second_arg(term: &IntTerm) -> bool {
+        match term {
+            IntTerm::Bin(IntOp::Mul, _, b) if **b == IntTerm::Arg(1) => true,
+            IntTerm::Bin(_, a, b) => multiplies_by_second_arg(a) || multiplies_by_second_arg(b),
+            IntTerm::Loop { init, .. } => multiplies_by_second_arg(init),
+            _ => false,
+        }
+    }
+
+    let program = FuzzProgram {
+        helper: IntTerm::Arg(0),
+        result: IntTerm::Bin(
+            IntOp::Add,
+            Box::new(IntTerm::Bin(
+                IntOp::Mul,
+                Box::new(IntTerm::Arg(0)),
+                Box::new(IntTerm::Arg(1)),
+            )),
+            Box::new(IntTerm::Loop {
+                init: Box::new(IntTerm::Const(7)),
+                iters: 3,
+                body: Box::new(IntTerm::Arg(1)),
+            }),
+        ),
+        prints: vec![IntTerm::Const(3)],
+        memory: Some(FuzzMemory {
+            init: vec![IntTerm::Const(1)],
+            ops: vec![MemoryOp::Read(0)],
+        }),
+    };
+    let shrunk = shrink(&program, |program| {
+        multiplies_by_second_arg(&program.result)
+    });
+    assert_eq!(
+        shrunk,
+        FuzzProgram {
+            helper: IntTerm::Const(0),
+            result: IntTerm::Bin(
+                IntOp::Mul,
+                Box::new(IntTerm::Const(0)),
+                Box::new(IntTerm::Arg(1))
+            ),
+            prints: vec![],
+            memory: None,
+        }
+    );
+}
+
+#[test]
+fn test_fuzz_rulesets() {
+    // set EGGCC_FUZZ_PROGRAMS to fuzz for longer
+    let programs = std::env::var("EGGCC_FUZZ_PROGRAMS")
+        .ok()
+        .and_then(|programs| programs.parse().ok())
+        .unwrap_or(1);
+    let config = FuzzConfig {
+        programs,
+        max_depth: 3,
+        ..Default::default()
+    };
+    if let Err(counterexample) = fuzz_rulesets(&config) {
+        panic!("{counterexample}");
+    }
+}
@@ -1,4 +1,10 @@
-#[derive(Debug)]
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::{extra_rules::ExtraRules, prologue_with_extra_rules};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum CompilerPass {
     // Run the given egglog schedule, then extract
     Schedule(String),
@@ -82,7 +88,7 @@ fn cheap_optimizations() -> Vec<String> {
     .collect()
 }
 
-fn optimizations() -> Vec<String> {
+pub(crate) fn optimizations() -> Vec<String> {
     [
         "select_opt",
         "loop-unroll",
@@ -99,7 +105,16 @@ fn optimizations() -> Vec<String> {
 }
 
 pub fn rulesets() -> String {
-    let all_optimizations = optimizations().join("\n");
+    rulesets_with_extra(&[])
+}
+
+/// Like `rulesets`, but with `extra_rulesets` added to `all-optimizations`.
+pub fn rulesets_with_extra(extra_rulesets: &[String]) -> String {
+    let all_optimizations = optimizations()
+        .into_iter()
+        .chain(extra_rulesets.iter().cloned())
+        .collect::<Vec<_>>()
+        .join("\n");
     let cheap_optimizations = cheap_optimizations().join("\n");
     format!(
         "
@@ -212,3 +227,223 @@ pub fn parallel_schedule() -> Vec<CompilerPass> {
         )),
     ]
 }
+
+#[derive(Debug, Error)]
+pub enum ScheduleFileError {
+    #[error("Could not read schedule file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Syntax error in schedule file: {0}")]
+    Syntax(String),
+    #[error("Schedule file is not valid with the eggcc prologue: {0}")]
+    Invalid(egglog::Error),
+}
+
+/// Reads a list of compiler passes from a file, see `parse_schedule`.
+/// The passes are checked against the prologue (with `extra_rules`) before being returned.
+pub fn load_schedule_file(
+    path: &Path,
+    extra_rules: Option<&ExtraRules>,
+) -> Result<Vec<CompilerPass>, ScheduleFileError> {
+    let contents = std::fs::read_to_string(path)?;
+    let passes = parse_schedule(&contents)?;
+    validate_schedule(&passes, extra_rules)?;
+    Ok(passes)
+}
+
+/// Parses a list of compiler passes, one per top-level form.
+/// A `(run-schedule ...)` form is a `CompilerPass::Schedule`,
+/// and an `(inline-with-schedule (run-schedule ...))` form is a `CompilerPass::InlineWithSchedule`.
+/// Schedules can use the `cheap-optimizations` and `all-optimizations` rulesets,
+/// and the bare symbol `helpers` is replaced by the schedule from `helpers()`.
+pub fn parse_schedule(contents: &str) -> Result<Vec<CompilerPass>, ScheduleFileError> {
+    let helpers = helpers();
+    top_level_forms(contents)?
+        .into_iter()
+        .map(|form| match form_head(form) {
+            "run-schedule" => Ok(CompilerPass::Schedule(expand_helpers(form, &helpers))),
+            "inline-with-schedule" => {
+                let body = &form[1..form.len() - 1];
+                let body = body.trim_start()["inline-with-schedule".len()..].trim();
+                match top_level_forms(body)?.as_slice() {
+                    [inner] if form_head(inner) == "run-schedule" => Ok(
+                        CompilerPass::InlineWithSchedule(expand_helpers(inner, &helpers)),
+                    ),
+                    _ => Err(ScheduleFileError::Syntax(format!(
+                        "expected a single (run-schedule ...) inside {form}"
+                    ))),
+                }
+            }
+            head => Err(ScheduleFileError::Syntax(format!(
+                "expected (run-schedule ...) or (inline-with-schedule (run-schedule ...)), found ({head} ...)"
+            ))),
+        })
+        .collect()
+}
+
+/// Checks that every pass only refers to rulesets defined by the prologue
+/// (with `extra_rules`) by running the passes on an empty program.
+pub fn validate_schedule(
+    passes: &[CompilerPass],
+    extra_rules: Option<&ExtraRules>,
+) -> Result<(), ScheduleFileError> {
+    let schedules = passes
+        .iter()
+        .map(|pass| pass.egglog_schedule())
+        .collect::<Vec<_>>()
+        .join("\n");
+    egglog::EGraph::default()
+        .parse_and_run_program(
+            None,
+            &format!("{}\n{schedules}", prologue_with_extra_rules(extra_rules)),
+        )
+        .map_err(ScheduleFileError::Invalid)?;
+    Ok(())
+}
+
+/// Splits egglog source into its top-level s-expressions, skipping comments.
+fn top_level_forms(contents: &str) -> Result<Vec<&str>, ScheduleFileError> {
+    let mut forms = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut chars = contents.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ';' => {
+                // skip to the end of the line
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+            }
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '(' => {
+                if depth == 0 {
+                    start = i;
+                }
+                depth += 1;
+            }
+            ')' => {
+                if depth == 0 {
+                    return Err(ScheduleFileError::Syntax(format!(
+                        "unexpected ')' at byte {i}"
+                    )));
+                }
+                depth -= 1;
+                if depth == 0 {
+                    forms.push(&contents[start..=i]);
+                }
+            }
+            c if depth == 0 && !c.is_whitespace() => {
+                return Err(ScheduleFileError::Syntax(format!(
+                    "unexpected '{c}' at byte {i}, expected a pass"
+                )));
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(ScheduleFileError::Syntax(
+            "unbalanced parentheses at end of file".to_string(),
+        ));
+    }
+    Ok(forms)
+}
+
+/// The symbol at the start of a form like `(run-schedule ...)`.
+fn form_head(form: &str) -> &str {
+    let inner = form[1..].trim_start();
+    let end = inner
+        .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .unwrap_or(inner.len());
+    &inner[..end]
+}
+
+/// Replaces each bare `helpers` symbol in `form` with the helpers schedule.
+fn expand_helpers(form: &str, helpers: &str) -> String {
+    let mut res = String::new();
+    let mut chars = form.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => {
+                res.push(c);
+                while let Some(c) = chars.next_if(|c| *c != '\n') {
+                    res.push(c);
+                }
+            }
+            '"' => {
+                res.push(c);
+                while let Some(c) = chars.next() {
+                    res.push(c);
+                    match c {
+                        '\\' => res.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            c if c.is_whitespace() || c == '(' || c == ')' => res.push(c),
+            c => {
+                let mut symbol = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| !(c.is_whitespace() || matches!(*c, '(' | ')' | ';' | '"')))
+                {
+                    symbol.push(c);
+                }
+                if symbol == "helpers" {
+                    res.push_str(helpers);
+                } else {
+                    res.push_str(&symbol);
+                }
+            }
+        }
+    }
+    res
+}
+
+#[test]
+fn test_parse_schedule() {
+    let passes = parse_schedule(
+        "
+;; a plain pass
+(run-schedule
+    (saturate helpers passthrough)
+    helpers)
+
+;; a pass that also inlines
+(inline-with-schedule
+    (run-schedule
+        (repeat 2 helpers all-optimizations)
+        (repeat 2 helpers cheap-optimizations)
+        helpers))
+",
+    )
+    .unwrap();
+    assert_eq!(passes.len(), 2);
+    assert!(matches!(&passes[0], CompilerPass::Schedule(s) if s.contains("subsume-after-helpers")));
+    assert!(matches!(
+        &passes[1],
+        CompilerPass::InlineWithSchedule(s) if s.trim_start().starts_with("(run-schedule")
+    ));
+    validate_schedule(&passes, None).unwrap();
+
+    assert!(matches!(
+        parse_schedule("(run-schedule helpers"),
+        Err(ScheduleFileError::Syntax(_))
+    ));
+    assert!(matches!(
+        parse_schedule("(run helpers)"),
+        Err(ScheduleFileError::Syntax(_))
+    ));
+    let unknown = parse_schedule("(run-schedule not-a-ruleset)").unwrap();
+    assert!(matches!(
+        validate_schedule(&unknown, None),
+        Err(ScheduleFileError::Invalid(_))
+    ));
+}
@@ -1,13 +1,15 @@
 //! This module mirrors `schema.egg`.
 //! No implementation or conversion should
 //! be implemented in this file.
-//! Also see schema.egg for documentation
+//! Also see schema.egg for documentation.
+//! The serde encodings of `Expr`, `Assumption` and `TreeProgram` are in `tree_json.rs`.
 
 use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
 use std::rc::Rc;
 use strum_macros::{Display, EnumIter};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Display)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Display, Serialize, Deserialize)]
 pub enum BaseType {
     IntT,
     FloatT,
@@ -16,7 +18,7 @@ pub enum BaseType {
     StateT,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Type {
     Base(BaseType),
     /// Nested tuple types are not allowed.
@@ -31,13 +33,13 @@ pub enum Type {
     Symbolic(String),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, EnumIter, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum TernaryOp {
     Write,
     Select,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, EnumIter, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum BinaryOp {
     Add,
     Sub,
@@ -71,13 +73,13 @@ pub enum BinaryOp {
     Free,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, EnumIter, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum UnaryOp {
     Abs,
     Not,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum Constant {
     Int(i64),
     Bool(bool),
@@ -0,0 +1,714 @@
+//! Structural diffs between two versions of a tree program, usually before and after optimization.
+//!
+//! Sub-expressions of the two versions are aligned by value numbering:
+//! two sub-expressions get the same number when they compute the same thing,
+//! regardless of their contexts or where in the program they are.
+//! To make this work across regions, the argument of an `If` or `Switch` branch is numbered
+//! as the branch's input, and so is a loop argument that the loop passes through unchanged.
+//! Other loop-carried values are numbered by their initial value.
+//! Effects are numbered without the state edge they consume, so reordering one effect
+//! doesn't change every effect after it.
+//!
+//! Each operation (everything but arguments, constants and tuple plumbing) is then
+//! unchanged, added, removed, or moved when it appears in a different set of regions,
+//! for example when it is hoisted out of a loop or pushed into a branch.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Write},
+    rc::Rc,
+};
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{
+    dag2svg::tree_to_svg_with_colors,
+    schema::{BaseType, Constant, Expr, RcExpr, TreeProgram, Type},
+    typechecker::TypeCache,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Moved,
+}
+
+/// An operation that changed between the two versions.
+/// Added and removed operations are only listed when they aren't
+/// part of a larger added or removed operation.
+#[derive(Debug, Clone)]
+pub struct DiffEntry {
+    pub kind: DiffKind,
+    /// The operation, from the version it appears in (the new one when it was moved).
+    pub expr: RcExpr,
+    /// The regions the operation appears in before and after, like `main > loop#0`.
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionDiff {
+    pub name: String,
+    pub entries: Vec<DiffEntry>,
+    pub unchanged: usize,
+}
+
+/// The diff of two versions of a program, made by `tree_diff`.
+pub struct TreeDiff {
+    pub functions: Vec<FunctionDiff>,
+    pub added_functions: Vec<String>,
+    pub removed_functions: Vec<String>,
+    /// The typed versions of the two programs, which the colors refer to.
+    before: TreeProgram,
+    after: TreeProgram,
+    before_colors: HashMap<*const Expr, String>,
+    after_colors: HashMap<*const Expr, String>,
+}
+
+const ADDED_COLOR: &str = "darkgreen";
+const REMOVED_COLOR: &str = "red";
+const MOVED_COLOR: &str = "blue";
+
+/// Diffs the functions that appear in both programs, see the module documentation.
+pub fn tree_diff(before: &TreeProgram, after: &TreeProgram) -> TreeDiff {
+    let (before, before_types) = before.with_arg_types_and_cache();
+    let (after, after_types) = after.with_arg_types_and_cache();
+    let before_fns = before.fns();
+    let after_fns = after.fns();
+
+    let mut numbering = Numbering::default();
+    let mut diff = TreeDiff {
+        functions: vec![],
+        added_functions: after_fns
+            .iter()
+            .filter(|name| !before_fns.contains(name))
+            .cloned()
+            .collect(),
+        removed_functions: before_fns
+            .iter()
+            .filter(|name| !after_fns.contains(name))
+            .cloned()
+            .collect(),
+        before_colors: HashMap::new(),
+        after_colors: HashMap::new(),
+        before: before.clone(),
+        after: after.clone(),
+    };
+
+    for name in before_fns.iter().filter(|name| after_fns.contains(name)) {
+        let old = function_ops(
+            &mut numbering,
+            &before_types,
+            before.get_function(name).unwrap(),
+        );
+        let new = function_ops(
+            &mut numbering,
+            &after_types,
+            after.get_function(name).unwrap(),
+        );
+
+        let removed: HashSet<usize> = old
+            .keys()
+            .filter(|id| !new.contains_key(*id))
+            .copied()
+            .collect();
+        let added: HashSet<usize> = new
+            .keys()
+            .filter(|id| !old.contains_key(*id))
+            .copied()
+            .collect();
+        let old_parents = parents(&old);
+        let new_parents = parents(&new);
+
+        let mut function_diff = FunctionDiff {
+            name: name.clone(),
+            entries: vec![],
+            unchanged: 0,
+        };
+        for (id, op) in &old {
+            let Some(new_op) = new.get(id) else {
+                diff.before_colors.extend(op.color(REMOVED_COLOR));
+                if is_changed_root(*id, &old_parents, &removed) {
+                    function_diff
+                        .entries
+                        .push(op.entry(DiffKind::Removed, Some(op), None));
+                }
+                continue;
+            };
+            if op.regions == new_op.regions {
+                function_diff.unchanged += 1;
+            } else {
+                diff.before_colors.extend(op.color(MOVED_COLOR));
+                diff.after_colors.extend(new_op.color(MOVED_COLOR));
+                function_diff
+                    .entries
+                    .push(new_op.entry(DiffKind::Moved, Some(op), Some(new_op)));
+            }
+        }
+        for (id, op) in new.iter().filter(|(id, _)| added.contains(*id)) {
+            diff.after_colors.extend(op.color(ADDED_COLOR));
+            if is_changed_root(*id, &new_parents, &added) {
+                function_diff
+                    .entries
+                    .push(op.entry(DiffKind::Added, None, Some(op)));
+            }
+        }
+        diff.functions.push(function_diff);
+    }
+    diff
+}
+
+impl TreeDiff {
+    /// The program before, with removed operations in red and moved ones in blue.
+    pub fn before_svg(&self) -> String {
+        tree_to_svg_with_colors(&self.before, &self.before_colors)
+    }
+
+    /// The program after, with added operations in green and moved ones in blue.
+    pub fn after_svg(&self) -> String {
+        tree_to_svg_with_colors(&self.after, &self.after_colors)
+    }
+}
+
+impl Display for TreeDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for name in &self.removed_functions {
+            writeln!(f, "removed function {name}")?;
+        }
+        for name in &self.added_functions {
+            writeln!(f, "added function {name}")?;
+        }
+        for function in &self.functions {
+            let count = |kind| {
+                function
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.kind == kind)
+                    .count()
+            };
+            writeln!(
+                f,
+                "function {}: {} added, {} removed, {} moved, {} unchanged",
+                function.name,
+                count(DiffKind::Added),
+                count(DiffKind::Removed),
+                count(DiffKind::Moved),
+                function.unchanged
+            )?;
+            for entry in &function.entries {
+                let expr = summary(&entry.expr, 3);
+                match entry.kind {
+                    DiffKind::Added => writeln!(f, "  + {expr} in {}", entry.after.join(", "))?,
+                    DiffKind::Removed => writeln!(f, "  - {expr} in {}", entry.before.join(", "))?,
+                    DiffKind::Moved => writeln!(
+                        f,
+                        "  ~ {expr} moved from {} to {}",
+                        entry.before.join(", "),
+                        entry.after.join(", ")
+                    )?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A short s-expression for `expr`, eliding children deeper than `depth`
+/// and the bodies of regions.
+fn summary(expr: &RcExpr, depth: usize) -> String {
+    match expr.as_ref() {
+        Expr::Arg(..) => return "arg".to_string(),
+        Expr::Empty(..) => return "empty".to_string(),
+        Expr::Const(constant, ..) => {
+            return match constant {
+                Constant::Int(i) => i.to_string(),
+                Constant::Bool(b) => b.to_string(),
+                Constant::Float(f) => f.to_string(),
+            }
+        }
+        Expr::Symbolic(name, _) => return name.clone(),
+        _ => {}
+    }
+    if depth == 0 {
+        return "...".to_string();
+    }
+    let mut res = match expr.as_ref() {
+        Expr::Bop(op, ..) => format!("({}", op.name()),
+        Expr::Uop(op, ..) => format!("({}", op.name()),
+        Expr::Top(op, ..) => format!("({}", op.name()),
+        Expr::Call(name, _) => format!("(Call {name}"),
+        _ => format!("({}", expr.constructor().name()),
+    };
+    for child in expr.children_same_scope() {
+        write!(res, " {}", summary(&child, depth - 1)).unwrap();
+    }
+    match expr.as_ref() {
+        Expr::Get(_, index) => write!(res, " {index}").unwrap(),
+        Expr::If(..) | Expr::Switch(..) | Expr::DoWhile(..) => res.push_str(" ..."),
+        _ => {}
+    }
+    res.push(')');
+    res
+}
+
+/// An operation of one version of a function, with every place it appears.
+struct OpInfo {
+    expr: RcExpr,
+    exprs: Vec<RcExpr>,
+    regions: IndexSet<String>,
+    /// The numbers of the closest operations below this one.
+    children: IndexSet<usize>,
+}
+
+impl OpInfo {
+    fn color(&self, color: &str) -> impl Iterator<Item = (*const Expr, String)> + '_ {
+        let color = color.to_string();
+        self.exprs
+            .iter()
+            .map(move |expr| (Rc::as_ptr(expr), color.clone()))
+    }
+
+    fn entry(&self, kind: DiffKind, before: Option<&OpInfo>, after: Option<&OpInfo>) -> DiffEntry {
+        let regions = |op: Option<&OpInfo>| {
+            op.map(|op| op.regions.iter().cloned().collect())
+                .unwrap_or_default()
+        };
+        DiffEntry {
+            kind,
+            expr: self.expr.clone(),
+            before: regions(before),
+            after: regions(after),
+        }
+    }
+}
+
+fn parents(ops: &IndexMap<usize, OpInfo>) -> HashMap<usize, Vec<usize>> {
+    let mut res = HashMap::<usize, Vec<usize>>::new();
+    for (id, op) in ops {
+        for child in &op.children {
+            res.entry(*child).or_default().push(*id);
+        }
+    }
+    res
+}
+
+/// Whether a changed operation is used by something that didn't change the same way,
+/// so it isn't already covered by a larger change.
+fn is_changed_root(
+    id: usize,
+    parents: &HashMap<usize, Vec<usize>>,
+    changed: &HashSet<usize>,
+) -> bool {
+    match parents.get(&id) {
+        Some(parents) => parents.iter().any(|parent| !changed.contains(parent)),
+        None => true,
+    }
+}
+
+/// Value numbers shared by both versions of the program.
+/// Each number stands for a label applied to the numbers of its children.
+#[derive(Default)]
+struct Numbering(HashMap<(String, Vec<usize>), usize>);
+
+impl Numbering {
+    fn intern(&mut self, label: impl Into<String>, children: Vec<usize>) -> usize {
+        let next = self.0.len();
+        *self.0.entry((label.into(), children)).or_insert(next)
+    }
+}
+
+struct Region {
+    index: usize,
+    path: String,
+    kind: RegionKind,
+}
+
+enum RegionKind {
+    Function,
+    Branch {
+        input: RcExpr,
+        parent: Rc<Region>,
+    },
+    Loop {
+        input: RcExpr,
+        body: RcExpr,
+        parent: Rc<Region>,
+    },
+}
+
+/// A part of a tuple built from `Single`, `Concat` and `Empty`.
+enum TuplePart {
+    Element(RcExpr),
+    /// All the elements of another tuple.
+    Spread(RcExpr),
+}
+
+fn tuple_parts(expr: &RcExpr) -> Vec<TuplePart> {
+    match expr.as_ref() {
+        Expr::Empty(..) => vec![],
+        Expr::Single(element) => vec![TuplePart::Element(element.clone())],
+        Expr::Concat(left, right) => {
+            let mut parts = tuple_parts(left);
+            parts.extend(tuple_parts(right));
+            parts
+        }
+        _ => vec![TuplePart::Spread(expr.clone())],
+    }
+}
+
+/// Whether a loop body passes its `index`th argument through unchanged.
+fn passes_through(body: &RcExpr, index: usize) -> bool {
+    // the first output is the predicate
+    for (i, part) in tuple_parts(body).into_iter().enumerate() {
+        let TuplePart::Element(element) = part else {
+            return false;
+        };
+        if i == index + 1 {
+            return matches!(element.as_ref(),
+                Expr::Get(tuple, j) if *j == index && matches!(tuple.as_ref(), Expr::Arg(..)));
+        }
+    }
+    false
+}
+
+fn function_ops(
+    numbering: &mut Numbering,
+    types: &TypeCache,
+    func: &RcExpr,
+) -> IndexMap<usize, OpInfo> {
+    let Expr::Function(name, _, _, body) = func.as_ref() else {
+        panic!("Expected function, got {:?}", func);
+    };
+    let mut numberer = Numberer {
+        numbering,
+        types,
+        memo: HashMap::new(),
+        regions: 0,
+        nested: HashMap::new(),
+        ops: IndexMap::new(),
+    };
+    let region = numberer.new_region(name.clone(), RegionKind::Function);
+    numberer.number(body, &region);
+    numberer.ops
+}
+
+struct Numberer<'a> {
+    numbering: &'a mut Numbering,
+    types: &'a TypeCache,
+    /// The number of each expression in each region,
+    /// along with the closest operations at or below it.
+    memo: HashMap<(*const Expr, usize), (usize, Rc<Vec<usize>>)>,
+    regions: usize,
+    /// How many regions have been found in each region, for naming them.
+    nested: HashMap<usize, usize>,
+    ops: IndexMap<usize, OpInfo>,
+}
+
+impl Numberer<'_> {
+    fn new_region(&mut self, path: String, kind: RegionKind) -> Rc<Region> {
+        self.regions += 1;
+        Rc::new(Region {
+            index: self.regions,
+            path,
+            kind,
+        })
+    }
+
+    fn nested_region(&mut self, parent: &Rc<Region>, name: &str, kind: RegionKind) -> Rc<Region> {
+        let path = format!("{} > {name}", parent.path);
+        self.new_region(path, kind)
+    }
+
+    fn next_nested(&mut self, region: &Region) -> usize {
+        let count = self.nested.entry(region.index).or_default();
+        *count += 1;
+        *count - 1
+    }
+
+    fn number(&mut self, expr: &RcExpr, region: &Rc<Region>) -> usize {
+        self.number_with_ops(expr, region).0
+    }
+
+    /// Numbers a child of an operation, adding its closest operations to `ops`.
+    /// State edges all get the same number.
+    fn child(&mut self, expr: &RcExpr, region: &Rc<Region>, ops: &mut Vec<usize>) -> usize {
+        let (id, child_ops) = self.number_with_ops(expr, region);
+        ops.extend(child_ops.iter());
+        match self.types.get(&Rc::as_ptr(expr)) {
+            Some(Type::Base(BaseType::StateT)) => self.numbering.intern("State", vec![]),
+            _ => id,
+        }
+    }
+
+    fn children(
+        &mut self,
+        exprs: &[&RcExpr],
+        region: &Rc<Region>,
+        ops: &mut Vec<usize>,
+    ) -> Vec<usize> {
+        exprs
+            .iter()
+            .map(|expr| self.child(expr, region, ops))
+            .collect()
+    }
+
+    fn tuple_len(&self, expr: &RcExpr) -> Option<usize> {
+        match self.types.get(&Rc::as_ptr(expr)) {
+            Some(Type::TupleT(types)) => Some(types.len()),
+            _ => None,
+        }
+    }
+
+    fn number_with_ops(&mut self, expr: &RcExpr, region: &Rc<Region>) -> (usize, Rc<Vec<usize>>) {
+        let key = (Rc::as_ptr(expr), region.index);
+        if let Some(res) = self.memo.get(&key) {
+            return res.clone();
+        }
+
+        let mut ops = vec![];
+        let (id, is_op) = match expr.as_ref() {
+            Expr::Arg(..) => (self.region_arg(region), false),
+            Expr::Get(tuple, index) => (self.number_get(tuple, *index, region, &mut ops), false),
+            Expr::Const(constant, ..) => {
+                (self.numbering.intern(constant.to_string(), vec![]), false)
+            }
+            Expr::Symbolic(name, _) => (self.numbering.intern(name.clone(), vec![]), false),
+            Expr::Empty(..) | Expr::Single(..) | Expr::Concat(..) => {
+                let mut ids = vec![];
+                for part in tuple_parts(expr) {
+                    match part {
+                        TuplePart::Element(element) => {
+                            ids.push(self.child(&element, region, &mut ops))
+                        }
+                        TuplePart::Spread(tuple) => {
+                            let id = self.child(&tuple, region, &mut ops);
+                            ids.push(self.numbering.intern("Spread", vec![id]));
+                        }
+                    }
+                }
+                (self.numbering.intern("Tuple", ids), false)
+            }
+            Expr::Bop(op, left, right) => {
+                let ids = self.children(&[left, right], region, &mut ops);
+                (self.numbering.intern(op.name(), ids), true)
+            }
+            Expr::Uop(op, inner) => {
+                let ids = self.children(&[inner], region, &mut ops);
+                (self.numbering.intern(op.name(), ids), true)
+            }
+            Expr::Top(op, x, y, z) => {
+                let ids = self.children(&[x, y, z], region, &mut ops);
+                (self.numbering.intern(op.name(), ids), true)
+            }
+            Expr::Call(name, arg) => {
+                let ids = self.children(&[arg], region, &mut ops);
+                (self.numbering.intern(format!("Call {name}"), ids), true)
+            }
+            Expr::Alloc(_id, amount, state, ty) => {
+                let ids = self.children(&[amount, state], region, &mut ops);
+                let label = format!("Alloc {}", Type::Base(ty.clone()));
+                (self.numbering.intern(label, ids), true)
+            }
+            Expr::If(pred, input, then_branch, else_branch) => {
+                let mut ids = self.children(&[pred, input], region, &mut ops);
+                let k = self.next_nested(region);
+                for (branch, name) in [(then_branch, "then"), (else_branch, "else")] {
+                    let kind = RegionKind::Branch {
+                        input: input.clone(),
+                        parent: region.clone(),
+                    };
+                    let branch_region = self.nested_region(region, &format!("if#{k}.{name}"), kind);
+                    ids.push(self.child(branch, &branch_region, &mut ops));
+                }
+                (self.numbering.intern("If", ids), true)
+            }
+            Expr::Switch(pred, input, branches) => {
+                let mut ids = self.children(&[pred, input], region, &mut ops);
+                let k = self.next_nested(region);
+                for (i, branch) in branches.iter().enumerate() {
+                    let kind = RegionKind::Branch {
+                        input: input.clone(),
+                        parent: region.clone(),
+                    };
+                    let branch_region =
+                        self.nested_region(region, &format!("switch#{k}.{i}"), kind);
+                    ids.push(self.child(branch, &branch_region, &mut ops));
+                }
+                (self.numbering.intern("Switch", ids), true)
+            }
+            Expr::DoWhile(input, body) => {
+                let mut ids = self.children(&[input], region, &mut ops);
+                let k = self.next_nested(region);
+                let kind = RegionKind::Loop {
+                    input: input.clone(),
+                    body: body.clone(),
+                    parent: region.clone(),
+                };
+                let body_region = self.nested_region(region, &format!("loop#{k}"), kind);
+                ids.push(self.child(body, &body_region, &mut ops));
+                (self.numbering.intern("DoWhile", ids), true)
+            }
+            Expr::Function(..) => panic!("Expected expression, got function"),
+        };
+
+        let ops = if is_op {
+            let op = self.ops.entry(id).or_insert_with(|| OpInfo {
+                expr: expr.clone(),
+                exprs: vec![],
+                regions: IndexSet::new(),
+                children: IndexSet::new(),
+            });
+            op.exprs.push(expr.clone());
+            op.regions.insert(region.path.clone());
+            op.children.extend(ops);
+            vec![id]
+        } else {
+            ops
+        };
+        let res = (id, Rc::new(ops));
+        self.memo.insert(key, res.clone());
+        res
+    }
+
+    /// Numbers the `index`th element of `tuple`, looking through tuple plumbing
+    /// and region arguments.
+    fn number_get(
+        &mut self,
+        tuple: &RcExpr,
+        index: usize,
+        region: &Rc<Region>,
+        ops: &mut Vec<usize>,
+    ) -> usize {
+        let mut offset = index;
+        for part in tuple_parts(tuple) {
+            match part {
+                TuplePart::Element(element) => {
+                    if offset == 0 {
+                        return self.child(&element, region, ops);
+                    }
+                    offset -= 1;
+                }
+                TuplePart::Spread(inner) => {
+                    let Some(len) = self.tuple_len(&inner) else {
+                        break;
+                    };
+                    if offset < len {
+                        if matches!(inner.as_ref(), Expr::Arg(..)) {
+                            return self.region_arg_element(region, offset);
+                        }
+                        let id = self.child(&inner, region, ops);
+                        return self.numbering.intern(format!("Get {offset}"), vec![id]);
+                    }
+                    offset -= len;
+                }
+            }
+        }
+        let id = self.child(tuple, region, ops);
+        self.numbering.intern(format!("Get {index}"), vec![id])
+    }
+
+    fn region_arg(&mut self, region: &Rc<Region>) -> usize {
+        match &region.kind {
+            RegionKind::Function => self.numbering.intern("Arg", vec![]),
+            RegionKind::Branch { input, parent } => self.number(input, parent),
+            RegionKind::Loop { input, parent, .. } => {
+                let input = self.number(input, parent);
+                self.numbering.intern("LoopArg", vec![input])
+            }
+        }
+    }
+
+    fn region_arg_element(&mut self, region: &Rc<Region>, index: usize) -> usize {
+        match &region.kind {
+            RegionKind::Function => {
+                let arg = self.numbering.intern("Arg", vec![]);
+                self.numbering.intern(format!("Get {index}"), vec![arg])
+            }
+            // operations in the parent region aren't operations of this one,
+            // so they are left out
+            RegionKind::Branch { input, parent } => {
+                self.number_get(input, index, parent, &mut vec![])
+            }
+            RegionKind::Loop {
+                input,
+                body,
+                parent,
+            } => {
+                let initial = self.number_get(input, index, parent, &mut vec![]);
+                if passes_through(body, index) {
+                    initial
+                } else {
+                    self.numbering.intern("LoopVar", vec![initial])
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tree_diff_hoisted_from_loop() {
+    use crate::ast::*;
+
+    // x * 2 is computed in the loop before, and hoisted out of it after
+    let before = program!(function(
+        "main",
+        base(intt()),
+        base(intt()),
+        get(
+            dowhile(
+                parallel!(arg(), int(0)),
+                parallel!(
+                    less_than(getat(1), int(10)),
+                    getat(0),
+                    add(getat(1), mul(getat(0), int(2)))
+                )
+            ),
+            1
+        )
+    ),);
+    let after = program!(function(
+        "main",
+        base(intt()),
+        base(intt()),
+        get(
+            dowhile(
+                parallel!(arg(), int(0), mul(arg(), int(2))),
+                parallel!(
+                    less_than(getat(1), int(10)),
+                    getat(0),
+                    add(getat(1), getat(2)),
+                    getat(2)
+                )
+            ),
+            1
+        )
+    ),);
+
+    let diff = tree_diff(&before, &after);
+    let [function] = &diff.functions[..] else {
+        panic!("expected one function");
+    };
+    // the comparison and the addition are unchanged
+    assert_eq!(function.unchanged, 2);
+    let moved: Vec<_> = function
+        .entries
+        .iter()
+        .filter(|entry| entry.kind == DiffKind::Moved)
+        .collect();
+    let [moved] = &moved[..] else {
+        panic!("expected one moved operation, got {:?}", function.entries);
+    };
+    assert_eq!(summary(&moved.expr, 3), "(Mul arg 2)");
+    assert_eq!(moved.before, vec!["main > loop#0"]);
+    assert_eq!(moved.after, vec!["main"]);
+    // the loop itself has new inputs
+    assert!(function
+        .entries
+        .iter()
+        .any(|entry| entry.kind == DiffKind::Added
+            && matches!(entry.expr.as_ref(), Expr::DoWhile(..))));
+
+    let text = diff.to_string();
+    assert!(text.contains("~ (Mul arg 2) moved from main > loop#0 to main"));
+}
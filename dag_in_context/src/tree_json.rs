@@ -0,0 +1,396 @@
+//! Serde support for `TreeProgram`, `Expr` and `Assumption`, for tools that
+//! analyze tree programs outside of eggcc.
+//! Types, constants and operators derive their encodings in `schema.rs`.
+//!
+//! Expressions are stored in a table, children before their parents,
+//! and refer to their children by index in the table.
+//! Each `RcExpr` is stored once, so sharing survives a round trip:
+//! ```text
+//! {
+//!   "version": 1,
+//!   "exprs": [
+//!     {"Arg": [{"TupleT": ["IntT", "StateT"]}, {"InFunc": "main"}]},
+//!     {"Get": [0, 0]},
+//!     {"Bop": ["Add", 1, 1]},
+//!     ...
+//!   ],
+//!   "entry": 7,
+//!   "functions": []
+//! }
+//! ```
+//! A lone `Expr` stores its index as `root`, and a lone `Assumption` is stored
+//! inline as `assumption`, referring to the table like the assumptions inside expressions.
+
+use std::rc::Rc;
+
+use indexmap::IndexMap;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::schema::{
+    Assumption, BaseType, BinaryOp, Constant, Expr, RcExpr, TernaryOp, TreeProgram, Type, UnaryOp,
+};
+
+/// Bump this when the encoding changes.
+pub const TREE_JSON_VERSION: u32 = 1;
+
+/// An index into the expression table.
+type ExprId = usize;
+
+#[derive(Serialize, Deserialize)]
+enum ExprNode {
+    Const(Constant, Type, AssumptionNode),
+    Top(TernaryOp, ExprId, ExprId, ExprId),
+    Bop(BinaryOp, ExprId, ExprId),
+    Uop(UnaryOp, ExprId),
+    Get(ExprId, usize),
+    Alloc(i64, ExprId, ExprId, BaseType),
+    Call(String, ExprId),
+    Empty(Type, AssumptionNode),
+    Single(ExprId),
+    Concat(ExprId, ExprId),
+    If(ExprId, ExprId, ExprId, ExprId),
+    Switch(ExprId, ExprId, Vec<ExprId>),
+    DoWhile(ExprId, ExprId),
+    Arg(Type, AssumptionNode),
+    Function(String, Type, Type, ExprId),
+    Symbolic(String, Option<Type>),
+}
+
+#[derive(Serialize, Deserialize)]
+enum AssumptionNode {
+    InLoop(ExprId, ExprId),
+    InFunc(String),
+    InIf(bool, ExprId, ExprId),
+    InSwitch(i64, ExprId, ExprId),
+    WildCard(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProgramJson {
+    version: u32,
+    exprs: Vec<ExprNode>,
+    entry: ExprId,
+    functions: Vec<ExprId>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExprJson {
+    version: u32,
+    exprs: Vec<ExprNode>,
+    root: ExprId,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AssumptionJson {
+    version: u32,
+    exprs: Vec<ExprNode>,
+    assumption: AssumptionNode,
+}
+
+#[derive(Debug, Error)]
+enum TreeJsonError {
+    #[error("unsupported tree JSON version {0}, expected {TREE_JSON_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("expression {node} refers to expression {child}, which does not come before it")]
+    BadReference { node: ExprId, child: ExprId },
+    #[error("there is no expression {0}")]
+    MissingRoot(ExprId),
+}
+
+/// Builds the expression table, storing each `RcExpr` once.
+#[derive(Default)]
+struct Encoder {
+    exprs: Vec<ExprNode>,
+    ids: IndexMap<*const Expr, ExprId>,
+}
+
+impl Encoder {
+    fn expr(&mut self, expr: &RcExpr) -> ExprId {
+        if let Some(id) = self.ids.get(&Rc::as_ptr(expr)) {
+            return *id;
+        }
+        let node = self.node(expr);
+        let id = self.push(node);
+        self.ids.insert(Rc::as_ptr(expr), id);
+        id
+    }
+
+    fn push(&mut self, node: ExprNode) -> ExprId {
+        self.exprs.push(node);
+        self.exprs.len() - 1
+    }
+
+    fn node(&mut self, expr: &Expr) -> ExprNode {
+        match expr {
+            Expr::Const(c, ty, assum) => {
+                ExprNode::Const(c.clone(), ty.clone(), self.assumption(assum))
+            }
+            Expr::Top(op, x, y, z) => {
+                ExprNode::Top(op.clone(), self.expr(x), self.expr(y), self.expr(z))
+            }
+            Expr::Bop(op, x, y) => ExprNode::Bop(op.clone(), self.expr(x), self.expr(y)),
+            Expr::Uop(op, x) => ExprNode::Uop(op.clone(), self.expr(x)),
+            Expr::Get(x, index) => ExprNode::Get(self.expr(x), *index),
+            Expr::Alloc(id, size, state, ty) => {
+                ExprNode::Alloc(*id, self.expr(size), self.expr(state), ty.clone())
+            }
+            Expr::Call(name, arg) => ExprNode::Call(name.clone(), self.expr(arg)),
+            Expr::Empty(ty, assum) => ExprNode::Empty(ty.clone(), self.assumption(assum)),
+            Expr::Single(x) => ExprNode::Single(self.expr(x)),
+            Expr::Concat(x, y) => ExprNode::Concat(self.expr(x), self.expr(y)),
+            Expr::If(pred, input, then, els) => ExprNode::If(
+                self.expr(pred),
+                self.expr(input),
+                self.expr(then),
+                self.expr(els),
+            ),
+            Expr::Switch(pred, input, branches) => ExprNode::Switch(
+                self.expr(pred),
+                self.expr(input),
+                branches.iter().map(|branch| self.expr(branch)).collect(),
+            ),
+            Expr::DoWhile(input, body) => ExprNode::DoWhile(self.expr(input), self.expr(body)),
+            Expr::Arg(ty, assum) => ExprNode::Arg(ty.clone(), self.assumption(assum)),
+            Expr::Function(name, ty_in, ty_out, body) => {
+                ExprNode::Function(name.clone(), ty_in.clone(), ty_out.clone(), self.expr(body))
+            }
+            Expr::Symbolic(name, ty) => ExprNode::Symbolic(name.clone(), ty.clone()),
+        }
+    }
+
+    fn assumption(&mut self, assum: &Assumption) -> AssumptionNode {
+        match assum {
+            Assumption::InLoop(input, body) => {
+                AssumptionNode::InLoop(self.expr(input), self.expr(body))
+            }
+            Assumption::InFunc(name) => AssumptionNode::InFunc(name.clone()),
+            Assumption::InIf(branch, pred, input) => {
+                AssumptionNode::InIf(*branch, self.expr(pred), self.expr(input))
+            }
+            Assumption::InSwitch(branch, pred, input) => {
+                AssumptionNode::InSwitch(*branch, self.expr(pred), self.expr(input))
+            }
+            Assumption::WildCard(name) => AssumptionNode::WildCard(name.clone()),
+        }
+    }
+}
+
+/// Rebuilds the expressions of a table in order.
+struct Decoder {
+    exprs: Vec<RcExpr>,
+}
+
+impl Decoder {
+    fn new(version: u32, nodes: Vec<ExprNode>) -> Result<Decoder, TreeJsonError> {
+        if version != TREE_JSON_VERSION {
+            return Err(TreeJsonError::UnsupportedVersion(version));
+        }
+        let mut decoder = Decoder { exprs: vec![] };
+        for node in nodes {
+            let expr = decoder.node(node)?;
+            decoder.exprs.push(Rc::new(expr));
+        }
+        Ok(decoder)
+    }
+
+    /// A root of the table, such as the entry function.
+    fn root(&self, id: ExprId) -> Result<RcExpr, TreeJsonError> {
+        self.exprs
+            .get(id)
+            .cloned()
+            .ok_or(TreeJsonError::MissingRoot(id))
+    }
+
+    /// The expression with the given id, which must already be decoded.
+    fn expr(&self, id: ExprId) -> Result<RcExpr, TreeJsonError> {
+        self.exprs
+            .get(id)
+            .cloned()
+            .ok_or(TreeJsonError::BadReference {
+                node: self.exprs.len(),
+                child: id,
+            })
+    }
+
+    fn node(&self, node: ExprNode) -> Result<Expr, TreeJsonError> {
+        Ok(match node {
+            ExprNode::Const(c, ty, assum) => Expr::Const(c, ty, self.assumption(assum)?),
+            ExprNode::Top(op, x, y, z) => {
+                Expr::Top(op, self.expr(x)?, self.expr(y)?, self.expr(z)?)
+            }
+            ExprNode::Bop(op, x, y) => Expr::Bop(op, self.expr(x)?, self.expr(y)?),
+            ExprNode::Uop(op, x) => Expr::Uop(op, self.expr(x)?),
+            ExprNode::Get(x, index) => Expr::Get(self.expr(x)?, index),
+            ExprNode::Alloc(id, size, state, ty) => {
+                Expr::Alloc(id, self.expr(size)?, self.expr(state)?, ty)
+            }
+            ExprNode::Call(name, arg) => Expr::Call(name, self.expr(arg)?),
+            ExprNode::Empty(ty, assum) => Expr::Empty(ty, self.assumption(assum)?),
+            ExprNode::Single(x) => Expr::Single(self.expr(x)?),
+            ExprNode::Concat(x, y) => Expr::Concat(self.expr(x)?, self.expr(y)?),
+            ExprNode::If(pred, input, then, els) => Expr::If(
+                self.expr(pred)?,
+                self.expr(input)?,
+                self.expr(then)?,
+                self.expr(els)?,
+            ),
+            ExprNode::Switch(pred, input, branches) => Expr::Switch(
+                self.expr(pred)?,
+                self.expr(input)?,
+                branches
+                    .into_iter()
+                    .map(|branch| self.expr(branch))
+                    .collect::<Result<_, _>>()?,
+            ),
+            ExprNode::DoWhile(input, body) => Expr::DoWhile(self.expr(input)?, self.expr(body)?),
+            ExprNode::Arg(ty, assum) => Expr::Arg(ty, self.assumption(assum)?),
+            ExprNode::Function(name, ty_in, ty_out, body) => {
+                Expr::Function(name, ty_in, ty_out, self.expr(body)?)
+            }
+            ExprNode::Symbolic(name, ty) => Expr::Symbolic(name, ty),
+        })
+    }
+
+    fn assumption(&self, assum: AssumptionNode) -> Result<Assumption, TreeJsonError> {
+        Ok(match assum {
+            AssumptionNode::InLoop(input, body) => {
+                Assumption::InLoop(self.expr(input)?, self.expr(body)?)
+            }
+            AssumptionNode::InFunc(name) => Assumption::InFunc(name),
+            AssumptionNode::InIf(branch, pred, input) => {
+                Assumption::InIf(branch, self.expr(pred)?, self.expr(input)?)
+            }
+            AssumptionNode::InSwitch(branch, pred, input) => {
+                Assumption::InSwitch(branch, self.expr(pred)?, self.expr(input)?)
+            }
+            AssumptionNode::WildCard(name) => Assumption::WildCard(name),
+        })
+    }
+}
+
+impl Serialize for TreeProgram {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut encoder = Encoder::default();
+        let entry = encoder.expr(&self.entry);
+        let functions = self
+            .functions
+            .iter()
+            .map(|func| encoder.expr(func))
+            .collect();
+        ProgramJson {
+            version: TREE_JSON_VERSION,
+            exprs: encoder.exprs,
+            entry,
+            functions,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TreeProgram {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let json = ProgramJson::deserialize(deserializer)?;
+        let decoder = Decoder::new(json.version, json.exprs).map_err(de::Error::custom)?;
+        let entry = decoder.root(json.entry).map_err(de::Error::custom)?;
+        let functions = json
+            .functions
+            .into_iter()
+            .map(|func| decoder.root(func))
+            .collect::<Result<_, _>>()
+            .map_err(de::Error::custom)?;
+        Ok(TreeProgram { entry, functions })
+    }
+}
+
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut encoder = Encoder::default();
+        let node = encoder.node(self);
+        let root = encoder.push(node);
+        ExprJson {
+            version: TREE_JSON_VERSION,
+            exprs: encoder.exprs,
+            root,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let json = ExprJson::deserialize(deserializer)?;
+        let decoder = Decoder::new(json.version, json.exprs).map_err(de::Error::custom)?;
+        let root = decoder.root(json.root).map_err(de::Error::custom)?;
+        Ok(root.as_ref().clone())
+    }
+}
+
+impl Serialize for Assumption {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut encoder = Encoder::default();
+        let assumption = encoder.assumption(self);
+        AssumptionJson {
+            version: TREE_JSON_VERSION,
+            exprs: encoder.exprs,
+            assumption,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Assumption {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let json = AssumptionJson::deserialize(deserializer)?;
+        let decoder = Decoder::new(json.version, json.exprs).map_err(de::Error::custom)?;
+        decoder
+            .assumption(json.assumption)
+            .map_err(de::Error::custom)
+    }
+}
+
+#[test]
+fn test_tree_json_round_trip() {
+    use crate::are_progs_eq;
+    use crate::ast::*;
+
+    let shared = add(getat(0), int(1));
+    let prog = program!(
+        function(
+            "main",
+            tuplet!(intt(), statet()),
+            tuplet!(intt(), statet()),
+            parallel!(mul(shared.clone(), shared), tprint(float(-0.5), getat(1)))
+        ),
+        function(
+            "other",
+            tuplet!(boolt(), statet()),
+            tuplet!(boolt(), statet()),
+            parallel!(not(getat(0)), getat(1))
+        ),
+    )
+    .add_dummy_ctx()
+    .0;
+
+    let json = serde_json::to_string(&prog).unwrap();
+    let parsed: TreeProgram = serde_json::from_str(&json).unwrap();
+    assert!(are_progs_eq(prog.clone(), parsed.clone()));
+    // serializing again gives the same table, so nothing was duplicated
+    assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+
+    let body = prog.entry.func_body().unwrap().clone();
+    let json = serde_json::to_string(body.as_ref()).unwrap();
+    let parsed: Expr = serde_json::from_str(&json).unwrap();
+    assert_eq!(&parsed, body.as_ref());
+}
+
+#[test]
+fn test_tree_json_errors() {
+    let forward = r#"{"version": 1, "exprs": [{"Single": 1}, {"Single": 0}], "root": 1}"#;
+    let err = serde_json::from_str::<Expr>(forward).unwrap_err();
+    assert!(err.to_string().contains("does not come before it"), "{err}");
+
+    let version = r#"{"version": 0, "exprs": [], "entry": 0, "functions": []}"#;
+    let err = serde_json::from_str::<TreeProgram>(version).unwrap_err();
+    assert!(err.to_string().contains("unsupported"), "{err}");
+}
@@ -0,0 +1,277 @@
+//! Parses tree programs written in the `.tree` text format,
+//! so that regression tests can be written directly at the DAG level.
+//!
+//! A `.tree` file is a sequence of egglog-style `let` bindings whose last
+//! item is the program, using the constructors of `schema.egg`:
+//! ```text
+//! ; ARGS: 1 2
+//! (let ctx (InFunc "main"))
+//! (let ty (TupleT (TCons (IntT) (TCons (StateT) (TNil)))))
+//! (let x (Get (Arg ty ctx) 0))
+//! (let main (Function "main" ty ty
+//!   (Concat (Single (Bop (Add) x x)) (Single (Get (Arg ty ctx) 1)))))
+//! (Program main (Nil))
+//! ```
+//! Later items can refer to earlier bindings by name, and `;` starts a comment.
+//! The last item may also be a binding of the program, so the output of
+//! `TreeProgram::pretty_print_to_egglog` is a valid `.tree` file.
+//!
+//! Equal sub-expressions are shared in the parsed program, whether or not they
+//! were written with a binding, so it satisfies the sharing invariant
+//! (see `TreeProgram::restore_sharing_invariant`).
+//! Syntax errors and unbound names are reported as a `TreeParseError`,
+//! but terms that don't match the schema panic like other conversions from egglog.
+
+use std::collections::HashMap;
+
+use egglog::{
+    ast::{parse_expr, Expr, Literal},
+    Term, TermDag,
+};
+use ordered_float::OrderedFloat;
+use thiserror::Error;
+
+use crate::{from_egglog::program_from_egglog, schema::TreeProgram};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TreeParseError {
+    #[error("line {line}: unbalanced parentheses")]
+    UnbalancedParens { line: usize },
+    #[error("line {line}: unterminated string")]
+    UnterminatedString { line: usize },
+    #[error("line {line}: expected a parenthesized item, found `{found}`")]
+    ExpectedItem { line: usize, found: String },
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("line {line}: unbound name `{name}`")]
+    UnboundName { line: usize, name: String },
+    #[error("the last item should be a `Program`")]
+    MissingProgram,
+}
+
+/// Parses a tree program in the `.tree` format.
+pub fn parse_tree_program(src: &str) -> Result<TreeProgram, TreeParseError> {
+    let mut termdag = TermDag::default();
+    let mut bindings = HashMap::<String, Term>::new();
+    let mut last = None;
+    for (line, item) in top_level_items(src)? {
+        let (name, body) = match split_let(item) {
+            Some((name, body)) => (Some(name), body),
+            None => (None, item),
+        };
+        let expr = parse_expr(None, body).map_err(|err| TreeParseError::Syntax {
+            line,
+            message: err.to_string(),
+        })?;
+        let term = to_term(&expr, &bindings, &mut termdag, line)?;
+        if let Some(name) = name {
+            bindings.insert(name.to_string(), term.clone());
+        }
+        last = Some(term);
+    }
+
+    match last {
+        Some(Term::App(head, children)) if head.to_string() == "Program" => {
+            Ok(program_from_egglog(Term::App(head, children), &termdag))
+        }
+        _ => Err(TreeParseError::MissingProgram),
+    }
+}
+
+/// Splits the source into its top-level parenthesized items,
+/// along with the line each one starts on.
+fn top_level_items(src: &str) -> Result<Vec<(usize, &str)>, TreeParseError> {
+    let mut items = vec![];
+    let mut depth = 0;
+    let mut start = (0, 0);
+    let mut line = 1;
+    let mut chars = src.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            ';' => {
+                // skip the comment, leaving the newline to be counted
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+            }
+            '"' => {
+                let string_line = line;
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => {
+                            chars.next();
+                        }
+                        Some((_, '\n')) => line += 1,
+                        Some(_) => {}
+                        None => {
+                            return Err(TreeParseError::UnterminatedString { line: string_line })
+                        }
+                    }
+                }
+            }
+            '(' => {
+                if depth == 0 {
+                    start = (line, i);
+                }
+                depth += 1;
+            }
+            ')' => {
+                if depth == 0 {
+                    return Err(TreeParseError::UnbalancedParens { line });
+                }
+                depth -= 1;
+                if depth == 0 {
+                    items.push((start.0, &src[start.1..=i]));
+                }
+            }
+            c if c.is_whitespace() => {}
+            _ if depth == 0 => {
+                let found = src[i..].split_whitespace().next().unwrap_or_default();
+                return Err(TreeParseError::ExpectedItem {
+                    line,
+                    found: found.to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(TreeParseError::UnbalancedParens { line: start.0 });
+    }
+    Ok(items)
+}
+
+/// Splits `(let name body)` into its name and body.
+fn split_let(item: &str) -> Option<(&str, &str)> {
+    let inner = item.strip_prefix('(')?.strip_suffix(')')?.trim_start();
+    let rest = inner.strip_prefix("let")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let name_end = rest.find(|c: char| c.is_whitespace() || c == '(')?;
+    Some((&rest[..name_end], &rest[name_end..]))
+}
+
+/// Converts a parsed expression to a term, replacing bound names with their terms.
+fn to_term(
+    expr: &Expr,
+    bindings: &HashMap<String, Term>,
+    termdag: &mut TermDag,
+    line: usize,
+) -> Result<Term, TreeParseError> {
+    match expr {
+        Expr::Lit(_, lit) => Ok(termdag.lit(lit.clone())),
+        Expr::Var(_, name) => {
+            bindings
+                .get(&name.to_string())
+                .cloned()
+                .ok_or_else(|| TreeParseError::UnboundName {
+                    line,
+                    name: name.to_string(),
+                })
+        }
+        Expr::Call(_, head, args) => {
+            let mut children = vec![];
+            for arg in args {
+                let child = match arg {
+                    // allow whole floats to be written without a decimal point
+                    Expr::Lit(_, Literal::Int(i)) if head.to_string() == "Float" => {
+                        termdag.lit(Literal::F64(OrderedFloat(*i as f64)))
+                    }
+                    _ => to_term(arg, bindings, termdag, line)?,
+                };
+                children.push(child);
+            }
+            Ok(termdag.app(*head, children))
+        }
+    }
+}
+
+#[cfg(test)]
+fn find_mul(expr: &crate::schema::RcExpr) -> Option<crate::schema::RcExpr> {
+    use crate::schema::{BinaryOp, Expr};
+    if let Expr::Bop(BinaryOp::Mul, ..) = expr.as_ref() {
+        return Some(expr.clone());
+    }
+    expr.children_exprs().iter().find_map(find_mul)
+}
+
+#[test]
+fn test_parse_pretty_printed_program() {
+    use crate::are_progs_eq;
+    use crate::ast::*;
+    use crate::schema::Expr;
+
+    let shared = add(getat(0), int(1));
+    let prog = program!(
+        function(
+            "main",
+            tuplet!(intt(), statet()),
+            tuplet!(intt(), statet()),
+            parallel!(mul(shared.clone(), shared), tprint(float(1.5), getat(1)))
+        ),
+        function(
+            "other",
+            tuplet!(boolt(), statet()),
+            tuplet!(boolt(), statet()),
+            parallel!(not(getat(0)), getat(1))
+        ),
+    )
+    .add_dummy_ctx()
+    .0;
+
+    let parsed = parse_tree_program(&prog.pretty_print_to_egglog()).unwrap();
+    let Expr::Bop(_, lhs, rhs) = find_mul(&parsed.entry).unwrap().as_ref().clone() else {
+        unreachable!()
+    };
+    assert!(std::rc::Rc::ptr_eq(&lhs, &rhs));
+    assert!(are_progs_eq(prog, parsed));
+}
+
+#[test]
+fn test_parse_handwritten_program() {
+    use crate::schema::Expr;
+
+    // multiplies the first argument by itself, writing `(Get (Arg ty ctx) 0)` out twice
+    // instead of binding it; the two copies should still be shared
+    let src = r#"
+; ARGS: 1 2
+(let ctx (InFunc "main"))
+(let ty (TupleT (TCons (IntT) (TCons (StateT) (TNil)))))
+(let main (Function "main" ty ty
+  (Concat
+    (Single (Bop (Mul) (Get (Arg ty ctx) 0) (Get (Arg ty ctx) 0)))
+    (Single (Get (Arg ty ctx) 1)))))
+(Program main (Nil))
+"#;
+    let prog = parse_tree_program(src).unwrap();
+    assert_eq!(prog.fns(), vec!["main".to_string()]);
+    let Expr::Bop(_, lhs, rhs) = find_mul(&prog.entry).unwrap().as_ref().clone() else {
+        unreachable!()
+    };
+    assert!(std::rc::Rc::ptr_eq(&lhs, &rhs));
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(
+        parse_tree_program("(let x (Int 1)\n").unwrap_err(),
+        TreeParseError::UnbalancedParens { line: 1 }
+    );
+    assert_eq!(
+        parse_tree_program("(let x (Int 1))\n(Single y)").unwrap_err(),
+        TreeParseError::UnboundName {
+            line: 2,
+            name: "y".to_string()
+        }
+    );
+    assert_eq!(
+        parse_tree_program("; nothing here\n(let x (Int 1))").unwrap_err(),
+        TreeParseError::MissingProgram
+    );
+    assert!(matches!(
+        parse_tree_program("x").unwrap_err(),
+        TreeParseError::ExpectedItem { line: 1, .. }
+    ));
+}
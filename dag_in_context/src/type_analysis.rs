@@ -164,7 +164,7 @@ fn pointers() -> crate::Result {
         ptr.clone(),
         pointert(intt()),
         val_int(0),
-        Value::Ptr(Pointer::new(0, 12, 0)),
+        Value::Ptr(Pointer::new(0, 0, 12, 0)),
     )?;
     type_test(
         write(ptr.clone(), int_ty(1, emptyt())),
@@ -180,7 +180,7 @@ fn pointers() -> crate::Result {
         .with_arg_types(emptyt(), pointert(boolt())),
         pointert(boolt()),
         val_int(0),
-        Value::Ptr(Pointer::new(0, 1, 3)),
+        Value::Ptr(Pointer::new(0, 0, 1, 3)),
     )
 }
 
@@ -1,13 +1,84 @@
-use std::rc::Rc;
+use std::{fmt::Display, rc::Rc};
 
 use indexmap::IndexMap;
+use thiserror::Error;
 
 use crate::{
     ast::{base, empty, emptyt, function, program, statet},
+    pretty_print::PrettyPrinter,
     schema::{BaseType, BinaryOp, Constant, Expr, RcExpr, TernaryOp, TreeProgram, Type},
     tuplet,
 };
 
+/// The ways a program can be ill-typed.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum TypeErrorKind {
+    #[error("Expected {operand} to have type {expected}, got {found}")]
+    OperandMismatch {
+        operand: &'static str,
+        expected: String,
+        found: Type,
+    },
+    #[error("Get index {index} out of bounds for tuple type {tuple}")]
+    GetOutOfBounds { index: usize, tuple: Type },
+    #[error("Branch {branch} has type {found}, but branch 0 has type {expected}")]
+    BranchMismatch {
+        branch: usize,
+        expected: Type,
+        found: Type,
+    },
+    #[error("Switch has no branches")]
+    EmptySwitch,
+    #[error("Loop body has type {found}, expected a bool followed by the loop inputs {inputs}")]
+    LoopMismatch { inputs: Type, found: Type },
+    #[error("Expected {operand} to be a state edge, got {found}")]
+    ExpectedState { operand: &'static str, found: Type },
+    #[error("{operand} is a state edge, which can only be passed to effects")]
+    UnexpectedState { operand: &'static str },
+    #[error("Expected the argument type to be {expected}, got {found}")]
+    ArgTypeMismatch { expected: Type, found: Type },
+    #[error("Expected the function to return {expected}, got {found}")]
+    ReturnMismatch { expected: Type, found: Type },
+    #[error("Call to unknown function {0}")]
+    UnknownFunction(String),
+    #[error("Missing type in {0}")]
+    MissingType(&'static str),
+    #[error("Expected a function")]
+    ExpectedFunction,
+    #[error("Functions can't be nested in expressions")]
+    NestedFunction,
+}
+
+/// A type error, along with where it was found.
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    pub kind: TypeErrorKind,
+    /// The function being checked, if the checker was given a whole function.
+    pub function: Option<String>,
+    /// The steps from the function body (or the checked expression) to `expr`.
+    /// Each step is the parent's constructor, or operator for operations,
+    /// and the index of the child among the parent's expression children.
+    pub path: Vec<String>,
+    pub expr: RcExpr,
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Type error")?;
+        if let Some(function) = &self.function {
+            write!(f, " in function {function}")?;
+        }
+        if !self.path.is_empty() {
+            write!(f, " at {}", self.path.join(" > "))?;
+        }
+        writeln!(f, ": {}", self.kind)?;
+        let (log, _binding) = PrettyPrinter::default().to_egglog_default(&self.expr);
+        write!(f, "{}", log.trim())
+    }
+}
+
+impl std::error::Error for TypeError {}
+
 pub(crate) struct TypeStack(Vec<Type>);
 
 impl TypeStack {
@@ -34,15 +105,28 @@ impl TreeProgram {
     /// and performs type checking.
     /// Maintains the invariant that common subexpressions are shared using
     /// the same Rc<Expr> pointer.
+    /// Panics on ill-typed programs, see `try_with_arg_types`.
     pub(crate) fn with_arg_types(&self) -> TreeProgram {
         let mut checker = TypeChecker::new(self, false);
         checker.add_arg_types()
     }
 
+    /// Like `with_arg_types`, but returns the first type error instead of panicking.
+    pub fn try_with_arg_types(&self) -> Result<TreeProgram, TypeError> {
+        let mut checker = TypeChecker::new(self, false);
+        checker.try_add_arg_types()
+    }
+
     pub fn with_arg_types_and_cache(&self) -> (TreeProgram, TypeCache) {
+        self.try_with_arg_types_and_cache()
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `with_arg_types_and_cache`, but returns the first type error instead of panicking.
+    pub fn try_with_arg_types_and_cache(&self) -> Result<(TreeProgram, TypeCache), TypeError> {
         let mut checker = TypeChecker::new(self, false);
-        let prog = checker.add_arg_types();
-        (prog, checker.type_cache)
+        let prog = checker.try_add_arg_types()?;
+        Ok((prog, checker.type_cache))
     }
 }
 
@@ -57,11 +141,13 @@ impl Expr {
         let mut checker = TypeChecker::new(&prog, false);
         let (ty, new_expr) =
             checker.add_arg_types_to_expr(self.clone(), &Some(TypeStack(vec![input_ty])));
-        assert_eq!(
-            ty, output_ty,
-            "Expected return type to be {:?}. Got {:?}",
-            output_ty, ty
-        );
+        if ty != output_ty {
+            let kind = TypeErrorKind::ReturnMismatch {
+                expected: output_ty,
+                found: ty,
+            };
+            panic!("{}", checker.error(kind, &new_expr));
+        }
         new_expr
     }
     /// Adds argument types to the expression.
@@ -108,6 +194,10 @@ pub(crate) struct TypeChecker<'a> {
     /// As a result, the type_expr_cache contains expressions from the original program.
     #[allow(dead_code)]
     expect_fully_typed: bool,
+    /// The function being checked, for error messages
+    function: Option<String>,
+    /// The path to the expression being checked, for error messages
+    path: Vec<String>,
 }
 
 impl<'a> TypeChecker<'a> {
@@ -117,51 +207,157 @@ impl<'a> TypeChecker<'a> {
             type_cache: IndexMap::new(),
             type_expr_cache: IndexMap::new(),
             expect_fully_typed,
+            function: None,
+            path: vec![],
+        }
+    }
+
+    fn error(&self, kind: TypeErrorKind, expr: &RcExpr) -> TypeError {
+        TypeError {
+            kind,
+            function: self.function.clone(),
+            path: self.path.clone(),
+            expr: expr.clone(),
+        }
+    }
+
+    fn mismatch(
+        &self,
+        operand: &'static str,
+        expected: impl Display,
+        found: &Type,
+        expr: &RcExpr,
+    ) -> TypeError {
+        let kind = TypeErrorKind::OperandMismatch {
+            operand,
+            expected: expected.to_string(),
+            found: found.clone(),
+        };
+        self.error(kind, expr)
+    }
+
+    fn expect(
+        &self,
+        operand: &'static str,
+        expected: &Type,
+        found: &Type,
+        expr: &RcExpr,
+    ) -> Result<(), TypeError> {
+        if expected != found {
+            return Err(self.mismatch(operand, expected, found, expr));
         }
+        Ok(())
+    }
+
+    fn expect_state(
+        &self,
+        operand: &'static str,
+        found: &Type,
+        expr: &RcExpr,
+    ) -> Result<(), TypeError> {
+        if found != &base(statet()) {
+            let kind = TypeErrorKind::ExpectedState {
+                operand,
+                found: found.clone(),
+            };
+            return Err(self.error(kind, expr));
+        }
+        Ok(())
+    }
+
+    fn expect_value(
+        &self,
+        operand: &'static str,
+        found: &Type,
+        expr: &RcExpr,
+    ) -> Result<(), TypeError> {
+        if found.contains_state() {
+            return Err(self.error(TypeErrorKind::UnexpectedState { operand }, expr));
+        }
+        Ok(())
+    }
+
+    /// Checks the `index`th expression child of `parent`.
+    fn child(
+        &mut self,
+        parent: &RcExpr,
+        index: usize,
+        child: &RcExpr,
+        arg_tys: &Option<TypeStack>,
+    ) -> Result<(Type, RcExpr), TypeError> {
+        let step = match parent.as_ref() {
+            Expr::Bop(op, ..) => op.name(),
+            Expr::Uop(op, ..) => op.name(),
+            Expr::Top(op, ..) => op.name(),
+            _ => parent.constructor().name(),
+        };
+        self.path.push(format!("{step}[{index}]"));
+        let res = self.try_add_arg_types_to_expr(child.clone(), arg_tys);
+        self.path.pop();
+        res
     }
 
     pub(crate) fn add_arg_types(&mut self) -> TreeProgram {
-        TreeProgram {
-            entry: self.add_arg_types_to_func(self.program.entry.clone()),
+        self.try_add_arg_types()
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub(crate) fn try_add_arg_types(&mut self) -> Result<TreeProgram, TypeError> {
+        Ok(TreeProgram {
+            entry: self.try_add_arg_types_to_func(self.program.entry.clone())?,
             functions: self
                 .program
                 .functions
                 .iter()
-                .map(|expr| self.add_arg_types_to_func(expr.clone()))
-                .collect(),
-        }
+                .map(|expr| self.try_add_arg_types_to_func(expr.clone()))
+                .collect::<Result<_, _>>()?,
+        })
     }
 
-    pub(crate) fn add_arg_types_to_func(&mut self, func: RcExpr) -> RcExpr {
-        match func.as_ref() {
-            Expr::Function(name, in_ty, out_ty, body) => {
-                let (expr_ty, new_body) =
-                    self.add_arg_types_to_expr(body.clone(), &Some(TypeStack(vec![in_ty.clone()])));
-                assert_eq!(
-                    expr_ty, *out_ty,
-                    "Expected return type to be {:?}. Got {:?}",
-                    out_ty, expr_ty
-                );
-                RcExpr::new(Expr::Function(
-                    name.clone(),
-                    in_ty.clone(),
-                    out_ty.clone(),
-                    new_body,
-                ))
-            }
-            _ => panic!("Expected function, got {:?}", func),
+    pub(crate) fn try_add_arg_types_to_func(&mut self, func: RcExpr) -> Result<RcExpr, TypeError> {
+        let Expr::Function(name, in_ty, out_ty, body) = func.as_ref() else {
+            return Err(self.error(TypeErrorKind::ExpectedFunction, &func));
+        };
+        self.function = Some(name.clone());
+        let (expr_ty, new_body) =
+            self.try_add_arg_types_to_expr(body.clone(), &Some(TypeStack(vec![in_ty.clone()])))?;
+        if expr_ty != *out_ty {
+            let kind = TypeErrorKind::ReturnMismatch {
+                expected: out_ty.clone(),
+                found: expr_ty,
+            };
+            return Err(self.error(kind, body));
         }
+        self.function = None;
+        Ok(RcExpr::new(Expr::Function(
+            name.clone(),
+            in_ty.clone(),
+            out_ty.clone(),
+            new_body,
+        )))
     }
 
+    /// Like `try_add_arg_types_to_expr`, but panics on type errors.
     pub(crate) fn add_arg_types_to_expr(
+        &mut self,
+        expr: RcExpr,
+        arg_tys: &Option<TypeStack>,
+    ) -> (Type, RcExpr) {
+        self.try_add_arg_types_to_expr(expr, arg_tys)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub(crate) fn try_add_arg_types_to_expr(
         &mut self,
         expr: RcExpr,
         // the current argument types
         // can be None when `expect_fully_typed` is true
         arg_tys: &Option<TypeStack>,
-    ) -> (Type, RcExpr) {
+    ) -> Result<(Type, RcExpr), TypeError> {
         if let Some(tys) = arg_tys {
-            assert!(tys.get() != &Type::Unknown, "Expected known argument type");
+            if tys.get() == &Type::Unknown {
+                return Err(self.error(TypeErrorKind::MissingType("the argument type"), &expr));
+            }
         }
 
         let old_expr_ptr = Rc::as_ptr(&expr);
@@ -172,7 +368,7 @@ impl<'a> TypeChecker<'a> {
         )) {
             let new_expr_ptr = Rc::as_ptr(updated_expr);
             let ty = self.type_cache.get(&new_expr_ptr).unwrap();
-            return (ty.clone(), updated_expr.clone());
+            return Ok((ty.clone(), updated_expr.clone()));
         }
 
         let (res_ty, mut res_expr) = match expr.as_ref() {
@@ -185,7 +381,7 @@ impl<'a> TypeChecker<'a> {
                 match ty {
                     Type::Unknown => {
                         if self.expect_fully_typed {
-                            panic!("Expected type to be known in constant")
+                            return Err(self.error(TypeErrorKind::MissingType("constant"), &expr));
                         }
                         (
                             cty.clone(),
@@ -197,67 +393,48 @@ impl<'a> TypeChecker<'a> {
                         )
                     }
                     _ => {
-                        if let Some(tys) = arg_tys {
-                            assert_eq!(
-                                tys.get(),
-                                ty,
-                                "Expected arg type in constant to be {:?}. Got {:?}",
-                                tys.get(),
-                                ty
-                            );
-                        }
+                        self.check_arg_type(ty, arg_tys, &expr)?;
                         (cty, expr.clone())
                     }
                 }
             }
             Expr::Top(TernaryOp::Write, left, right, state) => {
-                let (lty, new_left) = self.add_arg_types_to_expr(left.clone(), arg_tys);
-                let (rty, new_right) = self.add_arg_types_to_expr(right.clone(), arg_tys);
-                let (_sty, new_state) = self.add_arg_types_to_expr(state.clone(), arg_tys);
-                let Type::Base(BaseType::PointerT(innert)) = lty else {
-                    panic!("Expected pointer type. Got {:?}", lty)
+                let (lty, new_left) = self.child(&expr, 0, left, arg_tys)?;
+                let (rty, new_right) = self.child(&expr, 1, right, arg_tys)?;
+                let (sty, new_state) = self.child(&expr, 2, state, arg_tys)?;
+                let Type::Base(BaseType::PointerT(innert)) = &lty else {
+                    return Err(self.mismatch("the pointer", "a pointer type", &lty, &expr));
                 };
-                let Type::Base(baset) = &rty else {
-                    panic!("Expected base type. Got {:?}", rty);
-                };
-                assert_eq!(
-                    *innert,
-                    baset.clone(),
-                    "Expected right type to be {:?}. Got {:?}",
-                    innert,
-                    rty
-                );
+                self.expect(
+                    "the written value",
+                    &Type::Base(*innert.clone()),
+                    &rty,
+                    &expr,
+                )?;
+                self.expect_state("the state", &sty, &expr)?;
                 (
                     base(statet()),
                     RcExpr::new(Expr::Top(TernaryOp::Write, new_left, new_right, new_state)),
                 )
             }
             Expr::Top(TernaryOp::Select, c, t, e) => {
-                let (cty, new_cond) = self.add_arg_types_to_expr(c.clone(), arg_tys);
-                let (tty, new_then) = self.add_arg_types_to_expr(t.clone(), arg_tys);
-                let (ety, new_else) = self.add_arg_types_to_expr(e.clone(), arg_tys);
-                let Type::Base(BaseType::BoolT) = cty else {
-                    panic!("Expected base type. Got {:?}", cty)
-                };
-                assert_eq!(
-                    tty, ety,
-                    "Expected then and else types to be the same. Got {:?} and {:?}",
-                    tty, ety
-                );
+                let (cty, new_cond) = self.child(&expr, 0, c, arg_tys)?;
+                let (tty, new_then) = self.child(&expr, 1, t, arg_tys)?;
+                let (ety, new_else) = self.child(&expr, 2, e, arg_tys)?;
+                self.expect("the condition", &base(BaseType::BoolT), &cty, &expr)?;
+                self.expect("the else value", &tty, &ety, &expr)?;
                 (
                     tty,
                     RcExpr::new(Expr::Top(TernaryOp::Select, new_cond, new_then, new_else)),
                 )
             }
             Expr::Bop(BinaryOp::PtrAdd, left, right) => {
-                let (lty, new_left) = self.add_arg_types_to_expr(left.clone(), arg_tys);
-                let (rty, new_right) = self.add_arg_types_to_expr(right.clone(), arg_tys);
+                let (lty, new_left) = self.child(&expr, 0, left, arg_tys)?;
+                let (rty, new_right) = self.child(&expr, 1, right, arg_tys)?;
                 let Type::Base(BaseType::PointerT(innert)) = lty else {
-                    panic!("Expected pointer type. Got {:?}", lty)
-                };
-                let Type::Base(BaseType::IntT) = rty else {
-                    panic!("Expected int type. Got {:?}", rty)
+                    return Err(self.mismatch("the pointer", "a pointer type", &lty, &expr));
                 };
+                self.expect("the offset", &base(BaseType::IntT), &rty, &expr)?;
                 (
                     Type::Base(BaseType::PointerT(innert)),
                     RcExpr::new(Expr::Bop(BinaryOp::PtrAdd, new_left, new_right)),
@@ -266,18 +443,10 @@ impl<'a> TypeChecker<'a> {
             // covers all cases where the input and output types are concrete
             Expr::Bop(op, left, right) if op.types().is_some() => {
                 let (left_expected, right_expected, out_expected) = op.types().unwrap();
-                let (lty, new_left) = self.add_arg_types_to_expr(left.clone(), arg_tys);
-                let (rty, new_right) = self.add_arg_types_to_expr(right.clone(), arg_tys);
-                assert_eq!(
-                    lty, left_expected,
-                    "Expected left type to be {:?}. Got {:?}",
-                    left_expected, lty
-                );
-                assert_eq!(
-                    rty, right_expected,
-                    "Expected right type to be {:?} in {:?}. Got {:?}",
-                    right_expected, expr, rty
-                );
+                let (lty, new_left) = self.child(&expr, 0, left, arg_tys)?;
+                let (rty, new_right) = self.child(&expr, 1, right, arg_tys)?;
+                self.expect("the left operand", &left_expected, &lty, &expr)?;
+                self.expect("the right operand", &right_expected, &rty, &expr)?;
                 (
                     out_expected,
                     RcExpr::new(Expr::Bop(op.clone(), new_left, new_right)),
@@ -286,85 +455,83 @@ impl<'a> TypeChecker<'a> {
             // covers all cases where the input and output types are concrete
             Expr::Uop(op, inner) if op.types().is_some() => {
                 let (expected_inner, expected_out) = op.types().unwrap();
-                let (ity, new_inner) = self.add_arg_types_to_expr(inner.clone(), arg_tys);
-                assert_eq!(
-                    ity, expected_inner,
-                    "Expected inner type to be {:?}. Got {:?}",
-                    expected_inner, ity
-                );
+                let (ity, new_inner) = self.child(&expr, 0, inner, arg_tys)?;
+                self.expect("the operand", &expected_inner, &ity, &expr)?;
                 (expected_out, RcExpr::new(Expr::Uop(op.clone(), new_inner)))
             }
             Expr::Bop(BinaryOp::Print, inner, state) => {
-                let (_ity, new_inner) = self.add_arg_types_to_expr(inner.clone(), arg_tys);
-                let (_sty, new_state) = self.add_arg_types_to_expr(state.clone(), arg_tys);
+                let (ity, new_inner) = self.child(&expr, 0, inner, arg_tys)?;
+                let (sty, new_state) = self.child(&expr, 1, state, arg_tys)?;
+                self.expect_value("the printed value", &ity, &expr)?;
+                self.expect_state("the state", &sty, &expr)?;
                 (
                     base(statet()),
                     RcExpr::new(Expr::Bop(BinaryOp::Print, new_inner, new_state)),
                 )
             }
             Expr::Bop(BinaryOp::Load, inner, state) => {
-                let (ity, new_inner) = self.add_arg_types_to_expr(inner.clone(), arg_tys);
-                let (_sty, new_state) = self.add_arg_types_to_expr(state.clone(), arg_tys);
+                let (ity, new_inner) = self.child(&expr, 0, inner, arg_tys)?;
+                let (sty, new_state) = self.child(&expr, 1, state, arg_tys)?;
                 let Type::Base(BaseType::PointerT(out_ty)) = ity else {
-                    panic!("Expected pointer type. Got {:?}", ity)
+                    return Err(self.mismatch("the pointer", "a pointer type", &ity, &expr));
                 };
+                self.expect_state("the state", &sty, &expr)?;
                 (
                     tuplet!(*out_ty, statet()),
                     RcExpr::new(Expr::Bop(BinaryOp::Load, new_inner, new_state)),
                 )
             }
             Expr::Bop(BinaryOp::Free, inner, state) => {
-                let (ity, new_inner) = self.add_arg_types_to_expr(inner.clone(), arg_tys);
-                let (_sty, new_state) = self.add_arg_types_to_expr(state.clone(), arg_tys);
+                let (ity, new_inner) = self.child(&expr, 0, inner, arg_tys)?;
+                let (sty, new_state) = self.child(&expr, 1, state, arg_tys)?;
                 let Type::Base(BaseType::PointerT(_out_ty)) = ity else {
-                    panic!("Expected pointer type. Got {:?}", ity)
+                    return Err(self.mismatch("the pointer", "a pointer type", &ity, &expr));
                 };
+                self.expect_state("the state", &sty, &expr)?;
                 (
                     base(statet()),
                     RcExpr::new(Expr::Bop(BinaryOp::Free, new_inner, new_state)),
                 )
             }
             Expr::Get(child, index) => {
-                let (cty, new_child) = self.add_arg_types_to_expr(child.clone(), arg_tys);
-                let Type::TupleT(types) = cty.clone() else {
-                    panic!("Expected tuple type in {:?}. Got {:?}", child, cty)
+                let (cty, new_child) = self.child(&expr, 0, child, arg_tys)?;
+                let Type::TupleT(types) = &cty else {
+                    return Err(self.mismatch("the tuple", "a tuple type", &cty, &expr));
+                };
+                let Some(expected_ty) = types.get(*index).cloned() else {
+                    let kind = TypeErrorKind::GetOutOfBounds {
+                        index: *index,
+                        tuple: cty.clone(),
+                    };
+                    return Err(self.error(kind, &expr));
                 };
-                if *index >= types.len() {
-                    panic!(
-                        "Index out of bounds. Tuple has type {}, index is {}. Expr:\n{}",
-                        cty, index, expr
-                    );
-                }
-                let expected_ty = types[*index].clone();
                 (
                     Type::Base(expected_ty),
                     RcExpr::new(Expr::Get(new_child, *index)),
                 )
             }
             Expr::Alloc(id, amount, state, baset) => {
-                let (aty, new_amount) = self.add_arg_types_to_expr(amount.clone(), arg_tys);
-                let (_sty, new_state) = self.add_arg_types_to_expr(state.clone(), arg_tys);
-                let Type::Base(BaseType::IntT) = aty else {
-                    panic!("Expected int type. Got {:?}", aty)
-                };
+                let (aty, new_amount) = self.child(&expr, 0, amount, arg_tys)?;
+                let (sty, new_state) = self.child(&expr, 1, state, arg_tys)?;
+                self.expect("the allocation size", &base(BaseType::IntT), &aty, &expr)?;
+                self.expect_state("the state", &sty, &expr)?;
                 (
                     tuplet!(baset.clone(), statet()),
                     RcExpr::new(Expr::Alloc(*id, new_amount, new_state, baset.clone())),
                 )
             }
             Expr::Call(string, arg) => {
-                let (aty, new_arg) = self.add_arg_types_to_expr(arg.clone(), arg_tys);
-                let func = self
-                    .program
-                    .get_function(string)
-                    .unwrap_or_else(|| panic!("Function {string} should exist in program."));
-                assert_eq!(
-                    aty,
-                    func.func_input_ty().unwrap(),
-                    "Expected argument type to be {:?}. Got {:?}",
-                    func.func_input_ty().unwrap(),
-                    aty
-                );
+                let (aty, new_arg) = self.child(&expr, 0, arg, arg_tys)?;
+                let Some(func) = self.program.get_function(string) else {
+                    let kind = TypeErrorKind::UnknownFunction(string.clone());
+                    return Err(self.error(kind, &expr));
+                };
+                self.expect(
+                    "the call argument",
+                    &func.func_input_ty().unwrap(),
+                    &aty,
+                    &expr,
+                )?;
                 (
                     func.func_output_ty().unwrap(),
                     RcExpr::new(Expr::Call(string.clone(), new_arg)),
@@ -373,7 +540,7 @@ impl<'a> TypeChecker<'a> {
             Expr::Empty(ty, ctx) => match ty {
                 Type::Unknown => {
                     if self.expect_fully_typed {
-                        panic!("Expected type to be known in empty")
+                        return Err(self.error(TypeErrorKind::MissingType("empty"), &expr));
                     }
                     (
                         emptyt(),
@@ -384,23 +551,14 @@ impl<'a> TypeChecker<'a> {
                     )
                 }
                 _ => {
-                    if let Some(arg_tys) = arg_tys {
-                        assert_eq!(
-                            arg_tys.get(),
-                            ty,
-                            "Expected arg type in empty to be {:?}. Got {:?}",
-                            arg_tys.get(),
-                            ty
-                        );
-                    }
+                    self.check_arg_type(ty, arg_tys, &expr)?;
                     (emptyt(), expr.clone())
                 }
             },
             Expr::Single(arg) => {
-                let (Type::Base(basety), new_arg) =
-                    self.add_arg_types_to_expr(arg.clone(), arg_tys)
-                else {
-                    panic!("Expected base type in child of Single. Got {:?}", arg)
+                let (aty, new_arg) = self.child(&expr, 0, arg, arg_tys)?;
+                let Type::Base(basety) = aty else {
+                    return Err(self.mismatch("the element", "a base type", &aty, &expr));
                 };
                 (
                     Type::TupleT(vec![basety]),
@@ -408,16 +566,13 @@ impl<'a> TypeChecker<'a> {
                 )
             }
             Expr::Concat(left, right) => {
-                let (lty, new_left) = self.add_arg_types_to_expr(left.clone(), arg_tys);
-                let (rty, new_right) = self.add_arg_types_to_expr(right.clone(), arg_tys);
+                let (lty, new_left) = self.child(&expr, 0, left, arg_tys)?;
+                let (rty, new_right) = self.child(&expr, 1, right, arg_tys)?;
                 let Type::TupleT(ltypes) = lty else {
-                    panic!(
-                        "Expected tuple type. Got {:?}. Left Expr:{} Right Expr: {}",
-                        lty, left, right
-                    )
+                    return Err(self.mismatch("the left tuple", "a tuple type", &lty, &expr));
                 };
                 let Type::TupleT(rtypes) = rty else {
-                    panic!("Expected tuple type. Got {:?}", rty)
+                    return Err(self.mismatch("the right tuple", "a tuple type", &rty, &expr));
                 };
                 let result_types = ltypes.into_iter().chain(rtypes).collect();
                 (
@@ -426,78 +581,90 @@ impl<'a> TypeChecker<'a> {
                 )
             }
             Expr::Switch(integer, input, branches) => {
-                let (ity, new_integer) = self.add_arg_types_to_expr(integer.clone(), arg_tys);
-                let (inputty, new_input) = self.add_arg_types_to_expr(input.clone(), arg_tys);
-                let Type::Base(BaseType::IntT) = ity else {
-                    panic!("Expected int type. Got {:?}", ity)
-                };
+                let (ity, new_integer) = self.child(&expr, 0, integer, arg_tys)?;
+                let (inputty, new_input) = self.child(&expr, 1, input, arg_tys)?;
+                self.expect("the switch predicate", &base(BaseType::IntT), &ity, &expr)?;
                 let mut new_branches = vec![];
                 let mut res_type = None;
-                for branch in branches {
-                    let (bty, new_branch) = self.add_arg_types_to_expr(
-                        branch.clone(),
+                for (i, branch) in branches.iter().enumerate() {
+                    let (bty, new_branch) = self.child(
+                        &expr,
+                        2 + i,
+                        branch,
                         &arg_tys.as_ref().map(|inner| inner.pushed(inputty.clone())),
-                    );
+                    )?;
                     new_branches.push(new_branch);
-                    res_type = match res_type {
-                        Some(t) => {
-                            assert_eq!(t, bty, "Expected all branches to have the same type");
-                            Some(t)
+                    match &res_type {
+                        Some(t) if t != &bty => {
+                            let kind = TypeErrorKind::BranchMismatch {
+                                branch: i,
+                                expected: t.clone(),
+                                found: bty,
+                            };
+                            return Err(self.error(kind, &expr));
                         }
-                        None => Some(bty),
-                    };
+                        Some(_) => {}
+                        None => res_type = Some(bty),
+                    }
                 }
+                let Some(res_type) = res_type else {
+                    return Err(self.error(TypeErrorKind::EmptySwitch, &expr));
+                };
                 (
-                    res_type.unwrap(),
+                    res_type,
                     RcExpr::new(Expr::Switch(new_integer, new_input, new_branches)),
                 )
             }
             Expr::If(pred, input, then, else_branch) => {
-                let (pty, new_pred) = self.add_arg_types_to_expr(pred.clone(), arg_tys);
-                let (ity, new_input) = self.add_arg_types_to_expr(input.clone(), arg_tys);
-                let Type::Base(BaseType::BoolT) = pty else {
-                    panic!("Expected bool type. Got {:?}", pty)
-                };
-                let (tty, new_then) = self.add_arg_types_to_expr(
-                    then.clone(),
+                let (pty, new_pred) = self.child(&expr, 0, pred, arg_tys)?;
+                let (ity, new_input) = self.child(&expr, 1, input, arg_tys)?;
+                self.expect("the if predicate", &base(BaseType::BoolT), &pty, &expr)?;
+                let (tty, new_then) = self.child(
+                    &expr,
+                    2,
+                    then,
                     &arg_tys.as_ref().map(|inner| inner.pushed(ity.clone())),
-                );
-                let (ety, new_else) = self.add_arg_types_to_expr(
-                    else_branch.clone(),
+                )?;
+                let (ety, new_else) = self.child(
+                    &expr,
+                    3,
+                    else_branch,
                     &arg_tys.as_ref().map(|inner| inner.pushed(ity)),
-                );
-                assert_eq!(
-                    tty, ety,
-                    "Expected then and else types to be the same. Got {:?} and {:?}",
-                    tty, ety
-                );
+                )?;
+                if tty != ety {
+                    let kind = TypeErrorKind::BranchMismatch {
+                        branch: 1,
+                        expected: tty,
+                        found: ety,
+                    };
+                    return Err(self.error(kind, &expr));
+                }
                 (
                     tty,
                     RcExpr::new(Expr::If(new_pred, new_input, new_then, new_else)),
                 )
             }
             Expr::DoWhile(inputs, pred_and_outputs) => {
-                let (ity, new_inputs) = self.add_arg_types_to_expr(inputs.clone(), arg_tys);
+                let (ity, new_inputs) = self.child(&expr, 0, inputs, arg_tys)?;
                 let Type::TupleT(in_tys) = ity.clone() else {
-                    panic!("Expected tuple type. Got {:?}", ity)
+                    return Err(self.mismatch("the loop inputs", "a tuple type", &ity, &expr));
+                };
+                let (pty, new_pred_and_outputs) = self.child(
+                    &expr,
+                    1,
+                    pred_and_outputs,
+                    &arg_tys.as_ref().map(|inner| inner.pushed(ity.clone())),
+                )?;
+                let loop_mismatch = TypeErrorKind::LoopMismatch {
+                    inputs: ity,
+                    found: pty.clone(),
                 };
-                let (pty, new_pred_and_outputs) = self.add_arg_types_to_expr(
-                    pred_and_outputs.clone(),
-                    &arg_tys.as_ref().map(|inner| inner.pushed(ity)),
-                );
                 let Type::TupleT(out_tys) = pty else {
-                    panic!("Expected tuple type. Got {:?}", pty)
+                    return Err(self.error(loop_mismatch, &expr));
                 };
-                assert_eq!(
-                    out_tys[0],
-                    BaseType::BoolT,
-                    "Expected first output type to be bool"
-                );
-                assert_eq!(
-                    in_tys,
-                    out_tys[1..],
-                    "Expected output types to match input types"
-                );
+                if out_tys.first() != Some(&BaseType::BoolT) || in_tys != out_tys[1..] {
+                    return Err(self.error(loop_mismatch, &expr));
+                }
                 (
                     Type::TupleT(out_tys[1..].to_vec()),
                     RcExpr::new(Expr::DoWhile(new_inputs, new_pred_and_outputs)),
@@ -506,7 +673,7 @@ impl<'a> TypeChecker<'a> {
             // Replace the argument type with the new type
             Expr::Arg(Type::Unknown, ctx) => {
                 if self.expect_fully_typed {
-                    panic!("Expected type to be known in arg")
+                    return Err(self.error(TypeErrorKind::MissingType("argument"), &expr));
                 }
                 (
                     arg_tys.as_ref().unwrap().get().clone(),
@@ -517,23 +684,19 @@ impl<'a> TypeChecker<'a> {
                 )
             }
             Expr::Arg(found_ty, _ctx) => {
-                if let Some(arg_tys) = arg_tys {
-                    assert_eq!(
-                        &found_ty,
-                        &arg_tys.get(),
-                        "Expected argument type to be {:?}. Got {:?}",
-                        arg_tys.get(),
-                        found_ty
-                    );
-                }
+                self.check_arg_type(found_ty, arg_tys, &expr)?;
                 (found_ty.clone(), expr.clone())
             }
-            Expr::Function(_, _, _, _) => panic!("Expected expression, got function"),
-            Expr::Symbolic(_, ty) => (
-                ty.clone()
-                    .expect("symbolic expression missing type annotation"),
-                expr.clone(),
-            ),
+            Expr::Function(_, _, _, _) => {
+                return Err(self.error(TypeErrorKind::NestedFunction, &expr))
+            }
+            Expr::Symbolic(_, ty) => {
+                let Some(ty) = ty.clone() else {
+                    let kind = TypeErrorKind::MissingType("symbolic expression");
+                    return Err(self.error(kind, &expr));
+                };
+                (ty, expr.clone())
+            }
             // should have covered all cases, but rust can't prove it
             // due to the side conditions
             _ => panic!("Unexpected expression {:?}", expr.clone()),
@@ -552,7 +715,27 @@ impl<'a> TypeChecker<'a> {
         );
         self.type_cache.insert(new_expr_ptr, res_ty.clone());
 
-        (res_ty, res_expr)
+        Ok((res_ty, res_expr))
+    }
+
+    /// Checks the type annotated on an argument, constant or empty
+    /// against the current argument type.
+    fn check_arg_type(
+        &self,
+        found: &Type,
+        arg_tys: &Option<TypeStack>,
+        expr: &RcExpr,
+    ) -> Result<(), TypeError> {
+        if let Some(arg_tys) = arg_tys {
+            if arg_tys.get() != found {
+                let kind = TypeErrorKind::ArgTypeMismatch {
+                    expected: arg_tys.get().clone(),
+                    found: found.clone(),
+                };
+                return Err(self.error(kind, expr));
+            }
+        }
+        Ok(())
     }
 
     pub(crate) fn get_arg_type(expr: &RcExpr) -> Type {
@@ -576,3 +759,104 @@ impl<'a> TypeChecker<'a> {
         }
     }
 }
+
+#[test]
+fn test_type_error_locations() {
+    use crate::ast::*;
+
+    let check = |entry: RcExpr, functions: Vec<RcExpr>| {
+        TreeProgram { entry, functions }
+            .try_with_arg_types()
+            .unwrap_err()
+    };
+    let state_ty = tuplet!(intt(), statet());
+
+    let err = check(
+        function(
+            "main",
+            state_ty.clone(),
+            state_ty.clone(),
+            concat(single(getat(2)), single(getat(1))),
+        ),
+        vec![],
+    );
+    assert_eq!(
+        err.kind,
+        TypeErrorKind::GetOutOfBounds {
+            index: 2,
+            tuple: state_ty.clone()
+        }
+    );
+    assert_eq!(err.function, Some("main".to_string()));
+    assert_eq!(err.path, vec!["Concat[0]", "Single[0]"]);
+    assert!(err
+        .to_string()
+        .starts_with("Type error in function main at Concat[0] > Single[0]: Get index 2"));
+
+    let err = check(
+        function("main", base(intt()), base(intt()), call("f", arg())),
+        vec![function(
+            "f",
+            base(intt()),
+            base(intt()),
+            add(add(arg(), ttrue()), int(1)),
+        )],
+    );
+    assert_eq!(
+        err.kind,
+        TypeErrorKind::OperandMismatch {
+            operand: "the right operand",
+            expected: base(intt()).to_string(),
+            found: base(boolt()),
+        }
+    );
+    assert_eq!(err.function, Some("f".to_string()));
+    assert_eq!(err.path, vec!["Add[0]"]);
+
+    let err = check(
+        function(
+            "main",
+            base(intt()),
+            base(intt()),
+            switch!(int(0), arg(); int(1), ttrue()),
+        ),
+        vec![],
+    );
+    assert_eq!(
+        err.kind,
+        TypeErrorKind::BranchMismatch {
+            branch: 1,
+            expected: base(intt()),
+            found: base(boolt()),
+        }
+    );
+}
+
+#[test]
+fn test_type_error_state() {
+    use crate::ast::*;
+
+    let check = |body: RcExpr| {
+        let entry = function("main", tuplet!(intt(), statet()), base(statet()), body);
+        TreeProgram {
+            entry,
+            functions: vec![],
+        }
+        .try_with_arg_types()
+        .unwrap_err()
+        .kind
+    };
+    assert_eq!(
+        check(tprint(getat(1), getat(1))),
+        TypeErrorKind::UnexpectedState {
+            operand: "the printed value"
+        }
+    );
+    assert_eq!(
+        check(tprint(getat(0), getat(0))),
+        TypeErrorKind::ExpectedState {
+            operand: "the state",
+            found: base(intt()),
+        }
+    );
+}
@@ -0,0 +1,69 @@
+//! Generates random Bril programs and checks that every configuration
+//! prints the same output as brilirs on them.
+//! Failing programs are saved to `--out-dir` so they can become test cases.
+
+use clap::Parser;
+use eggcc::fuzz::{
+    check_program, parse_fuzz_program, random_bril_program, save_failing, BrilFuzzConfig,
+};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// The seed of the first program. Program `i` uses seed `seed + i`.
+    #[clap(long)]
+    seed: Option<u64>,
+    /// How many programs to generate.
+    #[clap(long)]
+    count: Option<u64>,
+    /// Where to save failing programs.
+    #[clap(long)]
+    out_dir: Option<PathBuf>,
+    /// Maximum nesting depth of branches and loops.
+    #[clap(long)]
+    max_depth: Option<usize>,
+}
+
+fn main() {
+    env_logger::init();
+    let args = Args::parse();
+    let start = args.seed.unwrap_or(0);
+    let count = args.count.unwrap_or(100);
+    let out_dir = args
+        .out_dir
+        .unwrap_or_else(|| PathBuf::from("tests/failing/fuzz"));
+    let mut config = BrilFuzzConfig::default();
+    if let Some(max_depth) = args.max_depth {
+        config.max_depth = max_depth;
+    }
+
+    let mut num_failing = 0;
+    for seed in start..start + count {
+        let name = format!("fuzz_{seed}");
+        let source = random_bril_program(seed, &config);
+        let prog = match parse_fuzz_program(&name, &source) {
+            Ok(prog) => prog,
+            Err(err) => {
+                eprintln!("{name}: generated an invalid program: {err}\n{source}");
+                num_failing += 1;
+                continue;
+            }
+        };
+        let failures = check_program(prog);
+        if failures.is_empty() {
+            continue;
+        }
+        num_failing += 1;
+        let path = save_failing(&out_dir, &name, &source, &failures)
+            .unwrap_or_else(|err| panic!("Failed to save {name}: {err}"));
+        eprintln!("{name} failed, saved to {}", path.display());
+        for failure in failures {
+            eprintln!("  {}: {}", failure.run, failure.problem);
+        }
+    }
+
+    println!("{num_failing} of {count} programs failed");
+    if num_failing > 0 {
+        std::process::exit(1);
+    }
+}
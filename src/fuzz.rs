@@ -0,0 +1,442 @@
+//! Random Bril programs for differential testing, used by the `bril-fuzz` binary.
+//! Programs have loops (some with early exits), branches, calls,
+//! in-bounds memory accesses and prints, and always terminate.
+//! Every configuration from `Run::all_configurations_for` has to print
+//! the same output as brilirs on them.
+
+use std::{
+    fmt::Write,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+};
+
+use dag_in_context::rng::Rng;
+
+use crate::{
+    util::{ProgWithArguments, Run, TestProgram},
+    EggCCError, Optimizer,
+};
+
+pub struct BrilFuzzConfig {
+    /// Maximum nesting depth of branches and loops.
+    pub max_depth: usize,
+    /// Maximum number of functions besides `main`.
+    pub max_functions: usize,
+    /// Maximum number of statements in each block.
+    pub max_statements: usize,
+}
+
+impl Default for BrilFuzzConfig {
+    fn default() -> Self {
+        BrilFuzzConfig {
+            max_depth: 3,
+            max_functions: 2,
+            max_statements: 6,
+        }
+    }
+}
+
+/// A configuration that disagreed with brilirs on a program.
+pub struct FuzzFailure {
+    /// The name of the failing `Run`.
+    pub run: String,
+    pub problem: String,
+}
+
+/// The source of a random Bril program, starting with its `# ARGS:` line.
+pub fn random_bril_program(seed: u64, config: &BrilFuzzConfig) -> String {
+    let mut rng = Rng::new(seed);
+    let mut out = format!("# ARGS: {} {}\n", rng.int(), rng.int());
+    let mut callees = vec![];
+    for i in 0..rng.below(config.max_functions + 1) {
+        let name = format!("f{i}");
+        let mut gen = BrilGen::new(&mut rng, config, &callees, &["x", "y"]);
+        gen.statements(config.max_depth);
+        let result = gen.pick_int();
+        gen.free_scope();
+        gen.line(format!("ret {result};"));
+        writeln!(out, "@{name}(x: int, y: int): int {{").unwrap();
+        out.push_str(&gen.lines.join("\n"));
+        out.push_str("\n}\n");
+        callees.push(name);
+    }
+
+    let mut gen = BrilGen::new(&mut rng, config, &callees, &["a", "b"]);
+    gen.statements(config.max_depth);
+    let result = gen.pick_int();
+    gen.free_scope();
+    gen.line(format!("print {result};"));
+    writeln!(out, "@main(a: int, b: int) {{").unwrap();
+    out.push_str(&gen.lines.join("\n"));
+    out.push_str("\n}\n");
+    out
+}
+
+/// Parses a program from `random_bril_program`.
+pub fn parse_fuzz_program(name: &str, source: &str) -> Result<ProgWithArguments, EggCCError> {
+    Ok(ProgWithArguments {
+        program: Optimizer::parse_bril(source)?,
+        name: name.to_string(),
+        args: Optimizer::parse_bril_args(source),
+        tree_src: None,
+    })
+}
+
+/// Runs every configuration from `Run::all_configurations_for` on `prog`,
+/// comparing interpreted results against brilirs.
+/// Configurations that fail or panic are also reported.
+pub fn check_program(prog: ProgWithArguments) -> Vec<FuzzFailure> {
+    let expected = match Optimizer::interp_bril(&prog.program, prog.args.clone(), None) {
+        Ok(expected) => expected,
+        Err(error) => {
+            return vec![FuzzFailure {
+                run: "brilirs".to_string(),
+                problem: error.to_string(),
+            }]
+        }
+    };
+    let mut failures = vec![];
+    for run in Run::all_configurations_for(TestProgram::Prog(prog)) {
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| run.run()));
+        let problem = match result {
+            Err(_) => "panicked".to_string(),
+            Ok(Err(error)) => error.to_string(),
+            Ok(Ok(result)) => match result.result_interpreted {
+                Some(output) if output != expected => format!(
+                    "Interpreted result does not match expected:\nExpected: {}\nGot: {}",
+                    expected, output
+                ),
+                _ => continue,
+            },
+        };
+        failures.push(FuzzFailure {
+            run: run.name(),
+            problem,
+        });
+    }
+    failures
+}
+
+/// Saves a failing program to `dir` as a new test case, returning its path.
+pub fn save_failing(
+    dir: &Path,
+    name: &str,
+    source: &str,
+    failures: &[FuzzFailure],
+) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{name}.bril"));
+    // the `# ARGS:` line has to stay first
+    let mut contents = source.to_string();
+    for failure in failures {
+        writeln!(contents, "# fails: {}", failure.run).unwrap();
+    }
+    std::fs::write(&path, contents)?;
+    Ok(path)
+}
+
+#[derive(Default)]
+struct Scope {
+    ints: Vec<String>,
+    bools: Vec<String>,
+    /// Allocations and their sizes, freed at the end of the scope.
+    ptrs: Vec<(String, usize)>,
+}
+
+/// Generates the body of one function.
+/// Variables are only used in the block that defines them (or blocks nested in it),
+/// so every use is initialized.
+struct BrilGen<'a> {
+    rng: &'a mut Rng,
+    config: &'a BrilFuzzConfig,
+    callees: &'a [String],
+    lines: Vec<String>,
+    scopes: Vec<Scope>,
+    /// Loop counters and bounds, which are never assigned to.
+    read_only: Vec<String>,
+    /// The exit label of each enclosing loop,
+    /// and the number of scopes outside of its body.
+    loops: Vec<(String, usize)>,
+    next_id: usize,
+}
+
+impl<'a> BrilGen<'a> {
+    fn new(
+        rng: &'a mut Rng,
+        config: &'a BrilFuzzConfig,
+        callees: &'a [String],
+        args: &[&str],
+    ) -> BrilGen<'a> {
+        BrilGen {
+            rng,
+            config,
+            callees,
+            lines: vec![],
+            scopes: vec![Scope {
+                ints: args.iter().map(|arg| arg.to_string()).collect(),
+                ..Default::default()
+            }],
+            read_only: vec![],
+            loops: vec![],
+            next_id: 0,
+        }
+    }
+
+    fn fresh(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{prefix}{}", self.next_id)
+    }
+
+    fn line(&mut self, line: String) {
+        let indent = if line.starts_with('.') { "" } else { "  " };
+        self.lines.push(format!("{indent}{line}"));
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn pick(&mut self, choices: &[String]) -> String {
+        choices[self.rng.below(choices.len())].clone()
+    }
+
+    fn pick_int(&mut self) -> String {
+        let ints = self
+            .scopes
+            .iter()
+            .flat_map(|scope| scope.ints.clone())
+            .collect::<Vec<_>>();
+        self.pick(&ints)
+    }
+
+    fn pick_bool(&mut self) -> String {
+        let bools = self
+            .scopes
+            .iter()
+            .flat_map(|scope| scope.bools.clone())
+            .collect::<Vec<_>>();
+        if bools.is_empty() || self.rng.below(3) == 0 {
+            self.comparison()
+        } else {
+            self.pick(&bools)
+        }
+    }
+
+    /// A new int variable, or sometimes one in scope to assign to again.
+    fn int_dest(&mut self) -> String {
+        let assignable = self
+            .scopes
+            .iter()
+            .flat_map(|scope| scope.ints.clone())
+            .filter(|int| !self.read_only.contains(int))
+            .collect::<Vec<_>>();
+        if !assignable.is_empty() && self.rng.below(3) == 0 {
+            return self.pick(&assignable);
+        }
+        let dest = self.fresh("v");
+        self.scope().ints.push(dest.clone());
+        dest
+    }
+
+    fn constant(&mut self, value: i64) -> String {
+        let dest = self.fresh("c");
+        self.line(format!("{dest}: int = const {value};"));
+        self.scope().ints.push(dest.clone());
+        self.read_only.push(dest.clone());
+        dest
+    }
+
+    fn comparison(&mut self) -> String {
+        let op = ["lt", "le", "eq", "gt", "ge"][self.rng.below(5)];
+        let (l, r) = (self.pick_int(), self.pick_int());
+        let dest = self.fresh("b");
+        self.line(format!("{dest}: bool = {op} {l} {r};"));
+        self.scope().bools.push(dest.clone());
+        dest
+    }
+
+    fn statements(&mut self, depth: usize) {
+        for _ in 0..1 + self.rng.below(self.config.max_statements) {
+            self.statement(depth);
+        }
+    }
+
+    /// Statements in a new scope, freeing its allocations at the end.
+    fn block(&mut self, depth: usize) {
+        self.scopes.push(Scope::default());
+        self.statements(depth);
+        self.free_scope();
+        self.scopes.pop();
+    }
+
+    /// Frees the allocations of the innermost scope,
+    /// which at the end of a function is the function's own scope.
+    fn free_scope(&mut self) {
+        let ptrs = std::mem::take(&mut self.scope().ptrs);
+        for (ptr, _size) in ptrs {
+            self.line(format!("free {ptr};"));
+        }
+    }
+
+    fn statement(&mut self, depth: usize) {
+        match self.rng.below(12) {
+            0 => {
+                let value = self.rng.int();
+                let dest = self.int_dest();
+                self.line(format!("{dest}: int = const {value};"));
+            }
+            1 | 2 => {
+                let op = ["add", "sub", "mul"][self.rng.below(3)];
+                let (l, r) = (self.pick_int(), self.pick_int());
+                let dest = self.int_dest();
+                self.line(format!("{dest}: int = {op} {l} {r};"));
+            }
+            3 => {
+                let l = self.pick_bool();
+                let dest = self.fresh("b");
+                if self.rng.below(3) == 0 {
+                    self.line(format!("{dest}: bool = not {l};"));
+                } else {
+                    let op = ["and", "or"][self.rng.below(2)];
+                    let r = self.pick_bool();
+                    self.line(format!("{dest}: bool = {op} {l} {r};"));
+                }
+                self.scope().bools.push(dest);
+            }
+            4 => {
+                let value = if self.rng.below(4) == 0 {
+                    self.pick_bool()
+                } else {
+                    self.pick_int()
+                };
+                self.line(format!("print {value};"));
+            }
+            5 if depth > 0 => self.branch(depth),
+            6 if depth > 0 => self.bounded_loop(depth),
+            7 if !self.callees.is_empty() => {
+                let callee = self.pick(self.callees);
+                let (x, y) = (self.pick_int(), self.pick_int());
+                let dest = self.int_dest();
+                self.line(format!("{dest}: int = call @{callee} {x} {y};"));
+            }
+            8 => self.alloc(),
+            9 | 10 => self.memory_access(),
+            11 if self.can_break() => {
+                let cond = self.pick_bool();
+                let (exit, _) = self.loops.last().unwrap().clone();
+                let cont = self.fresh(".cont");
+                self.line(format!("br {cond} {exit} {cont};"));
+                self.line(format!("{cont}:"));
+            }
+            _ => {
+                self.comparison();
+            }
+        }
+    }
+
+    fn branch(&mut self, depth: usize) {
+        let cond = self.pick_bool();
+        let (then_label, else_label, end_label) =
+            (self.fresh(".then"), self.fresh(".else"), self.fresh(".end"));
+        self.line(format!("br {cond} {then_label} {else_label};"));
+        for label in [then_label, else_label] {
+            self.line(format!("{label}:"));
+            self.block(depth - 1);
+            self.line(format!("jmp {end_label};"));
+        }
+        self.line(format!("{end_label}:"));
+    }
+
+    /// A loop that runs its body up to four times.
+    fn bounded_loop(&mut self, depth: usize) {
+        let iters = 1 + self.rng.below(4) as i64;
+        let counter = self.fresh("i");
+        self.line(format!("{counter}: int = const 0;"));
+        let bound = self.constant(iters);
+        let one = self.constant(1);
+        let (header, body, exit) = (
+            self.fresh(".loop"),
+            self.fresh(".body"),
+            self.fresh(".exit"),
+        );
+        let cond = self.fresh("b");
+
+        self.line(format!("{header}:"));
+        self.line(format!("{cond}: bool = lt {counter} {bound};"));
+        self.line(format!("br {cond} {body} {exit};"));
+        self.line(format!("{body}:"));
+        self.scope().ints.push(counter.clone());
+        self.read_only.push(counter.clone());
+        self.loops.push((exit.clone(), self.scopes.len()));
+        self.block(depth - 1);
+        self.loops.pop();
+        self.line(format!("{counter}: int = add {counter} {one};"));
+        self.line(format!("jmp {header};"));
+        self.line(format!("{exit}:"));
+    }
+
+    /// Breaking out of the innermost loop is only allowed
+    /// when it doesn't skip freeing an allocation.
+    fn can_break(&self) -> bool {
+        match self.loops.last() {
+            Some((_exit, outer_scopes)) => self.scopes[*outer_scopes..]
+                .iter()
+                .all(|scope| scope.ptrs.is_empty()),
+            None => false,
+        }
+    }
+
+    /// Allocates and initializes an array, so that every load reads a stored value.
+    fn alloc(&mut self) {
+        let size = 1 + self.rng.below(3);
+        let size_var = self.constant(size as i64);
+        let ptr = self.fresh("p");
+        self.line(format!("{ptr}: ptr<int> = alloc {size_var};"));
+        for index in 0..size {
+            let value = self.pick_int();
+            self.store(&ptr, index, &value);
+        }
+        self.scope().ptrs.push((ptr, size));
+    }
+
+    fn memory_access(&mut self) {
+        let ptrs = self
+            .scopes
+            .iter()
+            .flat_map(|scope| scope.ptrs.clone())
+            .collect::<Vec<_>>();
+        if ptrs.is_empty() {
+            return self.alloc();
+        }
+        let (ptr, size) = ptrs[self.rng.below(ptrs.len())].clone();
+        let index = self.rng.below(size);
+        if self.rng.below(2) == 0 {
+            let value = self.pick_int();
+            self.store(&ptr, index, &value);
+        } else {
+            let offset = self.constant(index as i64);
+            let addr = self.fresh("q");
+            self.line(format!("{addr}: ptr<int> = ptradd {ptr} {offset};"));
+            let dest = self.int_dest();
+            self.line(format!("{dest}: int = load {addr};"));
+        }
+    }
+
+    fn store(&mut self, ptr: &str, index: usize, value: &str) {
+        let offset = self.constant(index as i64);
+        let addr = self.fresh("q");
+        self.line(format!("{addr}: ptr<int> = ptradd {ptr} {offset};"));
+        self.line(format!("store {addr} {value};"));
+    }
+}
+
+#[test]
+fn test_random_bril_programs_run() {
+    for seed in 0..20 {
+        let source = random_bril_program(seed, &BrilFuzzConfig::default());
+        let prog = parse_fuzz_program(&format!("fuzz_{seed}"), &source)
+            .unwrap_or_else(|err| panic!("{err} in generated program:\n{source}"));
+        // brilirs fails on errors like leaks or out of bounds accesses
+        Optimizer::interp_bril(&prog.program, prog.args, None).unwrap();
+    }
+}
@@ -3,11 +3,20 @@ use bril_rs::Program;
 
 use cfg::{program_to_cfg, SimpleCfgProgram};
 use conversions::check_for_uninitialized_vars;
-use dag_in_context::interpreter::{interpret_dag_prog, Value};
+use dag_in_context::egraph_dump::EgraphDumpError;
+use dag_in_context::interpreter::{
+    interpret_dag_prog_with_config, InterpretConfig, MemoryCheck, RuntimeError, RuntimeErrorKind,
+    Value,
+};
 use dag_in_context::schema::Constant;
 use ordered_float::OrderedFloat;
 use rvsdg::{RvsdgError, RvsdgProgram};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::tempdir;
 
 use util::Interpretable;
 
@@ -16,6 +25,8 @@ use thiserror::Error;
 pub mod canonicalize_names;
 pub(crate) mod cfg;
 mod conversions;
+pub mod fuzz;
+pub mod reduce;
 pub(crate) mod rvsdg;
 pub mod util;
 
@@ -36,6 +47,44 @@ pub enum EggCCError {
     RvsdgError(RvsdgError),
     #[error("Uninitialized variable {0} used in function {1}")]
     UninitializedVariable(String, String),
+    #[error("{0}")]
+    EgraphDump(EgraphDumpError),
+    #[error("Interpreter error: {0}")]
+    Interpreter(RuntimeError),
+    #[error("Program did not terminate within {0} steps")]
+    DidNotTerminate(u64),
+    #[error("Program did not terminate within {0:?} in brilirs")]
+    BrilirsTimeout(Duration),
+    #[error("Brilirs error: {0}")]
+    Brilirs(String),
+}
+
+/// How long `Optimizer::interp_bril` waits for brilirs before killing it.
+/// Unlike the step limit of the DAG interpreter, this is a wall-clock limit,
+/// since brilirs can't count steps.
+pub const BRILIRS_TIME_LIMIT: Duration = Duration::from_secs(60);
+
+/// The eggcc executable, which `Optimizer::interp_bril` runs brilirs in.
+/// Other binaries and tests built by cargo find it next to them,
+/// or one directory up for the tests in `deps/`.
+fn eggcc_executable() -> PathBuf {
+    let current = std::env::current_exe().expect("couldn't find the current executable");
+    if current.file_stem().and_then(|stem| stem.to_str()) == Some("eggcc") {
+        return current;
+    }
+    let name = format!("eggcc{}", std::env::consts::EXE_SUFFIX);
+    current
+        .ancestors()
+        .skip(1)
+        .take(2)
+        .map(|dir| dir.join(&name))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| {
+            panic!(
+                "couldn't find the eggcc executable next to {}, build it with `cargo build`",
+                current.display()
+            )
+        })
 }
 
 pub struct Optimizer {
@@ -70,6 +119,13 @@ impl Optimizer {
                 {
                     args.push(arg);
                 }
+            } else if first_line.contains("; ARGS:") {
+                for arg in first_line["; ARGS: ".len()..]
+                    .split(' ')
+                    .map(|s| s.to_string())
+                {
+                    args.push(arg);
+                }
             }
         }
         args
@@ -94,21 +150,42 @@ impl Optimizer {
             .collect()
     }
 
+    /// The argument of a tree program's entry function: the program's arguments and the state.
+    pub(crate) fn tree_program_input(args: Vec<String>) -> Value {
+        let mut parsed = Self::parse_arguments(args);
+        // add the state value to the end
+        parsed.push(Value::StateV);
+        Value::Tuple(parsed)
+    }
+
     /// Interpret a program in an `Interpretable` IR.
     /// Returns the printed output of the program and optionally the cycles taken to run the program.
     /// The program should not return a value.
+    /// Fails if an interpreter gives up on a program that does not terminate.
     pub fn interp(
         program: &Interpretable,
         args: Vec<String>,
         profile_out: Option<PathBuf>,
-    ) -> (String, Option<u64>) {
-        match program {
-            Interpretable::Bril(program) => (Self::interp_bril(program, args, profile_out), None),
+    ) -> Result<(String, Option<u64>), EggCCError> {
+        Ok(match program {
+            Interpretable::Bril(program) => (Self::interp_bril(program, args, profile_out)?, None),
             Interpretable::TreeProgram(program) => {
-                let mut parsed = Self::parse_arguments(args);
-                // add the state value to the end
-                parsed.push(Value::StateV);
-                let (val, mut printed) = interpret_dag_prog(program, &Value::Tuple(parsed));
+                let input = Self::tree_program_input(args);
+                // check for leaks too, since brilirs does
+                let config = InterpretConfig {
+                    memory_check: MemoryCheck::Leaks,
+                    ..Default::default()
+                };
+                let (val, mut printed) =
+                    match interpret_dag_prog_with_config(program, &input, &config) {
+                        Ok(res) => res,
+                        Err(err) => match err.kind {
+                            RuntimeErrorKind::OutOfFuel(steps) => {
+                                return Err(EggCCError::DidNotTerminate(steps))
+                            }
+                            _ => return Err(EggCCError::Interpreter(err)),
+                        },
+                    };
                 assert_eq!(val, Value::Tuple(vec![Value::StateV]));
                 // add new line to the end of each line in printed
                 for line in printed.iter_mut() {
@@ -142,50 +219,112 @@ impl Optimizer {
 
                 (String::from_utf8(output).unwrap(), None)
             }
-        }
+        })
     }
 
     /// run the rust interpreter on the program
-    /// without any optimizations
+    /// without any optimizations.
+    /// brilirs has no step limit, so it runs in a child `eggcc interp-bril` process
+    /// that is killed after `BRILIRS_TIME_LIMIT` of wall-clock time.
     pub fn interp_bril(
         program: &Program,
         args: Vec<String>,
         profile_out: Option<PathBuf>,
-    ) -> String {
-        let mut program_out = Vec::new();
+    ) -> Result<String, EggCCError> {
+        Self::interp_bril_with_time_limit(program, args, profile_out, BRILIRS_TIME_LIMIT)
+    }
 
-        match profile_out {
-            Some(path) => {
-                let profile_file = std::fs::File::create(path).unwrap();
+    /// Like `interp_bril`, killing brilirs after `time_limit` instead.
+    pub fn interp_bril_with_time_limit(
+        program: &Program,
+        args: Vec<String>,
+        profile_out: Option<PathBuf>,
+        time_limit: Duration,
+    ) -> Result<String, EggCCError> {
+        // the output goes to files, so that a large output can't fill a pipe
+        // and block brilirs while nobody is reading it
+        let dir = tempdir().expect("couldn't create temp dir");
+        let stdout_path = dir.path().join("stdout");
+        let stderr_path = dir.path().join("stderr");
+        let mut command = Command::new(eggcc_executable());
+        command.arg("interp-bril");
+        if let Some(path) = profile_out {
+            command.arg("--profile-out").arg(path);
+        }
+        let mut child = command
+            .arg("--")
+            .args(&args)
+            .stdin(Stdio::piped())
+            .stdout(File::create(&stdout_path).expect("couldn't create brilirs output file"))
+            .stderr(File::create(&stderr_path).expect("couldn't create brilirs error file"))
+            .spawn()
+            .expect("couldn't start eggcc to run brilirs");
+        // dropping stdin closes it, so brilirs sees the end of the program
+        let written = child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(program.to_string().as_bytes());
 
+        let start = Instant::now();
+        let status = loop {
+            match child.try_wait().expect("couldn't wait for brilirs") {
+                Some(status) => break status,
+                None if start.elapsed() < time_limit => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                None => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(EggCCError::BrilirsTimeout(time_limit));
+                }
+            }
+        };
+
+        if status.success() {
+            written.expect("couldn't send the program to brilirs");
+            Ok(std::fs::read_to_string(&stdout_path).expect("couldn't read brilirs output"))
+        } else {
+            let message = std::fs::read_to_string(&stderr_path).unwrap_or_default();
+            Err(EggCCError::Brilirs(message.trim().to_string()))
+        }
+    }
+
+    /// Runs brilirs on the textual bril program read from `input`,
+    /// writing the program's output to `output`.
+    /// This is what the child process of `interp_bril` runs.
+    pub fn run_brilirs(
+        input: impl Read,
+        output: impl Write,
+        args: &[String],
+        profile_out: Option<PathBuf>,
+    ) -> Result<(), String> {
+        let result = match profile_out {
+            Some(path) => {
+                let profile_file = File::create(path).map_err(|err| err.to_string())?;
                 brilirs::run_input(
-                    std::io::BufReader::new(program.to_string().as_bytes()),
-                    std::io::BufWriter::new(&mut program_out),
-                    &args,
+                    BufReader::new(input),
+                    BufWriter::new(output),
+                    args,
                     true,
                     profile_file,
                     false,
                     true,
                     None,
                 )
-                .expect("brili interp error")
-            }
-            None => {
-                brilirs::run_input(
-                    std::io::BufReader::new(program.to_string().as_bytes()),
-                    std::io::BufWriter::new(&mut program_out),
-                    &args,
-                    false,
-                    std::io::stderr(),
-                    false,
-                    true,
-                    None,
-                )
-                .unwrap();
             }
-        }
-
-        String::from_utf8(program_out).unwrap()
+            None => brilirs::run_input(
+                BufReader::new(input),
+                BufWriter::new(output),
+                args,
+                false,
+                std::io::stderr(),
+                false,
+                true,
+                None,
+            ),
+        };
+        result.map_err(|err| err.to_string())
     }
 
     pub fn parse_bril(program: &str) -> Result<Program, EggCCError> {
@@ -1,7 +1,54 @@
-use clap::Parser;
-use dag_in_context::{EggccConfig, Schedule};
-use eggcc::util::{visualize, InterpMode, LLVMOptLevel, Run, RunMode, TestProgram};
-use std::{ffi::OsStr, i64, iter::once, path::PathBuf};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use dag_in_context::{
+    batching::Batching, extra_rules::ExtraRules, schedule::load_schedule_file, EggccConfig,
+    PassBudget, Schedule,
+};
+use eggcc::reduce::{bril_text, reduce, ReduceCommand};
+use eggcc::util::{
+    visualize, InterpMode, LLVMOptLevel, ProgWithArguments, Run, RunMode, TestProgram,
+};
+use eggcc::Optimizer;
+use std::{ffi::OsStr, i64, iter::once, path::PathBuf, time::Duration};
+
+#[derive(Debug, Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    args: Args,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Shrink a Bril program whose interpreted output changes when it is run
+    /// with the given eggcc flags, and print the smallest such program.
+    Reduce(ReduceArgs),
+    /// Run brilirs on the textual Bril program read from stdin.
+    /// Used by eggcc itself to run brilirs in a process it can kill.
+    #[clap(hide = true)]
+    InterpBril(InterpBrilArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct ReduceArgs {
+    /// The Bril program to reduce. Its arguments are read from its `# ARGS:` line.
+    file: PathBuf,
+    /// The eggcc flags of the failing configuration, e.g. `--run-mode llvm --optimize-egglog true`.
+    /// `--interp` and `--run-data-out` are added for each candidate.
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    eggcc_args: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct InterpBrilArgs {
+    /// Where to write the brilirs profile.
+    #[clap(long)]
+    profile_out: Option<PathBuf>,
+    /// The arguments to the bril program.
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    bril_args: Vec<String>,
+}
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -24,8 +71,10 @@ struct Args {
     #[clap(long)]
     profile_out: Option<PathBuf>,
 
-    /// The bril program to optimize
-    file: PathBuf,
+    /// The program to optimize: a `.bril` file, a `.rs` file,
+    /// or a tree program in the `.tree` format.
+    /// Not needed for `--run-mode extract-from-dump`, which reads the dumps instead.
+    file: Option<PathBuf>,
     /// The arguments to the bril program
     /// (only used when interpreting)
     bril_args: Vec<String>,
@@ -52,6 +101,15 @@ struct Args {
     /// For the eggcc schedule, choose between the sequential and parallel schedules.
     #[clap(long)]
     eggcc_schedule: Option<Schedule>,
+    /// Load the eggcc schedule from a file instead, overriding `eggcc_schedule`.
+    /// Each pass is either `(run-schedule ...)` or `(inline-with-schedule (run-schedule ...))`,
+    /// and may use `helpers`, `cheap-optimizations` and `all-optimizations`.
+    #[clap(long)]
+    schedule_file: Option<PathBuf>,
+    /// An egglog file with extra rules to add to the prologue.
+    /// Rulesets declared in the file join `all-optimizations`.
+    #[clap(long)]
+    extra_rules: Option<PathBuf>,
     /// Eggcc by default performs several passes.
     /// This argument specifies how many passes to run (all passes by default).
     /// If stop_after_n_passes is negative,
@@ -72,18 +130,112 @@ struct Args {
 
     #[clap(long)]
     optimize_function: Option<String>,
+
+    /// How many candidate programs to extract for each function.
+    /// Only used by the `empirical-llvm` run mode, which keeps the fastest one.
+    #[clap(long)]
+    top_k: Option<usize>,
+
+    /// Give up on a pass for a batch of functions when the egraph grows
+    /// past this many tuples, keeping the functions from before the pass.
+    /// egglog stops applying rules once the egraph reaches this size.
+    #[clap(long)]
+    pass_node_budget: Option<usize>,
+    /// Give up on a pass for a batch of functions when running its schedule
+    /// takes longer than this many milliseconds, keeping the functions from before the pass.
+    /// Checked once the schedule finishes.
+    #[clap(long)]
+    pass_time_budget_ms: Option<u64>,
+
+    /// How to split functions into batches that are optimized in separate egraphs.
+    /// Call graph batches are optimized bottom-up, callees first.
+    #[clap(long)]
+    batching: Option<Batching>,
+    /// With `--batching clusters`, the largest total number of expressions
+    /// in the functions of one batch.
+    #[clap(long)]
+    max_batch_size: Option<usize>,
+    /// Number of threads used to optimize independent batches of functions.
+    /// The output does not depend on this.
+    /// Ignored with `--reuse-egraph`.
+    #[clap(long)]
+    jobs: Option<usize>,
+
+    /// Cache optimized functions in this directory, and reuse them when
+    /// the same functions are optimized with the same schedule again.
+    /// Defaults to the `EGGCC_CACHE_DIR` environment variable, if set.
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+    /// Don't read or write the cache, even if a cache directory is given.
+    #[clap(long)]
+    no_cache: bool,
+
+    /// Run consecutive passes on the same egraph instead of
+    /// extracting and rebuilding it after every pass.
+    #[clap(long)]
+    reuse_egraph: bool,
+    /// With `--reuse-egraph`, also extract after these passes (counting from 0),
+    /// e.g. `--egraph-checkpoints 1,3`.
+    #[clap(long, value_delimiter = ',')]
+    egraph_checkpoints: Vec<usize>,
+    /// Include statistics for each egglog pass and batch in the `--run-data-out` file.
+    /// Collecting them slows down optimization.
+    #[clap(long)]
+    pass_stats: bool,
+    /// Write the serialized egraph of each pass and batch to this directory before extracting,
+    /// so that `--run-mode extract-from-dump` can re-run extraction on them.
+    #[clap(long)]
+    dump_egraphs: Option<PathBuf>,
 }
 
 fn main() {
-    let args = Args::parse();
-
     // enable logging
     env_logger::init();
 
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Reduce(reduce_args)) => {
+            reduce_main(reduce_args);
+            return;
+        }
+        Some(Command::InterpBril(interp_args)) => {
+            let result = Optimizer::run_brilirs(
+                std::io::stdin().lock(),
+                std::io::stdout().lock(),
+                &interp_args.bril_args,
+                interp_args.profile_out,
+            );
+            if let Err(message) = result {
+                eprintln!("{message}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+    let args = cli.args;
+
     let start_time = std::time::Instant::now();
 
-    if let Some(debug_dir) = args.debug_dir {
-        if let Result::Err(error) = visualize(TestProgram::BrilFile(args.file.clone()), debug_dir) {
+    let file = match &args.file {
+        Some(path) => Some(match path.extension().and_then(OsStr::to_str) {
+            Some("rs") => TestProgram::RustFile(path.clone()),
+            Some("bril") => TestProgram::BrilFile(path.clone()),
+            Some("tree") => TestProgram::TreeFile(path.clone()),
+            Some(x) => panic!("unexpected file extension {x}"),
+            None => panic!("could not parse file extension"),
+        }),
+        None if args.run_mode == RunMode::ExtractFromDump => None,
+        None => Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                format!("an input file is required for run mode {}", args.run_mode),
+            )
+            .exit(),
+    };
+
+    if let (Some(debug_dir), Some(file)) = (args.debug_dir, &file) {
+        if let Result::Err(error) = visualize(file.clone(), debug_dir) {
             eprintln!("{}", error);
             return;
         }
@@ -97,15 +249,46 @@ fn main() {
         return;
     }
 
-    let file = match args.file.extension().and_then(OsStr::to_str) {
-        Some("rs") => TestProgram::RustFile(args.file.clone()),
-        Some("bril") => TestProgram::BrilFile(args.file.clone()),
-        Some(x) => panic!("unexpected file extension {x}"),
-        None => panic!("could not parse file extension"),
+    let extra_rules = match args.extra_rules.as_deref().map(ExtraRules::load) {
+        Some(Ok(rules)) => Some(rules),
+        Some(Err(error)) => {
+            eprintln!("{}", error);
+            return;
+        }
+        None => None,
+    };
+
+    let custom_schedule = match args
+        .schedule_file
+        .as_deref()
+        .map(|path| load_schedule_file(path, extra_rules.as_ref()))
+    {
+        Some(Ok(passes)) => Some(passes),
+        Some(Err(error)) => {
+            eprintln!("{}", error);
+            return;
+        }
+        None => None,
+    };
+
+    let has_budget = args.pass_node_budget.is_some() || args.pass_time_budget_ms.is_some();
+    let pass_budget = has_budget.then(|| PassBudget {
+        max_nodes: args.pass_node_budget,
+        max_time: args.pass_time_budget_ms.map(Duration::from_millis),
+    });
+
+    let cache_dir = if args.no_cache {
+        None
+    } else {
+        args.cache_dir
+            .or_else(|| std::env::var_os("EGGCC_CACHE_DIR").map(PathBuf::from))
     };
 
     let run = Run {
-        prog_with_args: file.read_program(),
+        prog_with_args: file.map_or_else(
+            || ProgWithArguments::empty("extract-from-dump"),
+            TestProgram::read_program,
+        ),
         test_type: args.run_mode,
         interp: if args.interp {
             InterpMode::Interp
@@ -121,9 +304,21 @@ fn main() {
         add_timing: args.add_timing,
         eggcc_config: EggccConfig {
             schedule: args.eggcc_schedule.unwrap_or(Schedule::default()),
+            custom_schedule,
+            extra_rules,
             stop_after_n_passes: args.stop_after_n_passes.unwrap_or(i64::MAX),
             linearity: !args.no_linearity,
             optimize_functions: args.optimize_function.map(|s| once(s.clone()).collect()),
+            top_k: args.top_k.unwrap_or(1),
+            pass_budget,
+            batching: args.batching.unwrap_or_default(),
+            max_batch_size: args.max_batch_size.unwrap_or(10_000),
+            jobs: args.jobs.unwrap_or(1),
+            cache_dir,
+            reuse_egraph: args.reuse_egraph,
+            checkpoints: args.egraph_checkpoints,
+            collect_stats: args.pass_stats,
+            dump_egraphs: args.dump_egraphs,
         },
     };
 
@@ -161,3 +356,66 @@ fn main() {
         }
     }
 }
+
+/// `eggcc reduce <file.bril> [flags]` shrinks a program whose interpreted output
+/// changes when it is run with the given flags, and prints the smallest such program.
+/// Each candidate is run in a new eggcc process with those flags and `--interp`.
+fn reduce_main(reduce_args: ReduceArgs) {
+    // check the forwarded flags with the same parser that each candidate's process uses
+    let args = Args::parse_from(
+        [
+            "eggcc reduce".into(),
+            reduce_args.file.clone().into_os_string(),
+        ]
+        .into_iter()
+        .chain(reduce_args.eggcc_args.iter().map(Into::into)),
+    );
+    if reduce_args.file.extension().and_then(OsStr::to_str) != Some("bril") {
+        reduce_error(
+            ErrorKind::InvalidValue,
+            "eggcc reduce only works on bril files",
+        );
+    }
+    if !args.run_mode.produces_interpretable() {
+        reduce_error(
+            ErrorKind::InvalidValue,
+            format!(
+                "Cannot reduce with run type {} because it doesn't produce a bril program.",
+                args.run_mode
+            ),
+        );
+    }
+    if !args.bril_args.is_empty() {
+        reduce_error(
+            ErrorKind::ArgumentConflict,
+            "eggcc reduce reads the program's arguments from its `# ARGS:` line",
+        );
+    }
+    if args.interp || args.run_data_out.is_some() || args.debug_dir.is_some() {
+        reduce_error(
+            ErrorKind::ArgumentConflict,
+            "eggcc reduce sets `--interp` and `--run-data-out` itself and doesn't support `--debug-dir`",
+        );
+    }
+
+    let mut command = ReduceCommand {
+        eggcc: std::env::current_exe().unwrap(),
+        args: reduce_args.eggcc_args,
+        timeout: Duration::from_secs(600),
+    };
+    let prog = TestProgram::BrilFile(reduce_args.file).read_program();
+    let start_time = std::time::Instant::now();
+    if !command.still_fails(&prog) {
+        eprintln!("The program's output does not change with these flags, nothing to reduce.");
+        return;
+    }
+    // candidates that take much longer than the original probably loop forever
+    command.timeout = (start_time.elapsed() * 4).max(Duration::from_secs(5));
+
+    let reduced = reduce(prog, |candidate| command.still_fails(candidate));
+    print!("{}", bril_text(&reduced));
+}
+
+fn reduce_error(kind: ErrorKind, message: impl std::fmt::Display) -> ! {
+    Cli::command().error(kind, message).exit()
+}
@@ -0,0 +1,384 @@
+//! Test-case reduction for `eggcc reduce`.
+//! Repeatedly deletes functions, blocks, instructions and arguments from a Bril program,
+//! keeping a deletion only when the program still parses, has no uninitialized
+//! variables, and still fails.
+
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use bril_rs::{Code, EffectOps, Instruction, Program, ValueOps};
+use tempfile::tempdir;
+
+use crate::{
+    util::{ProgWithArguments, RunResult},
+    Optimizer,
+};
+
+/// The program as a Bril file, with its arguments on the `# ARGS:` line.
+pub fn bril_text(prog: &ProgWithArguments) -> String {
+    if prog.args.is_empty() {
+        prog.program.to_string()
+    } else {
+        format!("# ARGS: {}\n{}", prog.args.join(" "), prog.program)
+    }
+}
+
+/// Shrinks `prog` until no single reduction keeps `still_fails` true.
+/// `still_fails` is only called on valid programs, and should be true for `prog`.
+pub fn reduce(
+    prog: ProgWithArguments,
+    mut still_fails: impl FnMut(&ProgWithArguments) -> bool,
+) -> ProgWithArguments {
+    let mut current = prog;
+    loop {
+        let mut progress = false;
+
+        let num_functions = current.program.functions.len();
+        progress |= reduce_each(
+            &mut current,
+            &mut still_fails,
+            num_functions,
+            remove_function,
+        );
+
+        let num_blocks = labels(&current.program).len();
+        progress |= reduce_each(&mut current, &mut still_fails, num_blocks, remove_block);
+
+        // delete large chunks of instructions first, then smaller ones
+        let mut chunk_size = instructions(&current.program).len().next_power_of_two();
+        while chunk_size > 0 {
+            let num_chunks = instructions(&current.program).len().div_ceil(chunk_size);
+            progress |= reduce_each(&mut current, &mut still_fails, num_chunks, |prog, i| {
+                remove_instructions(prog, i * chunk_size, chunk_size)
+            });
+            chunk_size /= 2;
+        }
+
+        // each branch can become a jump to either of its targets
+        let num_branches = branches(&current.program).len();
+        progress |= reduce_each(
+            &mut current,
+            &mut still_fails,
+            num_branches * 2,
+            |prog, i| branch_to_jump(prog, i / 2, i % 2),
+        );
+
+        let num_args = arguments(&current.program).len();
+        progress |= reduce_each(&mut current, &mut still_fails, num_args, remove_argument);
+
+        if !progress {
+            return current;
+        }
+    }
+}
+
+/// Tries `reduce_at(current, i)` for each `i` from `count - 1` down to 0,
+/// keeping the reductions that are valid and still fail.
+/// Reductions at an index never change what lower indices refer to,
+/// so the indices stay meaningful as reductions are kept.
+fn reduce_each(
+    current: &mut ProgWithArguments,
+    still_fails: &mut impl FnMut(&ProgWithArguments) -> bool,
+    count: usize,
+    reduce_at: impl Fn(&ProgWithArguments, usize) -> Option<ProgWithArguments>,
+) -> bool {
+    let mut progress = false;
+    for i in (0..count).rev() {
+        let Some(candidate) = reduce_at(current, i).and_then(validate) else {
+            continue;
+        };
+        if still_fails(&candidate) {
+            log::info!(
+                "Reduced to {} instructions",
+                instructions(&candidate.program).len()
+            );
+            *current = candidate;
+            progress = true;
+        }
+    }
+    progress
+}
+
+/// Checks that the candidate survives printing and parsing,
+/// which also rejects uses of uninitialized variables.
+fn validate(candidate: ProgWithArguments) -> Option<ProgWithArguments> {
+    let program = Optimizer::parse_bril(&bril_text(&candidate)).ok()?;
+    // the reduced Bril program replaces any tree program it came from
+    Some(ProgWithArguments {
+        program,
+        tree_src: None,
+        ..candidate
+    })
+}
+
+/// Removes a function other than `main`, along with the calls to it.
+fn remove_function(prog: &ProgWithArguments, index: usize) -> Option<ProgWithArguments> {
+    if index >= prog.program.functions.len() {
+        return None;
+    }
+    let mut res = prog.clone();
+    let removed = res.program.functions.remove(index);
+    if removed.name == "main" {
+        return None;
+    }
+    for func in &mut res.program.functions {
+        func.instrs.retain(|code| !calls(code, &removed.name));
+    }
+    Some(res)
+}
+
+fn calls(code: &Code, name: &str) -> bool {
+    match code {
+        Code::Instruction(Instruction::Value {
+            op: ValueOps::Call,
+            funcs,
+            ..
+        })
+        | Code::Instruction(Instruction::Effect {
+            op: EffectOps::Call,
+            funcs,
+            ..
+        }) => funcs.iter().any(|func| func == name),
+        _ => false,
+    }
+}
+
+/// The function and position of each label, in program order.
+fn labels(program: &Program) -> Vec<(usize, usize)> {
+    positions(program, |code| matches!(code, Code::Label { .. }))
+}
+
+/// The function and position of each instruction, in program order.
+fn instructions(program: &Program) -> Vec<(usize, usize)> {
+    positions(program, |code| matches!(code, Code::Instruction(_)))
+}
+
+fn branches(program: &Program) -> Vec<(usize, usize)> {
+    positions(program, |code| {
+        matches!(
+            code,
+            Code::Instruction(Instruction::Effect {
+                op: EffectOps::Branch,
+                ..
+            })
+        )
+    })
+}
+
+fn positions(program: &Program, filter: impl Fn(&Code) -> bool) -> Vec<(usize, usize)> {
+    let mut res = vec![];
+    for (func, function) in program.functions.iter().enumerate() {
+        for (pos, code) in function.instrs.iter().enumerate() {
+            if filter(code) {
+                res.push((func, pos));
+            }
+        }
+    }
+    res
+}
+
+/// Removes a labeled block: its label and the code up to the next label.
+fn remove_block(prog: &ProgWithArguments, index: usize) -> Option<ProgWithArguments> {
+    let (func, start) = *labels(&prog.program).get(index)?;
+    let mut res = prog.clone();
+    let instrs = &mut res.program.functions[func].instrs;
+    let end = instrs[start + 1..]
+        .iter()
+        .position(|code| matches!(code, Code::Label { .. }))
+        .map_or(instrs.len(), |len| start + 1 + len);
+    instrs.drain(start..end);
+    Some(res)
+}
+
+/// Removes `len` instructions starting at the `start`th one, skipping labels.
+fn remove_instructions(
+    prog: &ProgWithArguments,
+    start: usize,
+    len: usize,
+) -> Option<ProgWithArguments> {
+    let positions = instructions(&prog.program);
+    if start >= positions.len() {
+        return None;
+    }
+    let mut res = prog.clone();
+    // remove from the back so that earlier positions stay valid
+    for &(func, pos) in positions.iter().skip(start).take(len).rev() {
+        res.program.functions[func].instrs.remove(pos);
+    }
+    Some(res)
+}
+
+/// Replaces a branch with a jump to one of its targets.
+fn branch_to_jump(
+    prog: &ProgWithArguments,
+    index: usize,
+    target: usize,
+) -> Option<ProgWithArguments> {
+    let (func, index) = *branches(&prog.program).get(index)?;
+    let mut res = prog.clone();
+    let Code::Instruction(Instruction::Effect { labels, pos, .. }) =
+        &res.program.functions[func].instrs[index]
+    else {
+        unreachable!("expected a branch");
+    };
+    let jump = Code::Instruction(Instruction::Effect {
+        args: vec![],
+        funcs: vec![],
+        labels: vec![labels.get(target)?.clone()],
+        op: EffectOps::Jump,
+        pos: pos.clone(),
+    });
+    res.program.functions[func].instrs[index] = jump;
+    Some(res)
+}
+
+/// The function and position of each function argument, in program order.
+fn arguments(program: &Program) -> Vec<(usize, usize)> {
+    program
+        .functions
+        .iter()
+        .enumerate()
+        .flat_map(|(func, function)| (0..function.args.len()).map(move |arg| (func, arg)))
+        .collect()
+}
+
+/// Removes an argument from a function and from every call to it.
+/// Removing an argument of `main` also removes its value from the `# ARGS:` line.
+fn remove_argument(prog: &ProgWithArguments, index: usize) -> Option<ProgWithArguments> {
+    let (func, arg) = *arguments(&prog.program).get(index)?;
+    let mut res = prog.clone();
+    let name = res.program.functions[func].name.clone();
+    res.program.functions[func].args.remove(arg);
+    if name == "main" {
+        if arg >= res.args.len() {
+            return None;
+        }
+        res.args.remove(arg);
+    }
+    for function in &mut res.program.functions {
+        for code in &mut function.instrs {
+            if calls(code, &name) {
+                let (Code::Instruction(Instruction::Value { args, .. })
+                | Code::Instruction(Instruction::Effect { args, .. })) = code
+                else {
+                    unreachable!("expected a call");
+                };
+                if arg < args.len() {
+                    args.remove(arg);
+                }
+            }
+        }
+    }
+    Some(res)
+}
+
+/// Runs eggcc on candidates in a subprocess,
+/// so that candidates which no longer terminate can be killed.
+pub struct ReduceCommand {
+    /// The eggcc executable.
+    pub eggcc: PathBuf,
+    /// Flags that select the failing configuration. The input file,
+    /// `--interp` and `--run-data-out` are added for each candidate.
+    pub args: Vec<String>,
+    /// How long to wait for each candidate.
+    pub timeout: Duration,
+}
+
+impl ReduceCommand {
+    /// Whether the configuration's interpreted output differs from brilirs on `prog`.
+    /// Crashes and timeouts don't count, since the program being reduced
+    /// has to keep running to be a reproducer.
+    pub fn still_fails(&self, prog: &ProgWithArguments) -> bool {
+        let dir = tempdir().expect("couldn't create temp dir");
+        let file = dir.path().join(format!("{}.bril", prog.name));
+        let run_data = dir.path().join("run-data.json");
+        std::fs::write(&file, bril_text(prog)).expect("couldn't write candidate");
+
+        let Ok(mut child) = Command::new(&self.eggcc)
+            .args(&self.args)
+            .arg("--interp")
+            .arg("--run-data-out")
+            .arg(&run_data)
+            .arg(&file)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            return false;
+        };
+        let start = Instant::now();
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) if status.success() => break,
+                Ok(None) if start.elapsed() < self.timeout => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Ok(None) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return false;
+                }
+                _ => return false,
+            }
+        }
+
+        let Ok(contents) = std::fs::read_to_string(&run_data) else {
+            return false;
+        };
+        let Ok(result) = serde_json::from_str::<RunResult>(&contents) else {
+            return false;
+        };
+        matches!(
+            (result.original_interpreted, result.result_interpreted),
+            (Some(original), Some(result)) if original != result
+        )
+    }
+}
+
+#[test]
+fn test_reduce() {
+    let source = "# ARGS: 3 4
+@unused(x: int): int {
+  ret x;
+}
+@main(a: int, b: int) {
+  c: int = const 7;
+  d: int = add a b;
+  e: bool = lt a b;
+  br e .then .else;
+.then:
+  print d;
+  jmp .end;
+.else:
+  print a;
+.end:
+  u: int = call @unused a;
+  print c;
+}
+";
+    let prog = ProgWithArguments {
+        program: Optimizer::parse_bril(source).unwrap(),
+        name: "reduce_test".to_string(),
+        args: Optimizer::parse_bril_args(source),
+        tree_src: None,
+    };
+    let prints_seven = |prog: &ProgWithArguments| {
+        let output = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Optimizer::interp_bril(&prog.program, prog.args.clone(), None)
+        }));
+        matches!(output, Ok(Ok(output)) if output.lines().any(|line| line == "7"))
+    };
+    assert!(prints_seven(&prog));
+
+    let reduced = reduce(prog, prints_seven);
+    assert!(prints_seven(&reduced));
+    assert_eq!(reduced.program.functions.len(), 1);
+    assert!(
+        instructions(&reduced.program).len() <= 3,
+        "not fully reduced:\n{}",
+        bril_text(&reduced)
+    );
+}
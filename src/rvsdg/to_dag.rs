@@ -409,7 +409,7 @@ fn dag_translation_test(
 
     assert_progs_eq(&result, &expected, "Resulting program is incorrect");
 
-    let (found_val, found_printlog) = interpret_dag_prog(&expected, &input_val);
+    let (found_val, found_printlog) = interpret_dag_prog(&expected, &input_val).unwrap();
     assert_eq!(
         expected_val, found_val,
         "Reference program produced incorrect result. Expected {:?}, found {:?}",
@@ -421,7 +421,7 @@ fn dag_translation_test(
         expected_printlog, found_printlog
     );
 
-    let (found_val, found_printlog) = interpret_dag_prog(&result, &input_val);
+    let (found_val, found_printlog) = interpret_dag_prog(&result, &input_val).unwrap();
     assert_eq!(
         expected_val, found_val,
         "Resulting program produced incorrect result. Expected {:?}, found {:?}",
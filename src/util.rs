@@ -4,10 +4,19 @@ use crate::{EggCCError, Optimizer};
 use bril_rs::Program;
 use clap::ValueEnum;
 use dag_in_context::dag2svg::tree_to_svg;
+use dag_in_context::dynamic_cost::profile_dag_prog;
+use dag_in_context::egraph_dump::{dumps_in_dir, EgraphDump};
+use dag_in_context::interpreter::InterpretConfig;
+use dag_in_context::pass_stats::PassStats;
 use dag_in_context::schedule::{self};
-use dag_in_context::{build_program, check_roundtrip_egraph, EggccConfig, Schedule};
+use dag_in_context::{
+    build_program, build_program_with_extra_rules, check_roundtrip_egraph, DefaultCostModel,
+    EggccConfig, Schedule,
+};
 
 use dag_in_context::schema::TreeProgram;
+use dag_in_context::tree_diff::tree_diff;
+use dag_in_context::tree_parser::parse_tree_program;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::fs::File;
@@ -15,6 +24,7 @@ use std::io::{Read, Write};
 use std::process::{Command, Stdio};
 use std::time::{Duration, Instant};
 use std::{
+    collections::HashSet,
     ffi::OsStr,
     fmt::{Display, Formatter},
     io,
@@ -57,6 +67,37 @@ pub(crate) fn parse_from_string(input: &str) -> bril_rs::Program {
     load_program_from_read(json_str.as_bytes())
 }
 
+/// Whether the program prints a pointer anywhere.
+/// Only the interpreters can print pointers; brilift and LLVM can't.
+fn prints_pointers(program: &Program) -> bool {
+    use bril_rs::{Code, EffectOps, Instruction, Type};
+    program.functions.iter().any(|func| {
+        let mut pointers = func
+            .args
+            .iter()
+            .filter(|arg| matches!(arg.arg_type, Type::Pointer(_)))
+            .map(|arg| arg.name.as_str())
+            .collect::<HashSet<_>>();
+        for code in &func.instrs {
+            if let Code::Instruction(Instruction::Value {
+                dest,
+                op_type: Type::Pointer(_),
+                ..
+            }) = code
+            {
+                pointers.insert(dest.as_str());
+            }
+        }
+        func.instrs.iter().any(|code| {
+            matches!(code, Code::Instruction(Instruction::Effect {
+                op: EffectOps::Print,
+                args,
+                ..
+            }) if args.iter().any(|arg| pointers.contains(arg.as_str())))
+        })
+    })
+}
+
 /// Write the visualizations to output files in the output directory.
 /// If the directory does not exist, it creates it.
 /// If the directory contains any files whose names conflict with the
@@ -217,6 +258,30 @@ pub enum RunMode {
     OptimizedPrettyPrint,
     /// Convert the input bril program to pretty-printed rust macro
     PrettyPrint,
+    /// Convert the input bril program to a tree-encoded expression and output it as JSON,
+    /// in the encoding of `dag_in_context::tree_json`.
+    TreeJson,
+    /// Like `TreeJson`, but output the tree-encoded program after optimizing it with egglog.
+    OptimizedTreeJson,
+    /// Optimize the tree-encoded program and diff it with the original, function by function.
+    /// Outputs the added, removed and moved sub-expressions as text, and SVGs of the program
+    /// before and after with the changes highlighted.
+    TreeDiff,
+    /// Optimize the tree-encoded program and explain the final extraction:
+    /// costs broken down by region and operator, and the alternatives
+    /// considered for each function body and its most expensive loops.
+    /// Outputs the report as JSON and as a text summary.
+    ExtractionReport,
+    /// Re-run extraction on the egraphs written by `--dump-egraphs`,
+    /// reading them from the directory given to that flag instead of optimizing the input.
+    /// The input file is optional for this mode, and ignored if given.
+    /// Outputs the cost and the pretty-printed program extracted from each dump.
+    ExtractFromDump,
+    /// Interpret the tree-encoded program before and after optimization on the program's
+    /// arguments, counting how often each function and expression is evaluated.
+    /// Outputs the calls and dynamic cost of each function under the default cost model,
+    /// and the iterations of each loop.
+    DynamicCost,
     /// Give the egglog program used to optimize the tree-encoded expression.
     Egglog,
     /// Check that converting the tree program to egglog
@@ -251,6 +316,12 @@ pub enum RunMode {
     /// Converts to an executable using brillvm.
     /// `optimize_egglog` and `optimize_bril_llvm` must be set.
     LLVM,
+    /// Like `LLVM` with egglog optimization, but extracts the `top_k` best
+    /// programs for each function according to the cost model.
+    /// Each candidate is compiled with LLVM and timed, and the fastest is kept.
+    /// Candidates whose output differs from the original program are rejected.
+    /// `optimize_bril_llvm` must be set.
+    EmpiricalLLVM,
     /// Tests a benchmark by running several different configurations of CompileBrilLLVM
     /// and comparing the results.
     /// The different configurations are with and without egglog optimization, and with and without
@@ -281,7 +352,8 @@ impl RunMode {
             | RunMode::DagConversion
             | RunMode::DagOptimize
             | RunMode::Cranelift
-            | RunMode::LLVM => true,
+            | RunMode::LLVM
+            | RunMode::EmpiricalLLVM => true,
             RunMode::RvsdgConversion
             | RunMode::RvsdgToCfg
             | RunMode::Egglog
@@ -290,6 +362,12 @@ impl RunMode {
             | RunMode::CheckExtractIdentical
             | RunMode::OptimizedPrettyPrint
             | RunMode::PrettyPrint
+            | RunMode::TreeJson
+            | RunMode::OptimizedTreeJson
+            | RunMode::TreeDiff
+            | RunMode::ExtractionReport
+            | RunMode::DynamicCost
+            | RunMode::ExtractFromDump
             | RunMode::ToCfg
             | RunMode::OptimizedCfg
             | RunMode::TestPrettyPrint
@@ -302,11 +380,30 @@ impl RunMode {
 #[derive(Clone, Debug)]
 pub struct ProgWithArguments {
     pub program: Program,
-    name: String,
-    args: Vec<String>,
+    pub(crate) name: String,
+    pub(crate) args: Vec<String>,
+    /// For `.tree` files, the source of the tree program.
+    /// Modes that work on trees start from this program, while `program`
+    /// (the tree lowered to Bril) is used for Bril-level modes and as the
+    /// reference when interpreting.
+    pub(crate) tree_src: Option<String>,
 }
 
 impl ProgWithArguments {
+    /// A program without functions, for run modes that don't read
+    /// the input program (`RunMode::ExtractFromDump`).
+    pub fn empty(name: &str) -> ProgWithArguments {
+        ProgWithArguments {
+            program: Program {
+                functions: vec![],
+                imports: vec![],
+            },
+            name: name.to_string(),
+            args: vec![],
+            tree_src: None,
+        }
+    }
+
     pub(crate) fn to_viz(&self) -> Visualization {
         Visualization {
             result: "# ARGS: ".to_string()
@@ -324,6 +421,8 @@ pub enum TestProgram {
     Prog(ProgWithArguments),
     BrilFile(PathBuf),
     RustFile(PathBuf),
+    /// A tree program in the `.tree` format of `dag_in_context::tree_parser`.
+    TreeFile(PathBuf),
 }
 
 impl TestProgram {
@@ -340,6 +439,7 @@ impl TestProgram {
                     program,
                     name,
                     args,
+                    tree_src: None,
                 }
             }
             TestProgram::RustFile(path) => {
@@ -356,6 +456,21 @@ impl TestProgram {
                     program,
                     name,
                     args,
+                    tree_src: None,
+                }
+            }
+            TestProgram::TreeFile(path) => {
+                let src = std::fs::read_to_string(path.clone()).unwrap();
+                let args = Optimizer::parse_bril_args(&src);
+                let tree = parse_tree_program(&src)
+                    .unwrap_or_else(|err| panic!("{}: {err}", path.display()));
+                let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+
+                ProgWithArguments {
+                    program: Run::tree_to_bril(&tree),
+                    name,
+                    args,
+                    tree_src: Some(src),
                 }
             }
         }
@@ -453,20 +568,112 @@ pub struct RunResult {
     // eggcc_compile_time is filled out by main.rs
     // so that we don't miss any time spent in the main function
     pub eggcc_compile_time: Duration,
+    // statistics for each egglog pass, only collected with --pass-stats
+    #[serde(default)]
+    pub pass_stats: Vec<PassStats>,
 }
 
 impl Run {
-    fn optimize_bril(program: &Program, config: &EggccConfig) -> Result<Program, EggCCError> {
-        let rvsdg = Optimizer::program_to_rvsdg(program)?;
-        let dag = rvsdg.to_dag_encoding();
-        let optimized = dag_in_context::optimize(&dag, config).map_err(EggCCError::EggLog)?;
-        let rvsdg2 = dag_to_rvsdg(&optimized);
-        let cfg = rvsdg2.to_cfg();
+    /// The tree program that the tree-level modes start from:
+    /// the parsed `.tree` file, or the tree encoding of the Bril program.
+    fn original_dag(&self) -> Result<TreeProgram, EggCCError> {
+        match &self.prog_with_args.tree_src {
+            Some(src) => {
+                Ok(parse_tree_program(src).expect("tree file was parsed when it was read"))
+            }
+            None => {
+                Ok(Optimizer::program_to_rvsdg(&self.prog_with_args.program)?.to_dag_encoding())
+            }
+        }
+    }
+
+    /// Optimizes the original program with egglog and lowers the result to Bril.
+    fn optimize_bril(&self) -> Result<(Program, Vec<PassStats>), EggCCError> {
+        let dag = self.original_dag()?;
+        let (optimized, pass_stats) = dag_in_context::optimize_with_stats(&dag, &self.eggcc_config)
+            .map_err(EggCCError::EggLog)?;
+        Ok((Run::tree_to_bril(&optimized), pass_stats))
+    }
+
+    fn tree_to_bril(tree: &TreeProgram) -> Program {
+        let rvsdg = dag_to_rvsdg(tree);
+        let cfg = rvsdg.to_cfg();
         let bril = cfg.to_bril();
         // re-name variables in the bril, hiding our nondeterminism bug ):
-        let bril = canonicalize_bril(&bril);
+        canonicalize_bril(&bril)
+    }
+
+    /// Optimizes the program, trying the top-k candidates found by extraction
+    /// for each function and keeping the one that runs fastest.
+    /// Functions are considered one at a time, starting from the program
+    /// made of the best candidates according to the cost model.
+    /// If that program's output differs from the original program,
+    /// the original program is returned unoptimized.
+    fn empirically_optimize_bril(&self, llvm_level: LLVMOptLevel) -> Result<Program, EggCCError> {
+        let dag = self.original_dag()?;
+        let (mut best, candidates) =
+            dag_in_context::optimize_with_candidates(&dag, &self.eggcc_config)
+                .map_err(EggCCError::EggLog)?;
+
+        let expected = Optimizer::interp_bril(
+            &self.prog_with_args.program,
+            self.prog_with_args.args.clone(),
+            None,
+        )?;
+        let Some(mut best_cycles) = self.time_candidate(&best, &expected, llvm_level)? else {
+            eprintln!(
+                "Warning: optimized program for {} does not match the original program, using the unoptimized program.",
+                self.name()
+            );
+            return Ok(self.prog_with_args.program.clone());
+        };
 
-        Ok(bril)
+        for (func, func_candidates) in candidates {
+            // the first candidate is the one already in `best`
+            for (i, candidate) in func_candidates.into_iter().enumerate().skip(1) {
+                let mut trial = best.clone();
+                trial.replace_fn(&func, candidate);
+                match self.time_candidate(&trial, &expected, llvm_level)? {
+                    Some(cycles) if cycles < best_cycles => {
+                        log::info!(
+                            "Candidate {i} for {func} is faster: {cycles} cycles vs {best_cycles}"
+                        );
+                        best = trial;
+                        best_cycles = cycles;
+                    }
+                    Some(_) => {}
+                    None => {
+                        eprintln!(
+                            "Warning: rejected candidate {i} for {func}, output differs from the original program."
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(Run::tree_to_bril(&best))
+    }
+
+    /// Lowers the candidate to an executable and returns the cycles it takes to run,
+    /// or `None` if its output differs from `expected`.
+    fn time_candidate(
+        &self,
+        candidate: &TreeProgram,
+        expected: &str,
+        llvm_level: LLVMOptLevel,
+    ) -> Result<Option<u64>, EggCCError> {
+        let bril = Run::tree_to_bril(candidate);
+        let args = self.prog_with_args.args.clone();
+        if Optimizer::interp_bril(&bril, args.clone(), None)? != expected {
+            return Ok(None);
+        }
+
+        let (executable, _llvm_time) = self.run_bril_llvm(bril, llvm_level, true)?;
+        let (output, cycles) = Optimizer::interp(&executable, args, None)?;
+        if output != expected {
+            return Ok(None);
+        }
+        Ok(cycles)
     }
 
     pub fn compile_brilift_config(
@@ -540,15 +747,20 @@ impl Run {
         seq.eggcc_config.schedule = Schedule::Sequential;
         res.push(seq);
 
+        // the native backends can't print pointers
+        let native = !prints_pointers(&prog.program);
+
         // run a cranelift baseline
-        res.push(Run::compile_brilift_config(
-            test.clone(),
-            true,
-            InterpMode::Interp,
-        ));
+        if native {
+            res.push(Run::compile_brilift_config(
+                test.clone(),
+                true,
+                InterpMode::Interp,
+            ));
+        }
 
         #[cfg(feature = "llvm")]
-        {
+        if native {
             for optimize_egglog in [true, false] {
                 for optimize_llvm in [LLVMOptLevel::O0_O0, LLVMOptLevel::O3_O0] {
                     res.push(Run {
@@ -593,6 +805,9 @@ impl Run {
             Schedule::Parallel => "",
             Schedule::Sequential => "-sequential",
         };
+        if self.eggcc_config.custom_schedule.is_some() {
+            name += "-custom-schedule";
+        }
 
         name
     }
@@ -603,7 +818,7 @@ impl Run {
                 &self.prog_with_args.program,
                 self.prog_with_args.args.clone(),
                 None,
-            ))
+            )?)
         } else if self.interp == InterpMode::InterpFast {
             let interpretable = self.run_brilift(self.prog_with_args.program.clone(), true);
             let res = Some(
@@ -611,7 +826,7 @@ impl Run {
                     interpretable.as_ref().unwrap(),
                     self.prog_with_args.args.clone(),
                     None,
-                )
+                )?
                 .0,
             );
 
@@ -625,6 +840,7 @@ impl Run {
         };
 
         let mut llvm_compile_time = Duration::from_millis(0);
+        let mut pass_stats = vec![];
         let (visualizations, interpretable_out) = match self.test_type {
             RunMode::Parse => (
                 vec![self.prog_with_args.to_viz()],
@@ -666,6 +882,7 @@ impl Run {
                     program: bril.clone(),
                     name: self.prog_with_args.name.clone(),
                     args: self.prog_with_args.args.clone(),
+                    tree_src: None,
                 };
                 (
                     vec![prog_with_args.to_viz()],
@@ -677,13 +894,12 @@ impl Run {
                 let cfg = rvsdg.to_cfg();
                 let bril = cfg.to_bril();
                 let (interpretable, llvm_time) =
-                    self.run_bril_llvm(bril, false, LLVMOptLevel::O0_O0, self.add_timing)?;
+                    self.run_bril_llvm(bril, LLVMOptLevel::O0_O0, self.add_timing)?;
                 llvm_compile_time = llvm_time;
                 (vec![], Some(interpretable))
             }
             RunMode::DagToRvsdg => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
-                let dag = rvsdg.to_dag_encoding();
+                let dag = self.original_dag()?;
                 let rvsdg2 = dag_to_rvsdg(&dag);
                 (
                     vec![Visualization {
@@ -695,8 +911,7 @@ impl Run {
                 )
             }
             RunMode::DagRoundTrip => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
-                let dag = rvsdg.to_dag_encoding();
+                let dag = self.original_dag()?;
                 let rvsdg2 = dag_to_rvsdg(&dag);
                 let cfg = rvsdg2.to_cfg();
                 let bril = cfg.to_bril();
@@ -704,6 +919,7 @@ impl Run {
                     program: bril.clone(),
                     name: self.prog_with_args.name.clone(),
                     args: self.prog_with_args.args.clone(),
+                    tree_src: None,
                 };
                 (
                     vec![prog_with_args.to_viz()],
@@ -711,17 +927,18 @@ impl Run {
                 )
             }
             RunMode::CheckExtractIdentical => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
-                let tree = rvsdg.to_dag_encoding();
+                let tree = self.original_dag()?;
                 check_roundtrip_egraph(&tree);
                 (vec![], None)
             }
             RunMode::Optimize => {
-                let bril = Run::optimize_bril(&self.prog_with_args.program, &self.eggcc_config)?;
+                let bril;
+                (bril, pass_stats) = self.optimize_bril()?;
                 let new_prog_with_args = ProgWithArguments {
                     program: bril.clone(),
                     name: self.prog_with_args.name.clone(),
                     args: self.prog_with_args.args.clone(),
+                    tree_src: None,
                 };
                 (
                     vec![new_prog_with_args.to_viz()],
@@ -729,8 +946,7 @@ impl Run {
                 )
             }
             RunMode::PrettyPrint => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
-                let dag = rvsdg.to_dag_encoding();
+                let dag = self.original_dag()?;
                 let res = TreeProgram::pretty_print_to_rust(&dag);
                 (
                     vec![Visualization {
@@ -742,8 +958,7 @@ impl Run {
                 )
             }
             RunMode::OptimizedPrettyPrint => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
-                let prog = rvsdg.to_dag_encoding();
+                let prog = self.original_dag()?;
                 let optimized = dag_in_context::optimize(&prog, &self.eggcc_config)
                     .map_err(EggCCError::EggLog)?;
                 let res = TreeProgram::pretty_print_to_rust(&optimized);
@@ -756,10 +971,118 @@ impl Run {
                     None,
                 )
             }
+            RunMode::TreeJson => {
+                let tree = self.original_dag()?;
+                (
+                    vec![Visualization {
+                        result: serde_json::to_string_pretty(&tree).unwrap(),
+                        file_extension: ".json".to_string(),
+                        name: "".to_string(),
+                    }],
+                    None,
+                )
+            }
+            RunMode::OptimizedTreeJson => {
+                let tree = self.original_dag()?;
+                let optimized = dag_in_context::optimize(&tree, &self.eggcc_config)
+                    .map_err(EggCCError::EggLog)?;
+                (
+                    vec![Visualization {
+                        result: serde_json::to_string_pretty(&optimized).unwrap(),
+                        file_extension: ".json".to_string(),
+                        name: "".to_string(),
+                    }],
+                    None,
+                )
+            }
+            RunMode::TreeDiff => {
+                let tree = self.original_dag()?;
+                let optimized = dag_in_context::optimize(&tree, &self.eggcc_config)
+                    .map_err(EggCCError::EggLog)?;
+                let diff = tree_diff(&tree, &optimized);
+                (
+                    vec![
+                        Visualization {
+                            result: diff.to_string(),
+                            file_extension: ".txt".to_string(),
+                            name: "diff".to_string(),
+                        },
+                        Visualization {
+                            result: diff.before_svg(),
+                            file_extension: ".svg".to_string(),
+                            name: "before".to_string(),
+                        },
+                        Visualization {
+                            result: diff.after_svg(),
+                            file_extension: ".svg".to_string(),
+                            name: "after".to_string(),
+                        },
+                    ],
+                    None,
+                )
+            }
+            RunMode::ExtractionReport => {
+                let tree = self.original_dag()?;
+                let (_optimized, report) =
+                    dag_in_context::optimize_with_report(&tree, &self.eggcc_config)
+                        .map_err(EggCCError::EggLog)?;
+                (
+                    vec![
+                        Visualization {
+                            result: serde_json::to_string_pretty(&report).unwrap(),
+                            file_extension: ".json".to_string(),
+                            name: "report".to_string(),
+                        },
+                        Visualization {
+                            result: report.to_string(),
+                            file_extension: ".txt".to_string(),
+                            name: "summary".to_string(),
+                        },
+                    ],
+                    None,
+                )
+            }
+            RunMode::DynamicCost => {
+                let tree = self.original_dag()?;
+                let optimized = dag_in_context::optimize(&tree, &self.eggcc_config)
+                    .map_err(EggCCError::EggLog)?;
+                let input = Optimizer::tree_program_input(self.prog_with_args.args.clone());
+                let mut visualizations = vec![];
+                for (name, program) in [("original", &tree), ("optimized", &optimized)] {
+                    let (_value, _log, profile) =
+                        profile_dag_prog(program, &input, &InterpretConfig::default())
+                            .map_err(EggCCError::Interpreter)?;
+                    visualizations.push(Visualization {
+                        result: profile.summary(&DefaultCostModel),
+                        file_extension: ".txt".to_string(),
+                        name: name.to_string(),
+                    });
+                }
+                (visualizations, None)
+            }
+            RunMode::ExtractFromDump => {
+                let dir = self.eggcc_config.dump_egraphs.as_ref().expect(
+                    "dump_egraphs is a required flag when running RunMode::ExtractFromDump",
+                );
+                let mut visualizations = vec![];
+                for path in dumps_in_dir(dir).map_err(EggCCError::EgraphDump)? {
+                    let dump = EgraphDump::load(&path).map_err(EggCCError::EgraphDump)?;
+                    let (extracted, cost) = dump.extract();
+                    visualizations.push(Visualization {
+                        result: format!(
+                            "// pass {}, batch {:?}, cost {cost}\n{}",
+                            dump.pass,
+                            dump.batch,
+                            TreeProgram::pretty_print_to_rust(&extracted)
+                        ),
+                        file_extension: ".rs".to_string(),
+                        name: path.file_stem().unwrap().to_string_lossy().to_string(),
+                    });
+                }
+                (visualizations, None)
+            }
             RunMode::TestPrettyPrint => {
-                let rvsdg =
-                    crate::Optimizer::program_to_rvsdg(&self.prog_with_args.program).unwrap();
-                let tree = rvsdg.to_dag_encoding();
+                let tree = self.original_dag().unwrap();
                 let unfolded_program = build_program(&tree, None, &tree.fns(), "");
                 let folded_program = tree.pretty_print_to_egglog();
                 let program =
@@ -771,8 +1094,7 @@ impl Run {
                 (vec![], None)
             }
             RunMode::DagConversion => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
-                let tree = rvsdg.to_dag_encoding();
+                let tree = self.original_dag()?;
                 (
                     vec![Visualization {
                         result: tree_to_svg(&tree),
@@ -783,8 +1105,7 @@ impl Run {
                 )
             }
             RunMode::DagOptimize => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
-                let tree = rvsdg.to_dag_encoding();
+                let tree = self.original_dag()?;
                 let optimized = dag_in_context::optimize(&tree, &self.eggcc_config)
                     .map_err(EggCCError::EggLog)?;
                 (
@@ -797,8 +1118,7 @@ impl Run {
                 )
             }
             RunMode::OptimizedRvsdg => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
-                let dag = rvsdg.to_dag_encoding();
+                let dag = self.original_dag()?;
                 let optimized = dag_in_context::optimize(&dag, &self.eggcc_config)
                     .map_err(EggCCError::EggLog)?;
                 let rvsdg = dag_to_rvsdg(&optimized);
@@ -812,9 +1132,8 @@ impl Run {
                 )
             }
             RunMode::Egglog => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
-                let dag = rvsdg.to_dag_encoding();
-                let schedules = self.eggcc_config.schedule.get_schedule_list();
+                let dag = self.original_dag()?;
+                let schedules = self.eggcc_config.get_schedule_list();
 
                 // how many actual passes to run
                 let cutoff = self.eggcc_config.get_normalized_cutoff(schedules.len());
@@ -834,11 +1153,12 @@ impl Run {
                     schedule::CompilerPass::InlineWithSchedule(_) => Some(&optimized),
                 };
 
-                let egglog = build_program(
+                let egglog = build_program_with_extra_rules(
                     &optimized,
                     inline_program,
                     &dag.fns(),
                     last_schedule_step.egglog_schedule(),
+                    self.eggcc_config.extra_rules.as_ref(),
                 );
                 (
                     vec![Visualization {
@@ -865,6 +1185,7 @@ impl Run {
                     program: bril.clone(),
                     name: self.prog_with_args.name.clone(),
                     args: self.prog_with_args.args.clone(),
+                    tree_src: None,
                 };
                 (
                     vec![prog_with_args.to_viz()],
@@ -879,6 +1200,7 @@ impl Run {
                     program: bril.clone(),
                     name: self.prog_with_args.name.clone(),
                     args: self.prog_with_args.args.clone(),
+                    tree_src: None,
                 };
                 (
                     vec![prog_with_args.to_viz()],
@@ -900,12 +1222,25 @@ impl Run {
                 let optimize_brillvm = self.optimize_bril_llvm.expect(
                     "optimize_bril_llvm is a required flag when running RunMode::CompileBrilLLVM",
                 );
-                let (interpretable, llvm_time) = self.run_bril_llvm(
-                    self.prog_with_args.program.clone(),
-                    optimize_egglog,
-                    optimize_brillvm,
-                    self.add_timing,
-                )?;
+                let bril = if optimize_egglog {
+                    let bril;
+                    (bril, pass_stats) = self.optimize_bril()?;
+                    bril
+                } else {
+                    self.prog_with_args.program.clone()
+                };
+                let interpretable;
+                (interpretable, llvm_compile_time) =
+                    self.run_bril_llvm(bril, optimize_brillvm, self.add_timing)?;
+                (vec![], Some(interpretable))
+            }
+            RunMode::EmpiricalLLVM => {
+                let optimize_brillvm = self.optimize_bril_llvm.expect(
+                    "optimize_bril_llvm is a required flag when running RunMode::EmpiricalLLVM",
+                );
+                let bril = self.empirically_optimize_bril(optimize_brillvm)?;
+                let (interpretable, llvm_time) =
+                    self.run_bril_llvm(bril, optimize_brillvm, self.add_timing)?;
                 llvm_compile_time = llvm_time;
                 (vec![], Some(interpretable))
             }
@@ -921,11 +1256,11 @@ impl Run {
                     &cranelift_interpretable,
                     self.prog_with_args.args.clone(),
                     None,
-                );
+                )?;
 
                 for optimize_egglog in [true, false] {
                     let resulting_bril = if optimize_egglog {
-                        Run::optimize_bril(&self.prog_with_args.program, &self.eggcc_config)?
+                        self.optimize_bril()?.0
                     } else {
                         self.prog_with_args.program.clone()
                     };
@@ -933,7 +1268,6 @@ impl Run {
                     for optimize_llvm in [LLVMOptLevel::O0_O0, LLVMOptLevel::O3_O0] {
                         let (interpretable, _time) = self.run_bril_llvm(
                             resulting_bril.clone(),
-                            false,
                             optimize_llvm,
                             self.add_timing,
                         )?;
@@ -941,7 +1275,7 @@ impl Run {
                             &interpretable,
                             self.prog_with_args.args.clone(),
                             None,
-                        );
+                        )?;
                         if interpreted != new_interpreted {
                             panic!(
                                     "Interpreted outputs differ for {} with optimize_egglog={} and optimize_llvm={}.",
@@ -969,7 +1303,7 @@ impl Run {
                 &interpretable_out,
                 self.prog_with_args.args.clone(),
                 self.profile_out.clone(),
-            ));
+            )?);
 
             // clean up binary
             if let Interpretable::Executable { executable } = interpretable_out {
@@ -988,6 +1322,7 @@ impl Run {
             llvm_compile_time,
             // eggcc_compile_time is filled out by main.rs
             eggcc_compile_time: Duration::from_millis(0),
+            pass_stats,
         })
     }
 
@@ -1060,20 +1395,13 @@ impl Run {
 
     fn run_bril_llvm(
         &self,
-        input_prog: Program,
-        optimize_egglog: bool,
+        program: Program,
         llvm_level: LLVMOptLevel,
         add_timing: bool,
     ) -> Result<(Interpretable, Duration), EggCCError> {
         // Make a unique name for this test running bril llvm
         // so we don't have conflicts in /tmp
-        let unique_name = format!("{}_{}_{}", self.name(), optimize_egglog, llvm_level);
-
-        let program = if optimize_egglog {
-            Run::optimize_bril(&input_prog, &self.eggcc_config)?
-        } else {
-            input_prog
-        };
+        let unique_name = format!("{}_{}", self.name(), llvm_level);
 
         let mut buf = Vec::new();
         serde_json::to_writer_pretty(&mut buf, &program).expect("failed to deserialize");
@@ -1226,9 +1554,65 @@ impl FreshNameGen {
 
 #[cfg(test)]
 mod test {
-    use dag_in_context::Schedule;
+    use dag_in_context::{ast::*, interpreter::RuntimeErrorKind, program, tuplet, Schedule};
+
+    use super::{Interpretable, Run, RunMode};
+    use crate::{EggCCError, Optimizer};
+
+    #[test]
+    fn test_interp_runtime_error() {
+        let prog = program!(function(
+            "main",
+            tuplet!(statet()),
+            tuplet!(statet()),
+            single(tprint(div(int(1), int(0)), getat(0)))
+        ),);
+        match Optimizer::interp(&Interpretable::TreeProgram(prog), vec![], None) {
+            Err(EggCCError::Interpreter(err)) => {
+                assert_eq!(err.kind, RuntimeErrorKind::DivisionByZero);
+            }
+            other => panic!("expected a division by zero, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_interp_bril_errors() {
+        use std::time::{Duration, Instant};
+
+        let looping = Optimizer::parse_bril("@main {\n.loop:\n  jmp .loop;\n}\n").unwrap();
+        let start = Instant::now();
+        let limit = Duration::from_millis(500);
+        match Optimizer::interp_bril_with_time_limit(&looping, vec![], None, limit) {
+            Err(EggCCError::BrilirsTimeout(timeout)) => assert_eq!(timeout, limit),
+            other => panic!("expected a timeout, got {other:?}"),
+        }
+        // the child is killed at the limit instead of being waited for
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        let leaking = Optimizer::parse_bril(
+            "@main {\n  one: int = const 1;\n  p: ptr<int> = alloc one;\n  print one;\n}\n",
+        )
+        .unwrap();
+        match Optimizer::interp_bril(&leaking, vec![], None) {
+            Err(EggCCError::Brilirs(message)) => assert!(!message.is_empty()),
+            other => panic!("expected a brilirs error, got {other:?}"),
+        }
+    }
 
-    use super::{Run, RunMode};
+    #[cfg(feature = "llvm")]
+    #[test]
+    fn test_empirical_llvm() {
+        use super::{InterpMode, LLVMOptLevel};
+
+        let test_program = super::TestProgram::BrilFile("tests/passing/small/add.bril".into());
+        let mut run = Run::new(test_program.read_program(), RunMode::EmpiricalLLVM);
+        run.interp = InterpMode::Interp;
+        run.optimize_bril_llvm = Some(LLVMOptLevel::O0_O0);
+        run.eggcc_config.top_k = 3;
+        let result = run.run().unwrap();
+        assert!(result.original_interpreted.is_some());
+        assert_eq!(result.result_interpreted, result.original_interpreted);
+    }
 
     #[test]
     fn test_to_egglog_cutoff() {
@@ -1,6 +1,7 @@
 use std::{collections::HashSet, ffi::OsStr};
 
 use eggcc::util::{Run, RunMode, TestProgram};
+use eggcc::EggCCError;
 use insta::assert_snapshot;
 use libtest_mimic::Trial;
 
@@ -15,6 +16,15 @@ fn generate_tests(glob: &str, slow_test: bool) -> Vec<Trial> {
 
         trials.push(Trial::test(test_name, move || {
             let result = match run.run() {
+                Err(EggCCError::DidNotTerminate(steps)) => {
+                    panic!("{} did not terminate within {steps} steps", run.name());
+                }
+                Err(EggCCError::BrilirsTimeout(limit)) => {
+                    panic!(
+                        "{} did not terminate within {limit:?} in brilirs (a wall-clock limit, not a step limit)",
+                        run.name()
+                    );
+                }
                 Err(error) => {
                     panic!("{}", error);
                 }
@@ -49,6 +59,7 @@ fn generate_tests(glob: &str, slow_test: bool) -> Vec<Trial> {
         let testprog = match file.extension().and_then(OsStr::to_str) {
             Some("rs") => TestProgram::RustFile(file.clone()),
             Some("bril") => TestProgram::BrilFile(file.clone()),
+            Some("tree") => TestProgram::TreeFile(file.clone()),
             Some(x) => panic!("unexpected file extension {x}"),
             None => panic!("could not parse file extension"),
         };
@@ -100,6 +111,7 @@ fn main() {
 
     let mut tests = generate_tests("tests/passing/**/*.bril", false);
     tests.extend(generate_tests("tests/passing/**/*.rs", false));
+    tests.extend(generate_tests("tests/passing/**/*.tree", false));
 
     tests.extend(generate_tests("tests/slow/**/*.bril", true));
     tests.extend(generate_tests("tests/slow/**/*.rs", true));